@@ -1,26 +1,164 @@
-#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+use std::collections::BTreeMap;
+
+use crate::email::{ParseProviderError, Provider, SmtpConfig};
+use crate::language::Language;
+
+#[derive(Debug, Default, Clone, PartialEq, clap::Args, serde::Deserialize, serde::Serialize)]
 pub struct Args {
     /// Email address of the sender
+    #[arg(long = "from")]
     pub email_from: String,
 
     /// Email address of the recipient
+    #[arg(
+        long = "to",
+        required_unless_present = "csv",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub email_to: String,
 
     /// BCC email address (optional)
+    #[arg(long)]
     pub bcc: Option<String>,
 
     /// Provider name
+    #[arg(
+        long,
+        required_unless_present = "csv",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub provider: String,
 
     /// Customer name
+    #[arg(
+        long,
+        required_unless_present = "csv",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub name: String,
 
     /// Data amount
+    #[arg(
+        long,
+        required_unless_present = "csv",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub data_amount: String,
 
     /// Time period
+    #[arg(
+        long,
+        required_unless_present = "csv",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub time_period: String,
 
     /// Location
+    #[arg(
+        long,
+        required_unless_present = "csv",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub location: String,
+
+    /// Confirmation code needed to install the eSIM, if the carrier uses one
+    #[arg(long)]
+    #[serde(default)]
+    pub confirmation_code: Option<String>,
+
+    /// Name of the email template to use instead of the built-in one
+    #[arg(long)]
+    #[serde(default)]
+    pub template: Option<String>,
+
+    /// Language of the email, picking the matching version of the template
+    #[arg(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub language: Language,
+
+    /// Values of the extra variables declared by the template, by name
+    #[arg(skip)]
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub numbering: Numbering,
+
+    /// Send all the QR codes in a single email instead of one email each
+    #[arg(long)]
+    #[serde(default)]
+    pub bundle: bool,
+
+    /// Convert QR code images to PNG and shrink large photos before attaching
+    #[arg(long)]
+    #[serde(default)]
+    pub normalize_images: bool,
+
+    /// SMTP server settings, when sending through a provider other than
+    /// Gmail or Outlook
+    #[arg(skip)]
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+}
+
+/// How the emails of a batch are numbered.
+///
+/// The number is appended to the subject as " - 7", or placed with
+/// `{{number}}` when the subject template uses it.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Args, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Numbering {
+    /// Leave the number out of the subject
+    #[arg(long = "no-numbering", action = clap::ArgAction::SetFalse)]
+    pub enabled: bool,
+
+    /// Number of the first email of the batch
+    #[arg(long = "number-start", value_name = "N", default_value_t = 1)]
+    pub start: usize,
+
+    /// Pad numbers with zeros to this many digits
+    #[arg(long = "number-width", value_name = "DIGITS", default_value_t = 0)]
+    pub width: usize,
+}
+
+impl Default for Numbering {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            start: 1,
+            width: 0,
+        }
+    }
+}
+
+impl Numbering {
+    /// The number of the email at 1-based `position` in the batch, or `None`
+    /// when numbering is off.
+    pub fn number(&self, position: usize) -> Option<String> {
+        self.enabled.then(|| {
+            format!(
+                "{:0width$}",
+                self.start + position.saturating_sub(1),
+                width = self.width
+            )
+        })
+    }
+}
+
+impl Args {
+    /// The provider to send through: the configured SMTP server if there is
+    /// one, otherwise Gmail or Outlook depending on the sender address.
+    pub fn email_provider(&self) -> Result<Provider, ParseProviderError> {
+        match &self.smtp {
+            Some(config) => Ok(Provider::Custom(config.clone())),
+            None => self.email_from.parse(),
+        }
+    }
 }
@@ -0,0 +1,881 @@
+use lettre::message::Mailbox;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Args;
+use crate::email;
+use crate::language::Language;
+use crate::qr::{self, QrSource};
+
+/// A single email of a batch: the values to fill into the template and the
+/// QR codes to attach, usually one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchItem {
+    pub args: Args,
+    pub qr_codes: Vec<QrSource>,
+}
+
+impl BatchItem {
+    /// Pairs the QR codes with the same arguments, as when sending a set of
+    /// QR codes to a single recipient: one email each, or all in one email
+    /// if `args.bundle` is set.
+    pub fn for_qr_codes(args: &Args, qr_codes: &[QrSource]) -> Vec<Self> {
+        if args.bundle && !qr_codes.is_empty() {
+            return vec![Self {
+                args: args.clone(),
+                qr_codes: qr_codes.to_vec(),
+            }];
+        }
+        qr_codes
+            .iter()
+            .map(|qr| Self {
+                args: args.clone(),
+                qr_codes: vec![qr.clone()],
+            })
+            .collect()
+    }
+
+    /// The QR codes, listed for messages.
+    pub fn qr_list(&self) -> String {
+        self.qr_codes
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// What happened to one item of a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    /// Not sent yet, or interrupted while sending.
+    Pending,
+    /// `attempts` counts the first try and any retries after a transient
+    /// failure.
+    Sent {
+        message_id: String,
+        attempts: u32,
+    },
+    Failed {
+        error: String,
+        attempts: u32,
+    },
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pending => write!(f, "Waiting"),
+            Self::Sent { attempts: 1, .. } => write!(f, "Sent"),
+            Self::Sent { attempts, .. } => write!(f, "Sent after {} attempts", attempts),
+            Self::Failed { error, attempts: 1 } => write!(f, "Failed: {}", error),
+            Self::Failed { error, attempts } => {
+                write!(f, "Failed after {} attempts: {}", attempts, error)
+            }
+        }
+    }
+}
+
+/// A batch item together with its outcome.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackedItem {
+    pub item: BatchItem,
+    pub outcome: Outcome,
+}
+
+/// Number of items of a batch in each state.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub sent: usize,
+    pub failed: usize,
+    pub pending: usize,
+}
+
+impl Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} failed, {} waiting",
+            self.sent, self.failed, self.pending
+        )
+    }
+}
+
+/// The items of the most recent batch and what happened to each.
+///
+/// The state is saved after every change so that a batch interrupted by an
+/// error, a closed window or a crash can be resumed where it stopped.
+#[derive(Debug, Default)]
+pub struct BatchProgress {
+    path: Option<PathBuf>,
+    items: Vec<TrackedItem>,
+}
+
+impl BatchProgress {
+    const FILE_NAME: &str = "batch.json";
+
+    /// Opens the batch saved in the user's config directory.
+    pub fn open_default() -> io::Result<Self> {
+        Self::open(&crate::paths::app_dir()?.join(Self::FILE_NAME))
+    }
+
+    /// Opens the batch saved at `path`. A missing or unreadable file gives
+    /// an empty batch.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let items = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                eprintln!("Discarding invalid batch file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            items,
+        })
+    }
+
+    pub fn items(&self) -> &[TrackedItem] {
+        &self.items
+    }
+
+    pub fn summary(&self) -> BatchSummary {
+        let mut summary = BatchSummary::default();
+        for tracked in &self.items {
+            match tracked.outcome {
+                Outcome::Pending => summary.pending += 1,
+                Outcome::Sent { .. } => summary.sent += 1,
+                Outcome::Failed { .. } => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    /// Replaces the tracked batch with `items`, none of them sent yet.
+    pub fn start(&mut self, items: Vec<BatchItem>) -> io::Result<()> {
+        self.items = items
+            .into_iter()
+            .map(|item| TrackedItem {
+                item,
+                outcome: Outcome::Pending,
+            })
+            .collect();
+        self.save()
+    }
+
+    /// The items still to be sent, with their position in the batch.
+    pub fn pending(&self) -> Vec<(usize, BatchItem)> {
+        self.items
+            .iter()
+            .enumerate()
+            .filter(|(_, tracked)| tracked.outcome == Outcome::Pending)
+            .map(|(index, tracked)| (index, tracked.item.clone()))
+            .collect()
+    }
+
+    /// Queues the failed items to be sent again.
+    pub fn retry_failed(&mut self) -> io::Result<()> {
+        for tracked in &mut self.items {
+            if matches!(tracked.outcome, Outcome::Failed { .. }) {
+                tracked.outcome = Outcome::Pending;
+            }
+        }
+        self.save()
+    }
+
+    pub fn set_outcome(&mut self, index: usize, outcome: Outcome) -> io::Result<()> {
+        if let Some(tracked) = self.items.get_mut(index) {
+            tracked.outcome = outcome;
+        }
+        self.save()
+    }
+
+    /// Forgets the batch.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.items.clear();
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.items.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let data = serde_json::to_vec_pretty(&self.items).map_err(io::Error::other)?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// A problem found on one row of an imported CSV file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+    /// Line number in the file, counting the header as line 1.
+    pub line: u64,
+    pub message: String,
+}
+
+impl Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Row {}: {}", self.line, self.message)
+    }
+}
+
+/// The result of importing a CSV file, to be reviewed before anything is sent.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ValidationReport {
+    /// One email per row that passed validation.
+    pub items: Vec<BatchItem>,
+    pub errors: Vec<RowError>,
+    /// Header names that don't map to any field and were skipped.
+    pub ignored_columns: Vec<String>,
+    /// Names of the template variables filled in from other columns.
+    pub variable_columns: Vec<String>,
+}
+
+impl ValidationReport {
+    /// Whether the batch can be sent, i.e. every row is valid.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty() && !self.items.is_empty()
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} valid row(s)", self.items.len())?;
+        if !self.errors.is_empty() {
+            write!(f, ", {} problem(s)", self.errors.len())?;
+        }
+        for column in &self.variable_columns {
+            write!(f, "\nTemplate variable column '{}'", column)?;
+        }
+        for column in &self.ignored_columns {
+            write!(f, "\nIgnored unknown column '{}'", column)?;
+        }
+        for error in &self.errors {
+            write!(f, "\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Column {
+    EmailTo,
+    Bcc,
+    Provider,
+    Name,
+    DataAmount,
+    TimePeriod,
+    Location,
+    Qr,
+    ActivationCode,
+    ConfirmationCode,
+    Template,
+    Language,
+}
+
+impl Column {
+    /// Maps a header to a column, accepting the spellings volunteers use.
+    fn from_header(header: &str) -> Option<Self> {
+        match normalize_header(header).as_str() {
+            "email_to" | "to" | "email" | "recipient" => Some(Self::EmailTo),
+            "bcc" => Some(Self::Bcc),
+            "provider" => Some(Self::Provider),
+            "name" => Some(Self::Name),
+            "data_amount" | "data" => Some(Self::DataAmount),
+            "time_period" | "period" | "validity" => Some(Self::TimePeriod),
+            "location" => Some(Self::Location),
+            "qr" | "qr_code" | "qr_file" | "qr_filename" | "image" => Some(Self::Qr),
+            "activation_code" | "lpa" | "lpa_string" => Some(Self::ActivationCode),
+            "confirmation_code" | "confirmation" => Some(Self::ConfirmationCode),
+            "template" => Some(Self::Template),
+            "language" | "lang" => Some(Self::Language),
+            _ => None,
+        }
+    }
+}
+
+fn normalize_header(header: &str) -> String {
+    header.trim().to_lowercase().replace([' ', '-'], "_")
+}
+
+/// Reads a CSV file with one recipient per row.
+///
+/// Each row needs either a QR code image or an activation code to render.
+/// Other columns fill in template variables of the same name, such as an
+/// `ICCID` column for `{{iccid}}`. Empty cells fall back to the values in
+/// `defaults`, and QR code filenames are resolved relative to the CSV file.
+pub fn import_csv(path: &Path, defaults: &Args) -> io::Result<ValidationReport> {
+    let reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(io::Error::other)?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    parse_csv(reader, base_dir, defaults)
+}
+
+fn parse_csv<R: io::Read>(
+    mut reader: csv::Reader<R>,
+    base_dir: &Path,
+    defaults: &Args,
+) -> io::Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+
+    let mut columns = HashMap::new();
+    let mut variable_columns = Vec::new();
+    for (index, header) in reader
+        .headers()
+        .map_err(io::Error::other)?
+        .iter()
+        .enumerate()
+    {
+        match Column::from_header(header) {
+            Some(column) => {
+                columns.insert(column, index);
+            }
+            None if header.is_empty() => {}
+            None if email::is_variable_name(&normalize_header(header)) => {
+                variable_columns.push((normalize_header(header), index));
+            }
+            None => report.ignored_columns.push(header.to_string()),
+        }
+    }
+    let missing = if !columns.contains_key(&Column::EmailTo) {
+        Some("recipient")
+    } else if !columns.contains_key(&Column::Qr) && !columns.contains_key(&Column::ActivationCode) {
+        Some("QR code or activation code")
+    } else {
+        None
+    };
+    if let Some(name) = missing {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("CSV file has no {} column", name),
+        ));
+    }
+
+    report.variable_columns = variable_columns
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let mut seen_qr_codes: HashMap<QrSource, u64> = HashMap::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                report.errors.push(RowError {
+                    line,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let cell = |column| {
+            columns
+                .get(&column)
+                .and_then(|&index| record.get(index))
+                .filter(|value| !value.is_empty())
+        };
+        let or_default = |column, default: &str| cell(column).unwrap_or(default).to_string();
+        let language = cell(Column::Language)
+            .map(str::parse::<Language>)
+            .transpose();
+        let mut variables = defaults.variables.clone();
+        for (name, index) in &variable_columns {
+            if let Some(value) = record.get(*index).filter(|value| !value.is_empty()) {
+                variables.insert(name.clone(), value.to_string());
+            }
+        }
+
+        let args = Args {
+            email_from: defaults.email_from.clone(),
+            email_to: or_default(Column::EmailTo, ""),
+            bcc: cell(Column::Bcc)
+                .map(str::to_string)
+                .or_else(|| defaults.bcc.clone()),
+            provider: or_default(Column::Provider, &defaults.provider),
+            name: or_default(Column::Name, &defaults.name),
+            data_amount: or_default(Column::DataAmount, &defaults.data_amount),
+            time_period: or_default(Column::TimePeriod, &defaults.time_period),
+            location: or_default(Column::Location, &defaults.location),
+            confirmation_code: cell(Column::ConfirmationCode)
+                .map(str::to_string)
+                .or_else(|| defaults.confirmation_code.clone()),
+            template: cell(Column::Template)
+                .map(str::to_string)
+                .or_else(|| defaults.template.clone()),
+            language: language
+                .as_ref()
+                .ok()
+                .copied()
+                .flatten()
+                .unwrap_or(defaults.language),
+            variables,
+            numbering: defaults.numbering.clone(),
+            bundle: defaults.bundle,
+            normalize_images: defaults.normalize_images,
+            smtp: defaults.smtp.clone(),
+        };
+        let mut problems = validate_args(&args);
+        if let Err(e) = language {
+            problems.push(e.to_string());
+        }
+        // Several QR codes for the same email are separated by semicolons
+        let split = |cell: &str| -> Vec<String> {
+            cell.split(';')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let mut qr_codes = Vec::new();
+        match (cell(Column::Qr), cell(Column::ActivationCode)) {
+            (None, None) => problems.push("missing QR code".to_string()),
+            (Some(_), Some(_)) => {
+                problems.push("has both a QR code and an activation code".to_string())
+            }
+            (Some(files), None) => {
+                for file in split(files) {
+                    let path = base_dir.join(file);
+                    if !path.is_file() {
+                        problems.push(format!("QR code '{}' not found", path.display()));
+                    } else if let Err(e) = qr::decode_qr_code(&path) {
+                        problems.push(format!("QR code '{}': {}", path.display(), e));
+                    } else {
+                        qr_codes.push(QrSource::Image(path));
+                    }
+                }
+            }
+            (None, Some(codes)) => {
+                for code in split(codes) {
+                    match code.parse() {
+                        Ok(code) => qr_codes.push(QrSource::ActivationCode(code)),
+                        Err(e) => {
+                            problems.push(format!("invalid activation code '{}': {}", code, e))
+                        }
+                    }
+                }
+            }
+        }
+        let mut row_qr_codes = HashSet::new();
+        for qr in &qr_codes {
+            if !row_qr_codes.insert(qr) {
+                problems.push(format!("QR code '{}' is listed twice", qr));
+                continue;
+            }
+            let first = *seen_qr_codes.entry(qr.clone()).or_insert(line);
+            if first != line {
+                problems.push(format!("QR code '{}' is already used on row {}", qr, first));
+            }
+        }
+
+        if problems.is_empty() {
+            report.items.push(BatchItem { args, qr_codes });
+        } else {
+            report.errors.extend(
+                problems
+                    .into_iter()
+                    .map(|message| RowError { line, message }),
+            );
+        }
+    }
+
+    Ok(report)
+}
+
+fn validate_args(args: &Args) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if args.email_to.is_empty() {
+        problems.push("missing recipient".to_string());
+    } else if args.email_to.parse::<Mailbox>().is_err() {
+        problems.push(format!("invalid recipient address '{}'", args.email_to));
+    }
+    if let Some(bcc) = args.bcc.as_deref().filter(|bcc| !bcc.is_empty())
+        && bcc.parse::<Mailbox>().is_err()
+    {
+        problems.push(format!("invalid BCC address '{}'", bcc));
+    }
+
+    for (value, name) in [
+        (&args.provider, "provider"),
+        (&args.name, "name"),
+        (&args.data_amount, "data amount"),
+        (&args.time_period, "time period"),
+        (&args.location, "location"),
+    ] {
+        if value.is_empty() {
+            problems.push(format!("missing {}", name));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qr::{qr_dir, write_test_qr};
+
+    fn defaults() -> Args {
+        Args {
+            email_from: "sender@gmail.com".to_string(),
+            provider: "TestProvider".to_string(),
+            time_period: "30 days".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn parse(csv: &str, base_dir: &Path) -> io::Result<ValidationReport> {
+        let reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(csv.as_bytes());
+        parse_csv(reader, base_dir, &defaults())
+    }
+
+    #[test]
+    fn test_import_csv_valid_rows() {
+        let tmp = qr_dir(&["a.png", "b.png"]);
+        let dir = tmp.path();
+        let csv_path = dir.join("recipients.csv");
+        fs::write(
+            &csv_path,
+            "Recipient,Name,Data Amount,Location,QR Filename\n\
+             one@example.com,Alice,5GB,Gaza,a.png\n\
+             two@example.com,Bob,10GB,Rafah,b.png\n",
+        )
+        .unwrap();
+
+        let report = import_csv(&csv_path, &defaults()).unwrap();
+
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.items.len(), 2);
+        let first = &report.items[0];
+        assert_eq!(first.args.email_from, "sender@gmail.com");
+        assert_eq!(first.args.email_to, "one@example.com");
+        assert_eq!(first.args.name, "Alice");
+        assert_eq!(first.args.provider, "TestProvider");
+        assert_eq!(first.args.time_period, "30 days");
+        assert_eq!(first.qr_codes, vec![QrSource::Image(dir.join("a.png"))]);
+        assert_eq!(report.items[1].args.location, "Rafah");
+    }
+
+    #[test]
+    fn test_import_csv_reports_row_problems() {
+        let tmp = qr_dir(&["a.png"]);
+        let dir = tmp.path();
+        let report = parse(
+            "email,name,data_amount,location,qr,notes (internal)\n\
+             not-an-address,Alice,5GB,Gaza,a.png,\n\
+             ,Bob,5GB,Gaza,missing.png,\n\
+             three@example.com,Carol,,Gaza,,\n",
+            dir,
+        )
+        .unwrap();
+
+        assert!(!report.is_ok());
+        assert!(report.items.is_empty());
+        assert_eq!(report.ignored_columns, vec!["notes (internal)".to_string()]);
+        let messages: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+        assert!(messages.contains(&"Row 2: invalid recipient address 'not-an-address'".into()));
+        assert!(messages.contains(&"Row 3: missing recipient".into()));
+        assert!(messages.iter().any(|m| m.starts_with("Row 3: QR code")));
+        assert!(messages.contains(&"Row 4: missing data amount".into()));
+        assert!(messages.contains(&"Row 4: missing QR code".into()));
+    }
+
+    #[test]
+    fn test_import_csv_rejects_invalid_qr_code() {
+        let tmp = qr_dir(&["a.png"]);
+        let dir = tmp.path();
+        write_test_qr(&dir.join("website.png"), "https://example.com");
+        fs::write(dir.join("photo.png"), b"not an image").unwrap();
+        let report = parse(
+            "to,name,data,location,qr\n\
+             one@example.com,Alice,5GB,Gaza,a.png\n\
+             two@example.com,Bob,5GB,Gaza,website.png\n\
+             three@example.com,Carol,5GB,Gaza,photo.png\n",
+            dir,
+        )
+        .unwrap();
+
+        assert_eq!(report.items.len(), 1);
+        assert_eq!(report.errors.len(), 2);
+        assert_eq!(report.errors[0].line, 3);
+        assert!(
+            report.errors[0]
+                .message
+                .contains("not an eSIM activation code")
+        );
+        assert_eq!(report.errors[1].line, 4);
+        assert!(report.errors[1].message.contains("not a supported image"));
+    }
+
+    #[test]
+    fn test_import_csv_rejects_reused_qr_code() {
+        let tmp = qr_dir(&["a.png"]);
+        let dir = tmp.path();
+        let report = parse(
+            "to,name,data,location,qr\n\
+             one@example.com,Alice,5GB,Gaza,a.png\n\
+             two@example.com,Bob,5GB,Gaza,a.png\n",
+            dir,
+        )
+        .unwrap();
+
+        assert_eq!(report.items.len(), 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 3);
+        assert!(report.errors[0].message.contains("already used on row 2"));
+    }
+
+    #[test]
+    fn test_import_csv_requires_recipient_and_qr_columns() {
+        let result = parse("name,location\nAlice,Gaza\n", Path::new(""));
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("recipient"));
+    }
+
+    #[test]
+    fn test_import_csv_activation_codes() {
+        let tmp = qr_dir(&["a.png"]);
+        let dir = tmp.path();
+        let report = parse(
+            "to,name,data,location,qr,activation code,confirmation code\n\
+             one@example.com,Alice,5GB,Gaza,a.png,,\n\
+             two@example.com,Bob,5GB,Gaza,,LPA:1$smdp.example.com$BOB$$1,7351\n\
+             three@example.com,Carol,5GB,Gaza,,LPA:1$smdp.example.com$BOB$$1,7351\n\
+             four@example.com,Dan,5GB,Gaza,,smdp.example.com$DAN,\n\
+             five@example.com,Eve,5GB,Gaza,a.png,LPA:1$smdp.example.com$EVE,\n",
+            dir,
+        )
+        .unwrap();
+
+        assert_eq!(report.items.len(), 2);
+        assert_eq!(
+            report.items[1].qr_codes,
+            vec![QrSource::ActivationCode(
+                "LPA:1$smdp.example.com$BOB$$1".parse().unwrap()
+            )]
+        );
+        assert_eq!(
+            report.items[1].args.confirmation_code.as_deref(),
+            Some("7351")
+        );
+        assert_eq!(report.items[0].args.confirmation_code, None);
+        let messages: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Row 4: QR code 'LPA:1$smdp.example.com$BOB$$1' is already used on row 3",
+                "Row 5: invalid activation code 'smdp.example.com$DAN': expected text starting with 'LPA:'",
+                "Row 6: has both a QR code and an activation code",
+            ]
+        );
+
+        let report = parse(
+            "email,name,data,location,lpa\n\
+             one@example.com,Alice,5GB,Gaza,LPA:1$smdp.example.com$ONE\n",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(report.items.len(), 1);
+    }
+
+    #[test]
+    fn test_import_csv_languages() {
+        let report = parse(
+            "email,name,data,location,lpa,language\n\
+             one@example.com,Alice,5GB,Gaza,LPA:1$smdp.example.com$ONE,ar\n\
+             two@example.com,Bob,5GB,Gaza,LPA:1$smdp.example.com$TWO,\n\
+             three@example.com,Carol,5GB,Gaza,LPA:1$smdp.example.com$THREE,Arabic\n\
+             four@example.com,Dan,5GB,Gaza,LPA:1$smdp.example.com$FOUR,fr\n",
+            Path::new(""),
+        )
+        .unwrap();
+
+        let languages: Vec<Language> = report.items.iter().map(|item| item.args.language).collect();
+        assert_eq!(
+            languages,
+            vec![Language::Arabic, Language::English, Language::Arabic]
+        );
+        assert_eq!(
+            report.errors[0].to_string(),
+            "Row 5: Unsupported language 'fr', use one of: en, ar"
+        );
+    }
+
+    #[test]
+    fn test_import_csv_variables() {
+        let mut defaults = defaults();
+        defaults
+            .variables
+            .insert("apn".to_string(), "internet".to_string());
+        let report = parse_csv(
+            csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(
+                "email,name,data,location,lpa,ICCID,APN\n\
+                 one@example.com,Alice,5GB,Gaza,LPA:1$smdp.example.com$ONE,8901,\n\
+                 two@example.com,Bob,5GB,Gaza,LPA:1$smdp.example.com$TWO,8902,roaming\n"
+                    .as_bytes(),
+            ),
+            Path::new(""),
+            &defaults,
+        )
+        .unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.variable_columns, vec!["iccid", "apn"]);
+        let variables = &report.items[0].args.variables;
+        assert_eq!(variables["iccid"], "8901");
+        assert_eq!(variables["apn"], "internet");
+        assert_eq!(report.items[1].args.variables["apn"], "roaming");
+    }
+
+    #[test]
+    fn test_batch_items_for_qr_codes() {
+        let args = defaults();
+        let qr_codes = vec![
+            QrSource::Image(PathBuf::from("a.png")),
+            QrSource::Image(PathBuf::from("b.png")),
+        ];
+        let items = BatchItem::for_qr_codes(&args, &qr_codes);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].args, args);
+        assert_eq!(
+            items[1].qr_codes,
+            vec![QrSource::Image(PathBuf::from("b.png"))]
+        );
+
+        let bundled = BatchItem::for_qr_codes(
+            &Args {
+                bundle: true,
+                ..args
+            },
+            &qr_codes,
+        );
+        assert_eq!(bundled.len(), 1);
+        assert_eq!(bundled[0].qr_codes, qr_codes);
+        assert_eq!(bundled[0].qr_list(), "a.png, b.png");
+    }
+
+    #[test]
+    fn test_import_csv_several_qr_codes_per_row() {
+        let report = parse(
+            "email,name,data,location,lpa\n\
+             one@example.com,Alice,5GB,Gaza,LPA:1$smdp.example.com$ONE; LPA:1$smdp.example.com$TWO\n\
+             two@example.com,Bob,5GB,Gaza,LPA:1$smdp.example.com$THREE;LPA:1$smdp.example.com$THREE\n",
+            Path::new(""),
+        )
+        .unwrap();
+
+        assert_eq!(report.items.len(), 1);
+        assert_eq!(report.items[0].qr_codes.len(), 2);
+        assert_eq!(
+            report.errors[0].to_string(),
+            "Row 3: QR code 'LPA:1$smdp.example.com$THREE' is listed twice"
+        );
+    }
+
+    #[test]
+    fn test_batch_progress_resume_after_reopen() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("batch.json");
+        let items = BatchItem::for_qr_codes(
+            &defaults(),
+            &["a.png", "b.png", "c.png"].map(|file| QrSource::Image(PathBuf::from(file))),
+        );
+
+        let mut progress = BatchProgress::open(&path).unwrap();
+        progress.start(items.clone()).unwrap();
+        progress
+            .set_outcome(
+                0,
+                Outcome::Sent {
+                    message_id: "<a@gmail.com>".to_string(),
+                    attempts: 1,
+                },
+            )
+            .unwrap();
+        progress
+            .set_outcome(
+                1,
+                Outcome::Failed {
+                    error: "connection reset".to_string(),
+                    attempts: 4,
+                },
+            )
+            .unwrap();
+
+        let mut reopened = BatchProgress::open(&path).unwrap();
+        assert_eq!(reopened.items(), progress.items());
+        assert_eq!(
+            reopened.summary(),
+            BatchSummary {
+                sent: 1,
+                failed: 1,
+                pending: 1
+            }
+        );
+        assert_eq!(reopened.pending(), vec![(2, items[2].clone())]);
+
+        reopened.retry_failed().unwrap();
+        let indices: Vec<usize> = reopened.pending().iter().map(|(i, _)| *i).collect();
+        assert_eq!(indices, vec![1, 2]);
+
+        reopened.clear().unwrap();
+        assert!(!path.exists());
+        assert!(BatchProgress::open(&path).unwrap().items().is_empty());
+    }
+
+    #[test]
+    fn test_outcome_attempts() {
+        let sent = Outcome::Sent {
+            message_id: "<a@gmail.com>".to_string(),
+            attempts: 1,
+        };
+        assert_eq!(sent.to_string(), "Sent");
+
+        let sent = Outcome::Sent {
+            message_id: "<a@gmail.com>".to_string(),
+            attempts: 2,
+        };
+        assert_eq!(sent.to_string(), "Sent after 2 attempts");
+        let failed = Outcome::Failed {
+            error: "connection reset".to_string(),
+            attempts: 4,
+        };
+        assert_eq!(
+            failed.to_string(),
+            "Failed after 4 attempts: connection reset"
+        );
+    }
+
+    #[test]
+    fn test_batch_progress_discards_invalid_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("batch.json");
+        fs::write(&path, "not json").unwrap();
+
+        let progress = BatchProgress::open(&path).unwrap();
+        assert!(progress.items().is_empty());
+    }
+}
@@ -0,0 +1,1158 @@
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::Args;
+use crate::batch::{self, BatchItem};
+use crate::email::{self, AuthMechanism, IMAGE_EXTENSIONS, SmtpConfig, TlsMode};
+use crate::ledger::{self, SendLedger};
+use crate::oauth::OAuthClient;
+use crate::qr::{self, ActivationCode, QrCheck, QrSource};
+use crate::rate_limit::{RateLimit, Throttle};
+use crate::retry::RetryPolicy;
+use crate::sender::{DefaultEmailOperations, EmailOperations};
+use crate::templates;
+
+/// Environment variable holding the password for a custom SMTP server. It is
+/// saved for later runs, so it only needs to be set when it changes.
+pub const SMTP_PASSWORD_VAR: &str = "ESIM_MAILER_SMTP_PASSWORD";
+
+/// Exit code when every email was sent.
+pub const EXIT_SUCCESS: u8 = 0;
+/// Exit code when nothing was sent, e.g. because of bad input or failed authentication.
+pub const EXIT_FAILURE: u8 = 1;
+/// Exit code when at least one of the emails could not be sent.
+pub const EXIT_SEND_FAILED: u8 = 3;
+
+#[derive(Debug, Parser)]
+#[command(name = "esim-mailer", version, about = "Send eSIM QR codes by email")]
+pub struct Cli {
+    /// Runs headless when given, otherwise the GUI is launched
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Send one email per QR code without opening a window
+    #[command(after_help = "Exit codes: 0 all emails sent, 1 nothing sent, 3 some emails failed")]
+    Send(SendCommand),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SendCommand {
+    #[command(flatten)]
+    pub args: Args,
+
+    /// QR code images, or directories containing them
+    #[arg(
+        long = "qr",
+        required_unless_present_any = ["csv", "lpa", "lpa_file"],
+        conflicts_with = "csv",
+        num_args = 1..
+    )]
+    pub qr: Vec<PathBuf>,
+
+    /// eSIM activation codes (LPA:1$...) to turn into QR codes
+    #[arg(long, value_name = "CODE", conflicts_with = "csv", num_args = 1..)]
+    pub lpa: Vec<ActivationCode>,
+
+    /// Text file with one activation code per line
+    #[arg(long, value_name = "FILE", conflicts_with = "csv")]
+    pub lpa_file: Option<PathBuf>,
+
+    /// CSV file with one recipient and QR code per row, whose empty cells
+    /// are filled in from the other options
+    #[arg(long)]
+    pub csv: Option<PathBuf>,
+
+    /// Value of a variable declared by the template, such as --var iccid=8901.
+    /// CSV columns of the same name take precedence
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_variable)]
+    pub variables: Vec<(String, String)>,
+
+    /// Send QR codes again even if the send history shows they were sent before
+    #[arg(long)]
+    pub allow_duplicates: bool,
+
+    /// Write each email to an .eml file in DIR instead of sending it
+    #[arg(long, value_name = "DIR")]
+    pub dry_run: Option<PathBuf>,
+
+    /// How many times to send an email again after a temporary failure, such
+    /// as a busy or rate-limited server or a dropped connection
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Seconds to wait before the first retry, doubled for each further one
+    #[arg(long, value_name = "SECONDS", default_value_t = 2)]
+    pub retry_delay: u64,
+
+    /// Most emails to send in a minute, 0 for no limit [default: 20 for
+    /// Gmail, 30 for Outlook, none for other servers]
+    #[arg(long, value_name = "N")]
+    pub per_minute: Option<u32>,
+
+    /// Daily limit of the account, warned about before sending, 0 for no
+    /// limit [default: 500 for Gmail, 300 for Outlook, none for other servers]
+    #[arg(long, value_name = "N")]
+    pub per_day: Option<u32>,
+
+    #[command(flatten)]
+    pub smtp: SmtpOptions,
+}
+
+/// Settings for sending through an SMTP server other than Gmail or Outlook.
+/// The password is read from the environment variable named by
+/// [`SMTP_PASSWORD_VAR`].
+#[derive(Debug, Default, clap::Args)]
+pub struct SmtpOptions {
+    /// SMTP server to send through instead of Gmail or Outlook
+    #[arg(long = "smtp-host")]
+    pub host: Option<String>,
+
+    /// SMTP server port [default: depends on --smtp-tls]
+    #[arg(long = "smtp-port", requires = "host")]
+    pub port: Option<u16>,
+
+    /// How the SMTP connection is secured
+    #[arg(long = "smtp-tls", value_enum, default_value_t, requires = "host")]
+    pub tls: TlsMode,
+
+    /// SMTP login mechanism
+    #[arg(long = "smtp-auth", value_enum, default_value_t, requires = "host")]
+    pub auth: AuthMechanism,
+}
+
+fn parse_variable(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", s))?;
+    let name = name.trim();
+    if !email::is_variable_name(name) {
+        return Err(format!(
+            "'{}' can't be a variable name, use letters, digits and underscores",
+            name
+        ));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+impl SmtpOptions {
+    fn config(&self) -> Option<SmtpConfig> {
+        self.host.as_ref().map(|host| SmtpConfig {
+            host: host.clone(),
+            port: self.port.unwrap_or(self.tls.default_port()),
+            tls: self.tls,
+            auth: self.auth,
+        })
+    }
+}
+
+/// Runs a parsed subcommand using the real OAuth client and SMTP transport.
+pub fn run(command: Command) -> ExitCode {
+    #[cfg(windows)]
+    attach_console();
+
+    let email_ops =
+        DefaultEmailOperations::new(Arc::new(Mutex::new(OAuthClient::with_file_storage())));
+    let mut ledger = match SendLedger::open_default() {
+        Ok(ledger) => ledger,
+        Err(e) => {
+            eprintln!("Error opening send history: {}", e);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    match command {
+        Command::Send(send) => ExitCode::from(run_send(&send, &email_ops, &mut ledger)),
+    }
+}
+
+/// Sends every QR code or CSV row in `command` and returns the process exit code.
+///
+/// QR codes found in `ledger` are skipped unless duplicates are allowed.
+/// Sending carries on after a failed email so that a single bad address or
+/// image does not hold up the rest of the batch.
+pub fn run_send(
+    command: &SendCommand,
+    email_ops: &dyn EmailOperations,
+    ledger: &mut SendLedger,
+) -> u8 {
+    let args = &Args {
+        smtp: command.smtp.config(),
+        variables: command.variables.iter().cloned().collect(),
+        ..command.args.clone()
+    };
+
+    let items = if let Some(csv) = &command.csv {
+        match batch::import_csv(csv, args) {
+            Ok(report) if report.is_ok() => {
+                println!("{}", report);
+                report.items
+            }
+            Ok(report) => {
+                println!("{}", report);
+                eprintln!("Nothing was sent. Fix the rows above and try again.");
+                return EXIT_FAILURE;
+            }
+            Err(e) => {
+                eprintln!("Error importing CSV: {}", e);
+                return EXIT_FAILURE;
+            }
+        }
+    } else {
+        let Some(qr_codes) = qr_sources(command) else {
+            return EXIT_FAILURE;
+        };
+        if qr_codes.is_empty() {
+            eprintln!("No QR codes found");
+            return EXIT_FAILURE;
+        }
+        BatchItem::for_qr_codes(args, &qr_codes)
+    };
+
+    let mut templates: Vec<_> = items
+        .iter()
+        .map(|item| (item.args.template.as_deref(), item.args.language))
+        .collect();
+    templates.sort();
+    templates.dedup();
+    let mut loaded = HashMap::new();
+    for (name, language) in templates {
+        match templates::load_template(name, language) {
+            Ok(template) => {
+                loaded.insert((name, language), template);
+            }
+            Err(e) => {
+                eprintln!("Error loading template: {}", e);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+    let mut complete = true;
+    for item in &items {
+        let template = &loaded[&(item.args.template.as_deref(), item.args.language)];
+        let missing = template.missing_variables(&item.args);
+        if !missing.is_empty() {
+            eprintln!(
+                "{} to {}: missing values for template variables: {}",
+                item.qr_list(),
+                item.args.email_to,
+                missing.join(", ")
+            );
+            complete = false;
+        }
+    }
+    if !complete {
+        eprintln!("Nothing was sent. Pass --var NAME=VALUE or add the columns to the CSV file.");
+        return EXIT_FAILURE;
+    }
+
+    let duplicates = ledger.find_duplicates(&items);
+    for duplicate in &duplicates {
+        eprintln!("{}", duplicate);
+    }
+    let items = if duplicates.is_empty() || command.allow_duplicates {
+        items
+    } else {
+        eprintln!(
+            "Skipping {} QR code(s) that were sent before. Pass --allow-duplicates to send them anyway.",
+            duplicates.len()
+        );
+        let remaining = ledger::without_duplicates(items, &duplicates);
+        if remaining.is_empty() {
+            eprintln!("Nothing to send.");
+            return EXIT_FAILURE;
+        }
+        remaining
+    };
+
+    if let Some(dir) = &command.dry_run {
+        return write_eml_files(&items, dir);
+    }
+
+    let provider = match args.email_provider() {
+        Ok(provider) => provider,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let default_limit = provider.default_rate_limit();
+    let limit = RateLimit {
+        per_minute: command.per_minute.unwrap_or(default_limit.per_minute),
+        per_day: command.per_day.unwrap_or(default_limit.per_day),
+        ..default_limit
+    };
+    let sent_today = ledger.sent_in_last_day(&args.email_from, Utc::now());
+    if let Some(warning) = limit.check_quota(&args.email_from, sent_today, items.len()) {
+        eprintln!(
+            "Warning: {}. Emails past the limit will likely be rejected until tomorrow.",
+            warning
+        );
+    }
+
+    if !provider.uses_oauth()
+        && let Ok(password) = env::var(SMTP_PASSWORD_VAR)
+        && let Err(e) = email_ops.save_password(&provider, &args.email_from, password)
+    {
+        eprintln!("Error saving password: {}", e);
+        return EXIT_FAILURE;
+    }
+
+    let token = match email_ops.get_token(&provider, &args.email_from) {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Error getting OAuth token: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let retry = RetryPolicy {
+        retries: command.retries,
+        base_delay: Duration::from_secs(command.retry_delay),
+        ..RetryPolicy::default()
+    };
+    let mut throttle = Throttle::new(limit);
+    let total = items.len();
+    let mut failed = 0;
+    for (index, item) in items.iter().enumerate() {
+        let label = item_label(index, total, item);
+        let (result, attempts) = retry.run(
+            || {
+                throttle.wait();
+                email_ops.send_email(&item.args, token.clone(), &item.qr_codes, index + 1)
+            },
+            |attempt, e, delay| {
+                eprintln!(
+                    "{}: attempt {} failed, retrying in {:.1}s: {}",
+                    label,
+                    attempt,
+                    delay.as_secs_f64(),
+                    e
+                );
+                thread::sleep(delay);
+            },
+        );
+        let label = match attempts {
+            1 => label,
+            _ => format!("{} (attempt {})", label, attempts),
+        };
+        match result {
+            Ok(message_id) => {
+                println!("{}: sent", label);
+                if let Err(e) = ledger.record_sent(item, &message_id, &provider) {
+                    eprintln!("Error recording sent email: {}", e);
+                }
+            }
+            Err(e) if e.stops_batch() => {
+                if e.needs_sign_in() {
+                    email_ops.forget_token(&provider, &args.email_from);
+                    eprintln!("{}: {}. Run the command again to sign in again.", label, e);
+                } else {
+                    eprintln!("{}: {}", label, e);
+                }
+                // Nothing else would get through either
+                failed += total - index;
+                break;
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", label, e);
+            }
+        }
+    }
+
+    println!("{} of {} eSIM emails sent.", total - failed, total);
+    exit_code(failed, total)
+}
+
+/// Writes the emails for `items` to `.eml` files in `dir` without sending
+/// them or recording them in the send history.
+fn write_eml_files(items: &[BatchItem], dir: &Path) -> u8 {
+    let total = items.len();
+    let mut failed = 0;
+    for (index, item) in items.iter().enumerate() {
+        let label = item_label(index, total, item);
+        match email::write_eml(&item.args, &item.qr_codes, index + 1, dir) {
+            Ok(path) => println!("{}: written to {}", label, path.display()),
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", label, e);
+            }
+        }
+    }
+
+    println!(
+        "{} of {} eSIM emails written to {}. Nothing was sent.",
+        total - failed,
+        total,
+        dir.display()
+    );
+    exit_code(failed, total)
+}
+
+fn item_label(index: usize, total: usize, item: &BatchItem) -> String {
+    format!(
+        "[{}/{}] {} to {}",
+        index + 1,
+        total,
+        item.qr_list(),
+        item.args.email_to
+    )
+}
+
+fn exit_code(failed: usize, total: usize) -> u8 {
+    match failed {
+        0 => EXIT_SUCCESS,
+        n if n == total => EXIT_FAILURE,
+        _ => EXIT_SEND_FAILED,
+    }
+}
+
+/// Gathers the QR codes given with `--qr`, `--lpa` and `--lpa-file`, printing
+/// every problem found. Returns `None` if there were any.
+fn qr_sources(command: &SendCommand) -> Option<Vec<QrSource>> {
+    let paths = match collect_qr_codes(&command.qr) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Error reading QR codes: {}", e);
+            return None;
+        }
+    };
+
+    let mut valid = true;
+    let mut sources = Vec::new();
+    for path in paths {
+        let check = QrCheck::new(&QrSource::Image(path));
+        if check.result.is_err() {
+            eprintln!("{}", check);
+            valid = false;
+        }
+        sources.push(check.source);
+    }
+    sources.extend(command.lpa.iter().cloned().map(QrSource::ActivationCode));
+    if let Some(path) = &command.lpa_file {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let (codes, errors) = qr::parse_activation_codes(&text);
+        for error in &errors {
+            eprintln!("{}: {}", path.display(), error);
+            valid = false;
+        }
+        sources.extend(codes.into_iter().map(QrSource::ActivationCode));
+    }
+
+    if !valid {
+        eprintln!("Nothing was sent. Fix the QR codes above and try again.");
+        return None;
+    }
+    Some(sources)
+}
+
+/// Expands directories into the images they contain, keeping files as given.
+fn collect_qr_codes(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut images = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.retain(|p| p.is_file() && is_image(p));
+            entries.sort();
+            images.extend(entries);
+        } else if path.is_file() {
+            images.push(path.clone());
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+    }
+    Ok(images)
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+// Release builds use the Windows GUI subsystem, which starts without a
+// console, so reattach to the shell we were launched from to show output.
+#[cfg(windows)]
+fn attach_console() {
+    use winapi::um::wincon::{ATTACH_PARENT_PROCESS, AttachConsole};
+
+    // SAFETY: AttachConsole has no preconditions; failure just means there is
+    // no parent console and output is discarded as before.
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MailerError;
+    use crate::args::Numbering;
+    use crate::language::Language;
+    use crate::qr::{qr_dir, write_test_qr};
+    use crate::sender::mock::{MockEmailOperations, mock_error};
+
+    fn send_command(qr: Vec<PathBuf>) -> SendCommand {
+        SendCommand {
+            args: Args {
+                email_from: "test@gmail.com".to_string(),
+                email_to: "recipient@example.com".to_string(),
+                ..Default::default()
+            },
+            qr,
+            lpa: Vec::new(),
+            lpa_file: None,
+            csv: None,
+            variables: Vec::new(),
+            allow_duplicates: false,
+            dry_run: None,
+            retries: 3,
+            retry_delay: 0,
+            per_minute: Some(0),
+            per_day: None,
+            smtp: SmtpOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_send_command() {
+        let cli = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "me@gmail.com",
+            "--to",
+            "you@example.com",
+            "--provider",
+            "TestProvider",
+            "--name",
+            "John",
+            "--data-amount",
+            "5GB",
+            "--time-period",
+            "30 days",
+            "--location",
+            "Egypt",
+            "--language",
+            "ar",
+            "--template",
+            "Short",
+            "--retries",
+            "5",
+            "--qr",
+            "a.png",
+            "b.png",
+        ])
+        .unwrap();
+
+        let Some(Command::Send(send)) = cli.command else {
+            panic!("expected send command");
+        };
+        assert_eq!(send.args.email_from, "me@gmail.com");
+        assert_eq!((send.retries, send.retry_delay), (5, 2));
+        assert_eq!((send.per_minute, send.per_day), (None, None));
+        assert_eq!(send.args.bcc, None);
+        assert_eq!(send.args.template.as_deref(), Some("Short"));
+        assert_eq!(send.args.language, Language::Arabic);
+        assert_eq!(send.args.time_period, "30 days");
+        assert_eq!(
+            send.qr,
+            vec![PathBuf::from("a.png"), PathBuf::from("b.png")]
+        );
+    }
+
+    #[test]
+    fn test_parse_send_custom_smtp() {
+        let cli = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "me@fastmail.com",
+            "--csv",
+            "r.csv",
+            "--smtp-host",
+            "smtp.fastmail.com",
+            "--smtp-tls",
+            "tls",
+            "--smtp-port",
+            "465",
+        ])
+        .unwrap();
+        let Some(Command::Send(send)) = cli.command else {
+            panic!("expected send command");
+        };
+        let smtp = send.smtp.config().unwrap();
+        assert_eq!(smtp.host, "smtp.fastmail.com");
+        assert_eq!(smtp.port, 465);
+        assert_eq!(smtp.tls, TlsMode::Tls);
+        assert_eq!(smtp.auth, AuthMechanism::Plain);
+
+        let cli =
+            Cli::try_parse_from(["esim-mailer", "send", "--from", "a@gmail.com", "--csv", "r"])
+                .unwrap();
+        let Some(Command::Send(send)) = cli.command else {
+            panic!("expected send command");
+        };
+        assert_eq!(send.smtp.config(), None);
+
+        let result = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "a@gmail.com",
+            "--csv",
+            "r",
+            "--smtp-port",
+            "25",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_send_numbering() {
+        let parse = |extra: &[&str]| {
+            let cli = Cli::try_parse_from(
+                [
+                    "esim-mailer",
+                    "send",
+                    "--from",
+                    "a@gmail.com",
+                    "--csv",
+                    "r.csv",
+                ]
+                .iter()
+                .chain(extra),
+            )
+            .unwrap();
+            let Some(Command::Send(send)) = cli.command else {
+                panic!("expected send command");
+            };
+            send.args.numbering
+        };
+        assert_eq!(parse(&[]), Numbering::default());
+        let numbering = parse(&["--number-start", "101", "--number-width", "4"]);
+        assert_eq!(numbering.number(2).as_deref(), Some("0102"));
+        assert_eq!(parse(&["--no-numbering"]).number(1), None);
+    }
+
+    #[test]
+    fn test_parse_send_variables() {
+        let cli = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "a@gmail.com",
+            "--csv",
+            "r.csv",
+            "--var",
+            "iccid=8901",
+            "--var",
+            "support_link=https://example.com/help?a=b",
+        ])
+        .unwrap();
+        let Some(Command::Send(send)) = cli.command else {
+            panic!("expected send command");
+        };
+        assert_eq!(
+            send.variables,
+            vec![
+                ("iccid".to_string(), "8901".to_string()),
+                (
+                    "support_link".to_string(),
+                    "https://example.com/help?a=b".to_string()
+                ),
+            ]
+        );
+
+        for var in ["iccid", "support link=x", "=x"] {
+            let result = Cli::try_parse_from([
+                "esim-mailer",
+                "send",
+                "--from",
+                "a@gmail.com",
+                "--csv",
+                "r.csv",
+                "--var",
+                var,
+            ]);
+            assert!(result.is_err(), "{} should be rejected", var);
+        }
+    }
+
+    #[test]
+    fn test_smtp_options_default_port() {
+        let options = SmtpOptions {
+            host: Some("mail.example.org".to_string()),
+            tls: TlsMode::Tls,
+            ..Default::default()
+        };
+        assert_eq!(options.config().unwrap().port, 465);
+    }
+
+    #[test]
+    fn test_parse_without_subcommand_launches_gui() {
+        let cli = Cli::try_parse_from(["esim-mailer"]).unwrap();
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn test_parse_send_requires_qr() {
+        let result = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "me@gmail.com",
+            "--to",
+            "you@example.com",
+            "--provider",
+            "TestProvider",
+            "--name",
+            "John",
+            "--data-amount",
+            "5GB",
+            "--time-period",
+            "30 days",
+            "--location",
+            "Egypt",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_collect_qr_codes_expands_directories() {
+        let tmp = qr_dir(&["b.png", "a.JPG", "notes.txt"]);
+        let dir = tmp.path().to_path_buf();
+
+        let images = collect_qr_codes(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(images, vec![dir.join("a.JPG"), dir.join("b.png")]);
+
+        let missing = collect_qr_codes(&[dir.join("missing.png")]);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_run_send_success() {
+        let tmp = qr_dir(&["1.png", "2.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+
+        let code = run_send(
+            &send_command(vec![dir.clone()]),
+            &ops,
+            &mut SendLedger::default(),
+        );
+
+        assert_eq!(code, EXIT_SUCCESS);
+        assert_eq!(ops.sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_run_send_stops_when_sign_in_rejected() {
+        let tmp = qr_dir(&["1.png", "2.png", "3.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+        let token_expired = || {
+            let error = crate::error::smtp_error("535 5.7.8 Token expired");
+            MailerError::from_smtp(error, &email::Provider::Gmail, "test@gmail.com")
+        };
+        ops.fail(2, token_expired, usize::MAX);
+
+        let code = run_send(
+            &send_command(vec![dir.clone()]),
+            &ops,
+            &mut SendLedger::default(),
+        );
+
+        assert_eq!(code, EXIT_SEND_FAILED);
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![QrSource::Image(dir.join("1.png"))]
+        );
+        assert_eq!(*ops.forgotten.lock().unwrap(), vec!["test@gmail.com"]);
+    }
+
+    fn busy_server() -> MailerError {
+        let error = crate::error::smtp_error("421 4.7.0 Try again later");
+        MailerError::from_smtp(error, &email::Provider::Gmail, "test@gmail.com")
+    }
+
+    #[test]
+    fn test_run_send_retries_transient_failures() {
+        let tmp = qr_dir(&["1.png", "2.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+        ops.fail(1, busy_server, 2);
+
+        let code = run_send(
+            &send_command(vec![dir.clone()]),
+            &ops,
+            &mut SendLedger::default(),
+        );
+
+        assert_eq!(code, 0);
+        assert_eq!(ops.sent.lock().unwrap().len(), 2);
+        assert_eq!(ops.failures_left(), 0);
+
+        // Gives up on the email once the retries are used up
+        let ops = MockEmailOperations::new(false);
+        ops.fail(1, busy_server, 2);
+        let mut command = send_command(vec![dir.clone()]);
+        command.retries = 1;
+
+        let code = run_send(&command, &ops, &mut SendLedger::default());
+
+        assert_eq!(code, EXIT_SEND_FAILED);
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![QrSource::Image(dir.join("2.png"))]
+        );
+        assert_eq!(ops.failures_left(), 0);
+    }
+
+    #[test]
+    fn test_run_send_continues_after_failure() {
+        let tmp = qr_dir(&["1.png", "2.png", "3.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+        ops.fail(2, mock_error, usize::MAX);
+
+        let code = run_send(
+            &send_command(vec![dir.clone()]),
+            &ops,
+            &mut SendLedger::default(),
+        );
+
+        assert_eq!(code, EXIT_SEND_FAILED);
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![
+                QrSource::Image(dir.join("1.png")),
+                QrSource::Image(dir.join("3.png"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_send_csv() {
+        let tmp = qr_dir(&["1.png", "2.png"]);
+        let dir = tmp.path().to_path_buf();
+        let csv = dir.join("rows.csv");
+        fs::write(
+            &csv,
+            "to,name,data_amount,time_period,location,provider,qr\n\
+             one@example.com,Alice,5GB,30 days,Gaza,TestProvider,1.png\n\
+             two@example.com,Bob,5GB,30 days,Gaza,TestProvider,2.png\n",
+        )
+        .unwrap();
+        let ops = MockEmailOperations::new(false);
+        let mut command = send_command(Vec::new());
+        command.csv = Some(csv);
+
+        assert_eq!(
+            run_send(&command, &ops, &mut SendLedger::default()),
+            EXIT_SUCCESS
+        );
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![
+                QrSource::Image(dir.join("1.png")),
+                QrSource::Image(dir.join("2.png"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_send_invalid_csv_sends_nothing() {
+        let tmp = qr_dir(&["1.png"]);
+        let dir = tmp.path().to_path_buf();
+        let csv = dir.join("rows.csv");
+        fs::write(
+            &csv,
+            "to,name,data_amount,time_period,location,provider,qr\n\
+             one@example.com,Alice,5GB,30 days,Gaza,TestProvider,1.png\n\
+             two@example.com,Bob,5GB,30 days,Gaza,TestProvider,missing.png\n",
+        )
+        .unwrap();
+        let ops = MockEmailOperations::new(false);
+        let mut command = send_command(Vec::new());
+        command.csv = Some(csv);
+
+        assert_eq!(
+            run_send(&command, &ops, &mut SendLedger::default()),
+            EXIT_FAILURE
+        );
+        assert!(ops.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_send_csv_without_recipient_options() {
+        let cli = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "me@gmail.com",
+            "--csv",
+            "r.csv",
+        ])
+        .unwrap();
+        let Some(Command::Send(send)) = cli.command else {
+            panic!("expected send command");
+        };
+        assert_eq!(send.csv, Some(PathBuf::from("r.csv")));
+        assert!(send.qr.is_empty());
+        assert_eq!(send.args.email_to, "");
+    }
+
+    #[test]
+    fn test_run_send_throttles_and_warns_over_quota() {
+        let tmp = qr_dir(&["1.png", "2.png", "3.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+        let mut command = send_command(vec![dir.clone()]);
+        command.per_minute = Some(600);
+        command.per_day = Some(2);
+
+        let started = std::time::Instant::now();
+        let mut ledger = SendLedger::default();
+        let code = run_send(&command, &ops, &mut ledger);
+
+        // Going over the daily limit is only warned about
+        assert_eq!(code, EXIT_SUCCESS);
+        assert_eq!(ops.sent.lock().unwrap().len(), 3);
+        // One email every 100ms
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(ledger.sent_in_last_day("test@gmail.com", Utc::now()), 3);
+    }
+
+    #[test]
+    fn test_run_send_skips_sent_qr_codes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        write_test_qr(&dir.join("1.png"), "LPA:1$smdp.example.com$FIRST");
+        write_test_qr(&dir.join("2.png"), "LPA:1$smdp.example.com$SECOND");
+        let mut ledger = SendLedger::default();
+
+        let ops = MockEmailOperations::new(false);
+        let command = send_command(vec![dir.join("1.png")]);
+        assert_eq!(run_send(&command, &ops, &mut ledger), EXIT_SUCCESS);
+        assert_eq!(ledger.entries().len(), 1);
+        assert_eq!(ledger.entries()[0].message_id, "<1@mock>");
+
+        let ops = MockEmailOperations::new(false);
+        let command = send_command(vec![dir.clone()]);
+        assert_eq!(run_send(&command, &ops, &mut ledger), EXIT_SUCCESS);
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![QrSource::Image(dir.join("2.png"))]
+        );
+
+        let ops = MockEmailOperations::new(false);
+        let command = send_command(vec![dir.join("1.png")]);
+        assert_eq!(run_send(&command, &ops, &mut ledger), EXIT_FAILURE);
+        assert!(ops.sent.lock().unwrap().is_empty());
+
+        let mut command = send_command(vec![dir.join("1.png")]);
+        command.allow_duplicates = true;
+        assert_eq!(run_send(&command, &ops, &mut ledger), EXIT_SUCCESS);
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![QrSource::Image(dir.join("1.png"))]
+        );
+    }
+
+    #[test]
+    fn test_run_send_dry_run() {
+        let tmp = qr_dir(&["1.png", "2.png"]);
+        let dir = tmp.path().to_path_buf();
+        let out = dir.join("out");
+        let ops = MockEmailOperations::new(true);
+        let mut ledger = SendLedger::default();
+        let mut command = send_command(vec![dir.join("1.png"), dir.join("2.png")]);
+        command.dry_run = Some(out.clone());
+        assert_eq!(run_send(&command, &ops, &mut ledger), EXIT_SUCCESS);
+        assert!(ops.sent.lock().unwrap().is_empty());
+        assert!(ledger.entries().is_empty());
+
+        let eml = fs::read_to_string(out.join("002-recipient@example.com.eml")).unwrap();
+        assert!(eml.contains("To: recipient@example.com"));
+        assert!(eml.contains("multipart/alternative"));
+        assert!(out.join("001-recipient@example.com.eml").exists());
+    }
+
+    #[test]
+    fn test_run_send_bundle() {
+        let tmp = qr_dir(&["1.png", "2.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+        let mut ledger = SendLedger::default();
+        let mut command = send_command(vec![dir.clone()]);
+        command.args.bundle = true;
+        assert_eq!(run_send(&command, &ops, &mut ledger), EXIT_SUCCESS);
+        assert_eq!(ops.sent.lock().unwrap().len(), 2);
+        assert_eq!(ledger.entries().len(), 2);
+        assert_eq!(
+            ledger.entries()[0].message_id,
+            ledger.entries()[1].message_id
+        );
+    }
+
+    #[test]
+    fn test_run_send_rejects_invalid_qr_codes() {
+        let tmp = qr_dir(&["1.png"]);
+        let dir = tmp.path().to_path_buf();
+        write_test_qr(&dir.join("2.png"), "WIFI:S:network;;");
+
+        let ops = MockEmailOperations::new(false);
+        let command = send_command(vec![dir.clone()]);
+        assert_eq!(
+            run_send(&command, &ops, &mut SendLedger::default()),
+            EXIT_FAILURE
+        );
+        assert!(ops.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_send_activation_codes() {
+        let tmp = qr_dir(&["1.png"]);
+        let dir = tmp.path().to_path_buf();
+        let codes_file = dir.join("codes.txt");
+        fs::write(
+            &codes_file,
+            "# from the carrier\nLPA:1$smdp.example.com$FROM-FILE\n",
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "test@gmail.com",
+            "--to",
+            "recipient@example.com",
+            "--provider",
+            "TestProvider",
+            "--name",
+            "John",
+            "--data-amount",
+            "5GB",
+            "--time-period",
+            "30 days",
+            "--location",
+            "Egypt",
+            "--lpa",
+            "LPA:1$smdp.example.com$PASTED",
+            "--lpa-file",
+            codes_file.to_str().unwrap(),
+            "--qr",
+            dir.join("1.png").to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Command::Send(command)) = cli.command else {
+            panic!("expected send command");
+        };
+
+        let ops = MockEmailOperations::new(false);
+        assert_eq!(
+            run_send(&command, &ops, &mut SendLedger::default()),
+            EXIT_SUCCESS
+        );
+        let code = |id: &str| {
+            QrSource::ActivationCode(format!("LPA:1$smdp.example.com${}", id).parse().unwrap())
+        };
+        assert_eq!(
+            *ops.sent.lock().unwrap(),
+            vec![
+                QrSource::Image(dir.join("1.png")),
+                code("PASTED"),
+                code("FROM-FILE")
+            ]
+        );
+
+        fs::write(&codes_file, "LPA:1$smdp.example.com$OK\nnot a code\n").unwrap();
+        let ops = MockEmailOperations::new(false);
+        assert_eq!(
+            run_send(&command, &ops, &mut SendLedger::default()),
+            EXIT_FAILURE
+        );
+        assert!(ops.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_send_rejects_invalid_activation_code() {
+        let result = Cli::try_parse_from([
+            "esim-mailer",
+            "send",
+            "--from",
+            "me@gmail.com",
+            "--to",
+            "you@example.com",
+            "--provider",
+            "TestProvider",
+            "--name",
+            "John",
+            "--data-amount",
+            "5GB",
+            "--time-period",
+            "30 days",
+            "--location",
+            "Egypt",
+            "--lpa",
+            "LPA:1$not a host$ABC",
+        ]);
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("invalid SM-DP+ address"));
+    }
+
+    #[test]
+    fn test_run_send_token_failure() {
+        let tmp = qr_dir(&["1.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(true);
+
+        let code = run_send(
+            &send_command(vec![dir.clone()]),
+            &ops,
+            &mut SendLedger::default(),
+        );
+
+        assert_eq!(code, EXIT_FAILURE);
+        assert!(ops.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_send_unsupported_provider() {
+        let tmp = qr_dir(&["1.png"]);
+        let dir = tmp.path().to_path_buf();
+        let ops = MockEmailOperations::new(false);
+        let mut command = send_command(vec![dir.clone()]);
+        command.args.email_from = "test@unsupported.com".to_string();
+
+        assert_eq!(
+            run_send(&command, &ops, &mut SendLedger::default()),
+            EXIT_FAILURE
+        );
+    }
+}
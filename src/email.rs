@@ -1,25 +1,146 @@
 use crate::Args;
-use lettre::message::header;
+use crate::error::MailerError;
+use crate::language::Language;
+use crate::qr::{ActivationCode, QrSource};
+use crate::rate_limit::{MAX_CONNECTIONS, RateLimit};
+use crate::templates;
+use lettre::message::{Mailbox, header};
+use lettre::transport::smtp::PoolConfig;
 use lettre::transport::smtp::authentication::{Credentials, Mechanism};
-use lettre::{Message, SmtpTransport, Transport};
-use std::error::Error;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, SmtpTransport, Tokio1Executor, Transport,
+};
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::fs;
-use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use uuid;
 
+/// File extensions accepted as QR code images.
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif"];
+
+/// Line width of plain-text bodies converted from HTML.
+pub const TEXT_WIDTH: usize = 78;
+
 /// An error which can be returned when parsing a provider from an email address.
 #[derive(Debug, PartialEq, Eq, thiserror::Error)]
 #[error("No supported email provider for '{0}'")]
 pub struct ParseProviderError(String);
 
+/// How the connection to an SMTP server is secured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum TlsMode {
+    /// Plain text, only sensible for servers on the local machine
+    None,
+    /// Upgrade a plain connection with STARTTLS, usually on port 587
+    #[default]
+    StartTls,
+    /// Connect with TLS from the start, usually on port 465
+    Tls,
+}
+
+impl TlsMode {
+    /// The port conventionally used with this mode.
+    pub fn default_port(self) -> u16 {
+        match self {
+            Self::None => 25,
+            Self::StartTls => 587,
+            Self::Tls => 465,
+        }
+    }
+}
+
+impl Display for TlsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::StartTls => write!(f, "STARTTLS"),
+            Self::Tls => write!(f, "TLS"),
+        }
+    }
+}
+
+/// How to log in to an SMTP server with a password.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum AuthMechanism {
+    #[default]
+    Plain,
+    Login,
+}
+
+impl From<AuthMechanism> for Mechanism {
+    fn from(mechanism: AuthMechanism) -> Self {
+        match mechanism {
+            AuthMechanism::Plain => Mechanism::Plain,
+            AuthMechanism::Login => Mechanism::Login,
+        }
+    }
+}
+
+impl Display for AuthMechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain => write!(f, "PLAIN"),
+            Self::Login => write!(f, "LOGIN"),
+        }
+    }
+}
+
+/// Connection settings for an SMTP server that accepts a password or app
+/// password, such as Fastmail, Proton Bridge or an organization's server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub auth: AuthMechanism,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: TlsMode::default().default_port(),
+            tls: TlsMode::default(),
+            auth: AuthMechanism::default(),
+        }
+    }
+}
+
 /// An email provider.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Provider {
     Gmail,
     Outlook,
+    /// Any other SMTP server, authenticated with a password instead of OAuth.
+    Custom(SmtpConfig),
+}
+
+impl Provider {
+    /// Whether the provider is signed in to through the browser with OAuth.
+    pub fn uses_oauth(&self) -> bool {
+        !matches!(self, Self::Custom(_))
+    }
+
+    /// The sending limits of a personal account, used unless the user sets
+    /// others. Other servers have no limit unless one is set.
+    pub fn default_rate_limit(&self) -> RateLimit {
+        match self {
+            Self::Gmail => RateLimit {
+                per_minute: 20,
+                per_day: 500,
+                connections: 3,
+            },
+            Self::Outlook => RateLimit {
+                per_minute: 30,
+                per_day: 300,
+                connections: 3,
+            },
+            Self::Custom(_) => RateLimit::default(),
+        }
+    }
 }
 
 impl FromStr for Provider {
@@ -39,13 +160,131 @@ impl Display for Provider {
         match self {
             Self::Gmail => write!(f, "Gmail"),
             Self::Outlook => write!(f, "Outlook"),
+            Self::Custom(config) => write!(f, "{}", config.host),
+        }
+    }
+}
+
+/// The placeholders that can be used in templates.
+pub const PLACEHOLDERS: &[&str] = &[
+    "provider",
+    "name",
+    "data_amount",
+    "time_period",
+    "location",
+    "smdp_address",
+    "activation_code",
+    "confirmation_code",
+    "confirmation_code_required",
+    "QR_CID",
+    "esims",
+    "lang",
+    "dir",
+];
+
+/// The placeholders that can only be used in the subject.
+pub const SUBJECT_PLACEHOLDERS: &[&str] = &["number"];
+
+/// A placeholder used in a template that is neither one of [`PLACEHOLDERS`]
+/// nor a variable the template declares.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownPlaceholder {
+    pub name: String,
+    /// The known placeholder it's probably a misspelling of.
+    pub suggestion: Option<String>,
+}
+
+impl UnknownPlaceholder {
+    fn new(name: String, known: &[&str]) -> Self {
+        let suggestion = known
+            .iter()
+            .map(|known| {
+                (
+                    edit_distance(&name.to_lowercase(), &known.to_lowercase()),
+                    known,
+                )
+            })
+            // Short names are too close to everything to guess from
+            .filter(|(distance, _)| *distance <= 2 && distance * 2 <= name.chars().count())
+            .min()
+            .map(|(_, known)| known.to_string());
+        Self { name, suggestion }
+    }
+}
+
+impl Display for UnknownPlaceholder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{{{}}}}}", self.name)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " (did you mean {{{{{}}}}}?)", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error in a template, found when checking or filling it in.
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("Invalid {part} template: {source}")]
+    Syntax {
+        part: &'static str,
+        source: minijinja::Error,
+    },
+    #[error("Invalid variable name '{name}': {reason}")]
+    InvalidVariable { name: String, reason: &'static str },
+    #[error("Unknown placeholders in the {part} template: {}", join(placeholders))]
+    UnknownPlaceholders {
+        part: &'static str,
+        placeholders: Vec<UnknownPlaceholder>,
+    },
+    #[error("Could not fill in the {part} template: {source}")]
+    Render {
+        part: &'static str,
+        source: minijinja::Error,
+    },
+    #[error("Could not convert the body to plain text: {0}")]
+    PlainText(html2text::Error),
+}
+
+fn join(placeholders: &[UnknownPlaceholder]) -> String {
+    placeholders
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Levenshtein distance, used to suggest the placeholder a typo was meant to be.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
         }
     }
+    row[b.len()]
 }
 
+/// The subject and HTML body of the emails, written as
+/// [MiniJinja](https://docs.rs/minijinja) templates with `{{placeholders}}`
+/// for the values of each email. Values are HTML-escaped in the body.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EmailTemplate {
-    subject_template: &'static str,
-    body_template: &'static str,
+    pub subject_template: String,
+    pub body_template: String,
+    /// The plain-text version of the body. Without one, it's converted from
+    /// the HTML body.
+    pub text_template: Option<String>,
+    /// Names of extra placeholders the template uses, such as an ICCID or
+    /// APN settings, filled in from [`Args::variables`].
+    pub variables: Vec<String>,
 }
 
 impl Default for EmailTemplate {
@@ -55,111 +294,599 @@ impl Default for EmailTemplate {
 }
 
 impl EmailTemplate {
+    /// The English template compiled into the app.
     pub fn new() -> Self {
+        Self::built_in(Language::English)
+    }
+
+    /// The template compiled into the app for `language`.
+    pub fn built_in(language: Language) -> Self {
+        let (subject_template, body_template) = match language {
+            Language::English => (
+                "[{{provider}}] {{location}} eSIM",
+                include_str!("../templates/email_template.html"),
+            ),
+            Language::Arabic => (
+                "[{{provider}}] شريحة eSIM في {{location}}",
+                include_str!("../templates/email_template_ar.html"),
+            ),
+        };
         Self {
-            subject_template: "[{{provider}}] {{location}} eSIM",
-            body_template: include_str!("../templates/email_template.html"),
+            subject_template: subject_template.to_string(),
+            body_template: body_template.to_string(),
+            text_template: None,
+            variables: Vec::new(),
+        }
+    }
+
+    /// Checks that both parts parse and only use known placeholders, so
+    /// mistakes are reported before anything is sent.
+    pub fn check(&self) -> Result<(), TemplateError> {
+        for (index, name) in self.variables.iter().enumerate() {
+            let reason = if !is_variable_name(name) {
+                Some("use only letters, digits and underscores, not starting with a digit")
+            } else if PLACEHOLDERS.contains(&name.as_str())
+                || SUBJECT_PLACEHOLDERS.contains(&name.as_str())
+            {
+                Some("it is already a placeholder")
+            } else if self.variables[..index].contains(name) {
+                Some("it is declared twice")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(TemplateError::InvalidVariable {
+                    name: name.clone(),
+                    reason,
+                });
+            }
         }
+
+        let env = Self::environment();
+        for (part, name, source) in self.parts() {
+            let only_subject = if part == "subject" {
+                SUBJECT_PLACEHOLDERS
+            } else {
+                &[]
+            };
+            let known: Vec<&str> = PLACEHOLDERS
+                .iter()
+                .chain(only_subject)
+                .copied()
+                .chain(self.variables.iter().map(String::as_str))
+                .collect();
+            let template = env
+                .template_from_named_str(name, source)
+                .map_err(|source| TemplateError::Syntax { part, source })?;
+            let mut placeholders: Vec<_> = template
+                .undeclared_variables(false)
+                .into_iter()
+                .filter(|name| !known.contains(&name.as_str()))
+                .map(|name| UnknownPlaceholder::new(name, &known))
+                .collect();
+            if !placeholders.is_empty() {
+                placeholders.sort_by(|a, b| a.name.cmp(&b.name));
+                return Err(TemplateError::UnknownPlaceholders { part, placeholders });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the body goes through all the eSIMs of an email, so it can
+    /// carry more than one.
+    pub fn lists_esims(&self) -> bool {
+        Self::environment()
+            .template_from_named_str("body.html", &self.body_template)
+            .is_ok_and(|template| template.undeclared_variables(false).contains("esims"))
     }
 
-    pub fn subject(&self, args: &Args, count: usize) -> String {
-        let subject = self
-            .subject_template
-            .replace("{{provider}}", &args.provider)
-            .replace("{{location}}", &args.location);
-        format!("{} - {}", subject, count)
+    /// The declared variables `args` has no value for.
+    pub fn missing_variables(&self, args: &Args) -> Vec<&str> {
+        self.variables
+            .iter()
+            .filter(|name| args.variables.get(*name).is_none_or(String::is_empty))
+            .map(String::as_str)
+            .collect()
     }
 
-    pub fn body(&self, args: &Args) -> String {
-        self.body_template
-            .replace("{{provider}}", &args.provider)
-            .replace("{{name}}", &args.name)
-            .replace("{{data_amount}}", &args.data_amount)
-            .replace("{{time_period}}", &args.time_period)
-            .replace("{{location}}", &args.location)
+    /// Fills in the subject of the email at 1-based `position` in the batch.
+    /// Its number goes where the template puts `{{number}}`, or at the end if
+    /// the template doesn't, unless numbering is turned off.
+    pub fn subject(&self, args: &Args, position: usize) -> Result<String, TemplateError> {
+        let (part, name) = ("subject", "subject.txt");
+        let number = args.numbering.number(position);
+        let placed = Self::environment()
+            .template_from_named_str(name, &self.subject_template)
+            .map_err(|source| TemplateError::Syntax { part, source })?
+            .undeclared_variables(false)
+            .contains("number");
+        let subject = self.render(
+            part,
+            name,
+            &self.subject_template,
+            args,
+            &[],
+            Some(number.as_deref().unwrap_or_default()),
+        )?;
+        let subject = subject.trim();
+        Ok(match number {
+            Some(number) if !placed => format!("{} - {}", subject, number),
+            _ => subject.to_string(),
+        })
     }
+
+    /// Fills in the body, pointing the QR code image at `qr_cid`. The manual
+    /// activation details come from `activation_code` and are left as
+    /// placeholders without one.
+    pub fn body(
+        &self,
+        args: &Args,
+        activation_code: Option<&ActivationCode>,
+        qr_cid: &str,
+    ) -> Result<String, TemplateError> {
+        self.body_with_esims(
+            args,
+            &[AttachedEsim {
+                activation_code,
+                qr_cid,
+            }],
+        )
+    }
+
+    /// Fills in the body of an email carrying several eSIMs, which templates
+    /// go through with `{% for esim in esims %}`. The top-level placeholders
+    /// hold the details of the first one.
+    pub fn body_with_esims(
+        &self,
+        args: &Args,
+        esims: &[AttachedEsim],
+    ) -> Result<String, TemplateError> {
+        self.render("body", "body.html", &self.body_template, args, esims, None)
+    }
+
+    /// Fills in the plain-text body, or converts `html_body` to text if there
+    /// is no plain-text template.
+    pub fn text(
+        &self,
+        args: &Args,
+        activation_code: Option<&ActivationCode>,
+        html_body: &str,
+    ) -> Result<String, TemplateError> {
+        self.text_with_esims(
+            args,
+            &[AttachedEsim {
+                activation_code,
+                qr_cid: "",
+            }],
+            html_body,
+        )
+    }
+
+    /// Fills in the plain-text body of an email carrying several eSIMs, or
+    /// converts `html_body` to text if there is no plain-text template.
+    pub fn text_with_esims(
+        &self,
+        args: &Args,
+        esims: &[AttachedEsim],
+        html_body: &str,
+    ) -> Result<String, TemplateError> {
+        match &self.text_template {
+            Some(source) => self.render("text", "body.txt", source, args, esims, None),
+            None => html2text::from_read(html_body.as_bytes(), TEXT_WIDTH)
+                .map_err(TemplateError::PlainText),
+        }
+    }
+
+    /// Named by file type so that only the HTML body is escaped.
+    fn parts(&self) -> Vec<(&'static str, &'static str, &str)> {
+        let mut parts = vec![
+            ("subject", "subject.txt", self.subject_template.as_str()),
+            ("body", "body.html", self.body_template.as_str()),
+        ];
+        if let Some(text) = &self.text_template {
+            parts.push(("text", "body.txt", text));
+        }
+        parts
+    }
+
+    /// Placeholders without a value are errors rather than silently left
+    /// empty, and lines holding only `{% %}` tags leave no blank lines.
+    fn environment() -> minijinja::Environment<'static> {
+        let mut env = minijinja::Environment::new();
+        env.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env
+    }
+
+    /// Declared variables without a value are left as placeholders, like the
+    /// activation details without an activation code. `number` is only given
+    /// for the subject.
+    fn render(
+        &self,
+        part: &'static str,
+        name: &str,
+        source: &str,
+        args: &Args,
+        esims: &[AttachedEsim],
+        number: Option<&str>,
+    ) -> Result<String, TemplateError> {
+        let esims: Vec<_> = esims.iter().map(|esim| esim.context(args)).collect();
+        let first = esims.first().cloned().unwrap_or_else(|| {
+            AttachedEsim {
+                activation_code: None,
+                qr_cid: "",
+            }
+            .context(args)
+        });
+        let variables = minijinja::Value::from_iter(
+            self.variables
+                .iter()
+                .map(|name| {
+                    let value = match args.variables.get(name) {
+                        Some(value) if !value.is_empty() => value.clone(),
+                        _ => format!("{{{{{}}}}}", name),
+                    };
+                    (name.clone(), value)
+                })
+                .chain(number.map(|number| ("number".to_string(), number.to_string()))),
+        );
+        // The first eSIM's details stay available at the top level for
+        // templates written for a single QR code.
+        let context = minijinja::value::merge_maps([
+            minijinja::context! {
+                provider => &args.provider,
+                name => &args.name,
+                data_amount => &args.data_amount,
+                time_period => &args.time_period,
+                location => &args.location,
+                esims,
+                lang => args.language.code(),
+                dir => args.language.dir(),
+            },
+            first,
+            variables,
+        ]);
+        let env = Self::environment();
+        env.template_from_named_str(name, source)
+            .map_err(|source| TemplateError::Syntax { part, source })?
+            .render(context)
+            .map_err(|source| TemplateError::Render { part, source })
+    }
+}
+
+/// An eSIM attached to an email: the activation code its QR code holds, if
+/// known, and the Content-ID of the image.
+#[derive(Debug, Clone, Copy)]
+pub struct AttachedEsim<'a> {
+    pub activation_code: Option<&'a ActivationCode>,
+    pub qr_cid: &'a str,
+}
+
+impl AttachedEsim<'_> {
+    /// The placeholders describing this eSIM.
+    fn context(&self, args: &Args) -> minijinja::Value {
+        let (smdp_address, matching_id, confirmation_code, confirmation_code_required) = match self
+            .activation_code
+        {
+            Some(code) => {
+                let confirmation_code = match args.confirmation_code.as_deref() {
+                    Some(confirmation_code) if !confirmation_code.is_empty() => confirmation_code,
+                    _ => confirmation_code_note(args.language, code.confirmation_code_required),
+                };
+                (
+                    code.smdp_address.as_str(),
+                    code.matching_id.as_str(),
+                    confirmation_code,
+                    code.confirmation_code_required,
+                )
+            }
+            None => (
+                "{{smdp_address}}",
+                "{{activation_code}}",
+                "{{confirmation_code}}",
+                false,
+            ),
+        };
+        minijinja::context! {
+            smdp_address,
+            activation_code => matching_id,
+            confirmation_code,
+            confirmation_code_required,
+            QR_CID => self.qr_cid,
+        }
+    }
+}
+
+/// Whether `name` can be used as a template variable, i.e. is a plain
+/// identifier.
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// What to show instead of a confirmation code that wasn't given.
+fn confirmation_code_note(language: Language, required: bool) -> &'static str {
+    match (language, required) {
+        (Language::English, true) => "sent separately",
+        (Language::English, false) => "not needed",
+        (Language::Arabic, true) => "يُرسل بشكل منفصل",
+        (Language::Arabic, false) => "غير مطلوب",
+    }
+}
+
+/// Sends one eSIM email carrying `qr_codes` and returns its Message-ID.
+/// Batches should keep a [`Mailer`] instead, so as not to connect and sign
+/// in again for every email.
+pub fn send_email(
+    args: &Args,
+    token: String,
+    qr_codes: &[QrSource],
+    count: usize,
+) -> Result<String, MailerError> {
+    Mailer::new(args.email_provider()?, &args.email_from, token)?.send(args, qr_codes, count)
+}
+
+/// A connection to the mail server, signed in as one account, that sends
+/// every email of a batch. It is opened on the first email, and opened again
+/// if the server closes it in between.
+pub struct Mailer {
+    provider: Provider,
+    email_from: String,
+    token: String,
+    transport: SmtpTransport,
+}
+
+impl Mailer {
+    pub fn new(provider: Provider, email_from: &str, token: String) -> Result<Self, MailerError> {
+        let transport = configure_mailer(&provider, email_from, token.clone())?;
+        Ok(Self {
+            provider,
+            email_from: email_from.to_string(),
+            token,
+            transport,
+        })
+    }
+
+    /// Whether the mailer is signed in as `email_from` on `provider` with
+    /// `token`, so that it can send for them.
+    pub fn signs_in_as(&self, provider: &Provider, email_from: &str, token: &str) -> bool {
+        self.provider == *provider && self.email_from == email_from && self.token == token
+    }
+
+    /// Sends one eSIM email carrying `qr_codes` and returns its Message-ID.
+    pub fn send(
+        &self,
+        args: &Args,
+        qr_codes: &[QrSource],
+        count: usize,
+    ) -> Result<String, MailerError> {
+        let (email, message_id) = build_email(args, qr_codes, count)?;
+        match self.transport.send(&email) {
+            Ok(_) => Ok(message_id),
+            Err(e) => Err(MailerError::from_smtp(e, &self.provider, &self.email_from)),
+        }
+    }
+}
+
+/// A [`Mailer`] for the tokio runtime, which can send several emails of a
+/// batch at the same time over a connection each.
+pub struct AsyncMailer {
+    provider: Provider,
+    email_from: String,
+    token: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl AsyncMailer {
+    pub fn new(provider: Provider, email_from: &str, token: String) -> Result<Self, MailerError> {
+        let transport = configure_async_mailer(&provider, email_from, token.clone())?;
+        Ok(Self {
+            provider,
+            email_from: email_from.to_string(),
+            token,
+            transport,
+        })
+    }
+
+    /// Whether the mailer is signed in as `email_from` on `provider` with
+    /// `token`, so that it can send for them.
+    pub fn signs_in_as(&self, provider: &Provider, email_from: &str, token: &str) -> bool {
+        self.provider == *provider && self.email_from == email_from && self.token == token
+    }
+
+    /// Sends one eSIM email carrying `qr_codes` and returns its Message-ID.
+    pub async fn send(
+        &self,
+        args: &Args,
+        qr_codes: &[QrSource],
+        count: usize,
+    ) -> Result<String, MailerError> {
+        // Reading, decoding and resizing the QR code images blocks, so it is
+        // done off the runtime's worker threads
+        let (args, qr_codes) = (args.clone(), qr_codes.to_vec());
+        let (email, message_id) =
+            tokio::task::spawn_blocking(move || build_email(&args, &qr_codes, count))
+                .await
+                .map_err(std::io::Error::other)??;
+        match self.transport.send(email).await {
+            Ok(_) => Ok(message_id),
+            Err(e) => Err(MailerError::from_smtp(e, &self.provider, &self.email_from)),
+        }
+    }
+}
+
+/// Writes the email [`send_email`] would send to an `.eml` file in `dir`
+/// instead of sending it, and returns the file's path. BCC recipients only
+/// appear in the SMTP envelope, so the file doesn't list them.
+pub fn write_eml(
+    args: &Args,
+    qr_codes: &[QrSource],
+    count: usize,
+    dir: &Path,
+) -> Result<PathBuf, MailerError> {
+    let (email, _) = build_email(args, qr_codes, count)?;
+    fs::create_dir_all(dir)?;
+    let path = dir.join(eml_file_name(count, &args.email_to));
+    fs::write(&path, email.formatted())?;
+    Ok(path)
+}
+
+/// Names the file of the email at `count` in the batch after its
+/// recipient, so that the files sort in sending order.
+fn eml_file_name(count: usize, email_to: &str) -> String {
+    let recipient: String = email_to
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "@.-_+".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{:03}-{}.eml", count, recipient)
 }
 
-pub fn send_email(args: &Args, token: String, image_path: &Path, count: usize) -> io::Result<()> {
+/// Builds one eSIM email and returns it with its Message-ID. Text-only mail
+/// clients show the plain-text alternative; the others show the HTML body
+/// with the QR code images inline.
+pub(crate) fn build_email(
+    args: &Args,
+    qr_codes: &[QrSource],
+    count: usize,
+) -> Result<(Message, String), MailerError> {
     let email_from = &args.email_from;
     let email_to = &args.email_to;
 
     // Get template content
-    let template = EmailTemplate::new();
+    let template = templates::load_template(args.template.as_deref(), args.language)?;
+    let missing = template.missing_variables(args);
+    if !missing.is_empty() {
+        return Err(MailerError::MissingVariables(
+            missing.into_iter().map(str::to_string).collect(),
+        ));
+    }
+    if qr_codes.is_empty() {
+        return Err(MailerError::NoQrCode);
+    }
+    if qr_codes.len() > 1 && !template.lists_esims() {
+        return Err(MailerError::SingleQrTemplate);
+    }
 
-    // Read image file
-    let image_data = fs::read(image_path)?;
+    // Read the images or render the activation codes, refusing anything that
+    // isn't an eSIM QR code before connecting
+    let mut attachments = Vec::new();
+    for qr_code in qr_codes {
+        let qr_image = qr_code
+            .load()
+            .and_then(|image| {
+                if args.normalize_images {
+                    image.normalize()
+                } else {
+                    Ok(image)
+                }
+            })
+            .map_err(|source| MailerError::AttachmentUnreadable {
+                qr_code: qr_code.clone(),
+                source: Box::new(source),
+            })?;
+        // Always valid, as the MIME types come from the image crate
+        let content_type = header::ContentType::parse(qr_image.mime_type()).unwrap();
+        // Generate a unique Content-ID for each image
+        let content_id = format!("qr_image_cid@{}", uuid::Uuid::new_v4());
+        attachments.push((qr_image, content_type, content_id));
+    }
+    let esims: Vec<AttachedEsim> = attachments
+        .iter()
+        .map(|(qr_image, _, content_id)| AttachedEsim {
+            activation_code: Some(&qr_image.activation_code),
+            qr_cid: content_id,
+        })
+        .collect();
 
-    // Get subject and body content
-    let subject = template.subject(args, count);
-    // Generate a unique Content-ID for the image
-    let content_id = format!("qr_image_cid@{}", uuid::Uuid::new_v4());
+    // Fill in the subject and body, pointing the images at their Content-IDs
+    let subject = template.subject(args, count)?;
+    let body = template.body_with_esims(args, &esims)?;
+    let text = template.text_with_esims(args, &esims, &body)?;
 
-    // Get the body content and replace the QR_CID placeholder with the actual Content-ID
-    let body_content = template.body(args);
-    let body = body_content.replace("{{QR_CID}}", &content_id);
+    // Generate a Message-ID so the email can be traced in the send ledger
+    let domain = email_from.rsplit_once('@').map_or("localhost", |(_, d)| d);
+    let message_id = format!("<{}@{}>", uuid::Uuid::new_v4(), domain);
 
     // Create multipart email with HTML body and image attachment
     let mut email_builder = Message::builder()
-        .from(
-            email_from
-                .parse()
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
-        )
-        .to(email_to
-            .parse()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
-        .subject(subject);
+        .from(parse_mailbox("From", email_from)?)
+        .to(parse_mailbox("To", email_to)?)
+        .subject(subject)
+        .message_id(Some(message_id.clone()));
 
     // Add BCC if provided and not empty
-    if let Some(bcc) = &args.bcc {
-        if !bcc.is_empty() {
-            email_builder = email_builder.bcc(
-                bcc.parse()
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
-            );
-        }
+    if let Some(bcc) = &args.bcc
+        && !bcc.is_empty()
+    {
+        email_builder = email_builder.bcc(parse_mailbox("BCC", bcc)?);
     }
 
-    // Build the email with multipart/related content
+    // Build the email with the plain text as an alternative to the
+    // multipart/related HTML and images
+    let mut related = lettre::message::MultiPart::related().singlepart(
+        lettre::message::SinglePart::builder()
+            .header(header::ContentType::TEXT_HTML)
+            .body(body),
+    );
+    for (qr_image, content_type, content_id) in attachments {
+        related = related.singlepart(
+            lettre::message::Attachment::new_inline(content_id).body(qr_image.data, content_type),
+        );
+    }
     let email = email_builder
         .multipart(
-            lettre::message::MultiPart::related()
-                .singlepart(
-                    lettre::message::SinglePart::builder()
-                        .header(header::ContentType::TEXT_HTML)
-                        .body(body),
-                )
-                .singlepart(
-                    lettre::message::Attachment::new_inline(content_id)
-                        .body(image_data, header::ContentType::parse("image/png").unwrap()),
-                ),
+            lettre::message::MultiPart::alternative()
+                .singlepart(lettre::message::SinglePart::plain(text))
+                .multipart(related),
         )
         .unwrap();
 
-    // Configure SMTP client with TLS
-    let provider: Provider = email_from
+    Ok((email, message_id))
+}
+
+fn parse_mailbox(field: &'static str, address: &str) -> Result<Mailbox, MailerError> {
+    address
         .parse()
-        // TODO: Ideally this wouldn't get mapped to an io::Error, but right now
-        // the function signature requires it.
-        .map_err(|_| io::Error::other("Unsupported email provider"))?;
-    let mailer = configure_mailer(&provider, email_from, token)?;
-
-    // Send the email
-    match mailer.send(&email) {
-        Ok(_) => {
-            println!("Email sent successfully!");
-            Ok(())
+        .map_err(|source| MailerError::InvalidAddress {
+            field,
+            address: address.to_string(),
+            source,
+        })
+}
+
+/// The server, port, encryption and login to send through `provider` with.
+fn smtp_settings(
+    provider: &Provider,
+    email_address: &str,
+) -> Result<(String, u16, Tls, Mechanism), MailerError> {
+    match provider {
+        Provider::Gmail | Provider::Outlook => {
+            let host = match provider {
+                Provider::Gmail => "smtp.gmail.com",
+                _ => "smtp-mail.outlook.com",
+            };
+            let tls = Tls::Required(TlsParameters::new(host.to_string()).unwrap());
+            Ok((host.to_string(), 587, tls, Mechanism::Xoauth2))
         }
-        Err(e) => {
-            eprintln!("Could not send email: {:?}", e);
-            if let Some(source) = e.source() {
-                eprintln!("Error source: {:?}", source);
-            }
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Could not send email: {}", e),
-            ))
+        Provider::Custom(config) => {
+            let tls_parameters = TlsParameters::new(config.host.clone())
+                .map_err(|e| MailerError::from_smtp(e, provider, email_address))?;
+            let tls = match config.tls {
+                TlsMode::None => Tls::None,
+                TlsMode::StartTls => Tls::Required(tls_parameters),
+                TlsMode::Tls => Tls::Wrapper(tls_parameters),
+            };
+            Ok((config.host.clone(), config.port, tls, config.auth.into()))
         }
     }
 }
@@ -168,36 +895,42 @@ fn configure_mailer(
     provider: &Provider,
     email_address: &str,
     token: String,
-) -> io::Result<SmtpTransport> {
-    match provider {
-        Provider::Gmail => Ok(SmtpTransport::relay("smtp.gmail.com")
-            .unwrap()
-            .credentials(Credentials::new(email_address.to_string(), token))
-            .authentication(vec![Mechanism::Xoauth2])
-            .port(587)
-            .tls(lettre::transport::smtp::client::Tls::Required(
-                lettre::transport::smtp::client::TlsParameters::new("smtp.gmail.com".to_string())
-                    .unwrap(),
-            ))
-            .build()),
-        Provider::Outlook => Ok(SmtpTransport::relay("smtp-mail.outlook.com")
-            .unwrap()
+) -> Result<SmtpTransport, MailerError> {
+    let (host, port, tls, mechanism) = smtp_settings(provider, email_address)?;
+    Ok(SmtpTransport::builder_dangerous(host)
+        .credentials(Credentials::new(email_address.to_string(), token))
+        .authentication(vec![mechanism])
+        .port(port)
+        .tls(tls)
+        // A single connection, which the pool checks is still open before
+        // each email and opens again if the server closed it
+        .pool_config(PoolConfig::new().max_size(1))
+        .build())
+}
+
+fn configure_async_mailer(
+    provider: &Provider,
+    email_address: &str,
+    token: String,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, MailerError> {
+    let (host, port, tls, mechanism) = smtp_settings(provider, email_address)?;
+    Ok(
+        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
             .credentials(Credentials::new(email_address.to_string(), token))
-            .authentication(vec![Mechanism::Xoauth2])
-            .port(587)
-            .tls(lettre::transport::smtp::client::Tls::Required(
-                lettre::transport::smtp::client::TlsParameters::new(
-                    "smtp-mail.outlook.com".to_string(),
-                )
-                .unwrap(),
-            ))
-            .build()),
-    }
+            .authentication(vec![mechanism])
+            .port(port)
+            .tls(tls)
+            // Connections are only opened for emails sent at the same time
+            .pool_config(PoolConfig::new().max_size(MAX_CONNECTIONS))
+            .build(),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::qr::QrError;
+    use std::io;
 
     #[test]
     fn test_email_template_subject() {
@@ -211,11 +944,55 @@ mod tests {
             data_amount: "5GB".to_string(),
             time_period: "30 days".to_string(),
             location: "Egypt".to_string(),
+            confirmation_code: None,
+            bundle: false,
+            normalize_images: false,
+            template: None,
+            language: Language::English,
+            variables: Default::default(),
+            numbering: Default::default(),
+            smtp: None,
         };
-        let result = template.subject(&args, 1);
+        let result = template.subject(&args, 1).unwrap();
         assert_eq!(result, "[TestProvider] Egypt eSIM - 1");
     }
 
+    #[test]
+    fn test_email_template_subject_numbering() {
+        let mut args = Args {
+            location: "Gaza".to_string(),
+            ..Default::default()
+        };
+        args.numbering.start = 40;
+        args.numbering.width = 3;
+        let template = EmailTemplate::new();
+        assert_eq!(template.subject(&args, 3).unwrap(), "[] Gaza eSIM - 042");
+
+        let template = EmailTemplate {
+            subject_template: "#{{ number }} {{location}} eSIM".to_string(),
+            ..EmailTemplate::new()
+        };
+        assert!(template.check().is_ok());
+        assert_eq!(template.subject(&args, 1).unwrap(), "#040 Gaza eSIM");
+
+        args.numbering.enabled = false;
+        assert_eq!(template.subject(&args, 1).unwrap(), "# Gaza eSIM");
+        assert_eq!(
+            EmailTemplate::new().subject(&args, 1).unwrap(),
+            "[] Gaza eSIM"
+        );
+
+        // The number only exists for the subject
+        let template = EmailTemplate {
+            body_template: "{{number}}".to_string(),
+            ..EmailTemplate::new()
+        };
+        assert!(matches!(
+            template.check(),
+            Err(TemplateError::UnknownPlaceholders { part: "body", .. })
+        ));
+    }
+
     #[test]
     fn test_email_template_body() {
         let template = EmailTemplate::new();
@@ -228,13 +1005,310 @@ mod tests {
             data_amount: "5GB".to_string(),
             time_period: "30 days".to_string(),
             location: "Egypt".to_string(),
+            confirmation_code: None,
+            bundle: false,
+            normalize_images: false,
+            template: None,
+            language: Language::English,
+            variables: Default::default(),
+            numbering: Default::default(),
+            smtp: None,
         };
-        let result = template.body(&args);
+        let result = template.body(&args, None, "cid").unwrap();
         assert!(result.contains("John"));
         assert!(result.contains("TestProvider"));
         assert!(result.contains("5GB"));
         assert!(result.contains("30 days"));
         assert!(result.contains("Egypt"));
+        assert!(result.contains("{{smdp_address}}"));
+        assert!(result.contains("cid:cid"));
+    }
+
+    #[test]
+    fn test_email_template_body_activation_details() {
+        let template = EmailTemplate::new();
+        let mut args = Args::default();
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-ABC123".parse().unwrap();
+
+        let result = template.body(&args, Some(&code), "cid").unwrap();
+        assert!(result.contains("smdp.example.com"));
+        assert!(result.contains("K2-ABC123"));
+        assert!(result.contains("not needed"));
+        assert!(!result.contains("{{activation_code}}"));
+
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-ABC123$$1".parse().unwrap();
+        let result = template.body(&args, Some(&code), "cid").unwrap();
+        assert!(result.contains("sent separately"));
+
+        args.confirmation_code = Some("4821".to_string());
+        let result = template.body(&args, Some(&code), "cid").unwrap();
+        assert!(result.contains("4821"));
+    }
+
+    #[test]
+    fn test_email_template_arabic() {
+        let template = EmailTemplate::built_in(Language::Arabic);
+        assert!(template.check().is_ok());
+        let args = Args {
+            provider: "Nomad".to_string(),
+            location: "Gaza".to_string(),
+            language: Language::Arabic,
+            ..Default::default()
+        };
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-ABC123$$1".parse().unwrap();
+
+        let body = template.body(&args, Some(&code), "cid").unwrap();
+        assert!(body.starts_with("<html lang=\"ar\" dir=\"rtl\">"));
+        assert!(body.contains("<b dir=\"ltr\">smdp.example.com</b>"));
+        assert!(body.contains("يُرسل بشكل منفصل"));
+        assert_eq!(
+            template.subject(&args, 3).unwrap(),
+            "[Nomad] شريحة eSIM في Gaza - 3"
+        );
+
+        let template = EmailTemplate {
+            body_template: "<p lang=\"{{lang}}\" dir=\"{{dir}}\">{{confirmation_code}}</p>"
+                .to_string(),
+            ..EmailTemplate::new()
+        };
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-ABC123".parse().unwrap();
+        assert_eq!(
+            template.body(&args, Some(&code), "cid").unwrap(),
+            "<p lang=\"ar\" dir=\"rtl\">غير مطلوب</p>"
+        );
+    }
+
+    #[test]
+    fn test_email_template_escapes_body() {
+        let template = EmailTemplate {
+            subject_template: "{{name}} & co".to_string(),
+            body_template: "<p>{{name}}</p>{% if confirmation_code_required %}<p>Ask me for the code</p>{% endif %}".to_string(),
+            text_template: None,
+            variables: Vec::new(),
+        };
+        let args = Args {
+            name: "<Tom & Jerry>".to_string(),
+            ..Default::default()
+        };
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-ABC123$$1".parse().unwrap();
+
+        assert_eq!(
+            template.subject(&args, 2).unwrap(),
+            "<Tom & Jerry> & co - 2"
+        );
+        assert_eq!(
+            template.body(&args, Some(&code), "cid").unwrap(),
+            "<p>&lt;Tom &amp; Jerry&gt;</p><p>Ask me for the code</p>"
+        );
+    }
+
+    #[test]
+    fn test_email_template_text() {
+        let args = Args {
+            name: "<Tom & Jerry>".to_string(),
+            ..Default::default()
+        };
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-ABC123".parse().unwrap();
+
+        let template = EmailTemplate::new();
+        let html = template.body(&args, Some(&code), "cid").unwrap();
+        let text = template.text(&args, Some(&code), &html).unwrap();
+        assert!(text.contains("<Tom & Jerry>"));
+        assert!(text.contains("SM-DP+ address: **smdp.example.com**"));
+        assert!(!text.contains("<p>"));
+
+        let template = EmailTemplate {
+            text_template: Some("{{name}}: {{smdp_address}}${{activation_code}}".to_string()),
+            ..EmailTemplate::new()
+        };
+        assert_eq!(
+            template.text(&args, Some(&code), &html).unwrap(),
+            "<Tom & Jerry>: smdp.example.com$K2-ABC123"
+        );
+
+        let template = EmailTemplate {
+            text_template: Some("{{ nmae }}".to_string()),
+            ..EmailTemplate::new()
+        };
+        let err = template.check().unwrap_err();
+        assert!(matches!(
+            err,
+            TemplateError::UnknownPlaceholders { part: "text", .. }
+        ));
+    }
+
+    #[test]
+    fn test_build_email_multipart_alternative() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image_path = tmp.path().join("qr.png");
+        crate::qr::write_test_qr(&image_path, "LPA:1$smdp.example.com$TEST");
+        let args = Args {
+            email_from: "sender@example.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            location: "Gaza".to_string(),
+            ..Default::default()
+        };
+
+        let (email, message_id) = build_email(&args, &[QrSource::Image(image_path)], 1).unwrap();
+
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains(&format!("Message-ID: {}", message_id)));
+        let alternative = formatted.find("multipart/alternative").unwrap();
+        let plain = formatted.find("text/plain").unwrap();
+        let related = formatted.find("multipart/related").unwrap();
+        let html = formatted.find("text/html").unwrap();
+        let image = formatted.find("image/png").unwrap();
+        assert!(alternative < plain && plain < related && related < html && html < image);
+    }
+
+    #[test]
+    fn test_write_eml() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let args = Args {
+            email_from: "sender@example.com".to_string(),
+            email_to: "Ann <ann@example.com>".to_string(),
+            ..Default::default()
+        };
+        let qr_code = QrSource::ActivationCode("LPA:1$smdp.example.com$EML".parse().unwrap());
+
+        let path = write_eml(&args, &[qr_code], 12, dir).unwrap();
+        assert_eq!(path, dir.join("012-Ann__ann@example.com_.eml"));
+        let eml = fs::read_to_string(&path).unwrap();
+        assert!(eml.contains("Message-ID: <"));
+        assert!(eml.contains("Content-Type: image/png"));
+        assert!(eml.contains("- 12"));
+    }
+
+    #[test]
+    fn test_build_email_several_qr_codes() {
+        let args = Args {
+            email_from: "sender@example.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let qr_codes: Vec<QrSource> = ["LPA:1$smdp.example.com$ONE", "LPA:1$smdp.example.com$TWO"]
+            .iter()
+            .map(|code| QrSource::ActivationCode(code.parse().unwrap()))
+            .collect();
+
+        let (email, _) = build_email(&args, &qr_codes, 1).unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert_eq!(formatted.matches("Content-Type: image/png").count(), 2);
+        assert!(formatted.contains("eSIM 2"));
+        assert!(formatted.contains("<b>ONE</b>") && formatted.contains("<b>TWO</b>"));
+
+        let err = build_email(&args, &[], 1).unwrap_err();
+        assert_eq!(err.to_string(), "No QR code to send");
+
+        assert!(EmailTemplate::new().lists_esims());
+        let single = EmailTemplate {
+            body_template: "<img src=\"cid:{{QR_CID}}\">".to_string(),
+            ..EmailTemplate::new()
+        };
+        assert!(!single.lists_esims());
+        let esims: Vec<AttachedEsim> = qr_codes
+            .iter()
+            .map(|qr_code| AttachedEsim {
+                activation_code: match qr_code {
+                    QrSource::ActivationCode(code) => Some(code),
+                    QrSource::Image(_) => None,
+                },
+                qr_cid: "cid",
+            })
+            .collect();
+        let body = EmailTemplate::new().body_with_esims(&args, &esims).unwrap();
+        assert!(body.contains("eSIM 1") && body.contains("eSIM 2"));
+    }
+
+    #[test]
+    fn test_email_template_check() {
+        assert!(EmailTemplate::new().check().is_ok());
+
+        let template = EmailTemplate {
+            subject_template: "{{ location }} eSIM".to_string(),
+            body_template:
+                "{{nmae}} {{Location}} {{ qr }} {% for line in [1] %}{{ line }}{% endfor %}"
+                    .to_string(),
+            text_template: None,
+            variables: Vec::new(),
+        };
+        let err = template.check().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown placeholders in the body template: {{Location}} (did you mean {{location}}?), \
+             {{nmae}} (did you mean {{name}}?), {{qr}}"
+        );
+
+        let template = EmailTemplate {
+            subject_template: "{% if provider %}{{ provider }}".to_string(),
+            ..EmailTemplate::new()
+        };
+        let err = template.check().unwrap_err();
+        assert!(matches!(
+            err,
+            TemplateError::Syntax {
+                part: "subject",
+                ..
+            }
+        ));
+
+        // Placeholders that are known but not filled in fail when rendering
+        let template = EmailTemplate {
+            subject_template: "{{ provider.missing }}".to_string(),
+            ..EmailTemplate::new()
+        };
+        assert!(template.check().is_ok());
+        let err = template.subject(&Args::default(), 1).unwrap_err();
+        assert!(matches!(
+            err,
+            TemplateError::Render {
+                part: "subject",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_email_template_variables() {
+        let mut template = EmailTemplate {
+            subject_template: "{{location}} eSIM".to_string(),
+            body_template: "<p>ICCID {{ iccid }}, APN {{apn}}</p>".to_string(),
+            text_template: None,
+            variables: vec!["iccid".to_string(), "apn".to_string()],
+        };
+        assert!(template.check().is_ok());
+
+        let mut args = Args::default();
+        args.variables
+            .insert("iccid".to_string(), "8901".to_string());
+        assert_eq!(template.missing_variables(&args), vec!["apn"]);
+        assert_eq!(
+            template.body(&args, None, "cid").unwrap(),
+            "<p>ICCID 8901, APN {{apn}}</p>"
+        );
+        args.variables
+            .insert("apn".to_string(), "<internet>".to_string());
+        assert!(template.missing_variables(&args).is_empty());
+        assert_eq!(
+            template.body(&args, None, "cid").unwrap(),
+            "<p>ICCID 8901, APN &lt;internet&gt;</p>"
+        );
+
+        template.body_template = "{{ icid }}".to_string();
+        assert_eq!(
+            template.check().unwrap_err().to_string(),
+            "Unknown placeholders in the body template: {{icid}} (did you mean {{iccid}}?)"
+        );
+
+        for name in ["2fa", "support link", "name", "apn"] {
+            template.variables.push(name.to_string());
+            assert!(matches!(
+                template.check(),
+                Err(TemplateError::InvalidVariable { .. })
+            ));
+            template.variables.pop();
+        }
     }
 
     #[test]
@@ -267,18 +1341,160 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_configure_mailer_custom() {
+        for tls in [TlsMode::None, TlsMode::StartTls, TlsMode::Tls] {
+            let provider = Provider::Custom(SmtpConfig {
+                host: "smtp.fastmail.com".to_string(),
+                port: tls.default_port(),
+                tls,
+                auth: AuthMechanism::Login,
+            });
+            let result = configure_mailer(&provider, "me@fastmail.com", "password".to_string());
+            assert!(result.is_ok());
+        }
+    }
+
+    /// Runs a mail server on a local port that accepts `connections` one
+    /// after the other, each closed after `limit` emails. Returns the port
+    /// and a handle giving the number of emails received on each connection.
+    fn fake_smtp_server(
+        connections: usize,
+        limit: usize,
+    ) -> (u16, std::thread::JoinHandle<Vec<usize>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                let mut line = String::new();
+                let mut count = 0;
+                writer.write_all(b"220 localhost ready\r\n").unwrap();
+                while count < limit {
+                    line.clear();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let command = line.to_ascii_uppercase();
+                    let reply: &[u8] = if command.starts_with("EHLO") {
+                        b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                    } else if command.starts_with("AUTH") {
+                        b"235 2.7.0 Accepted\r\n"
+                    } else if command.starts_with("DATA") {
+                        writer.write_all(b"354 Go ahead\r\n").unwrap();
+                        loop {
+                            line.clear();
+                            if reader.read_line(&mut line).unwrap_or(0) == 0 || line == ".\r\n" {
+                                break;
+                            }
+                        }
+                        count += 1;
+                        b"250 2.0.0 Queued\r\n"
+                    } else if command.starts_with("QUIT") {
+                        b"221 Bye\r\n"
+                    } else {
+                        b"250 OK\r\n"
+                    };
+                    let _ = writer.write_all(reply);
+                }
+                received.push(count);
+            }
+            received
+        });
+        (port, server)
+    }
+
+    fn local_mailer(port: u16) -> (Mailer, Args) {
+        let config = SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: TlsMode::None,
+            auth: AuthMechanism::Plain,
+        };
+        let args = Args {
+            email_from: "me@example.com".to_string(),
+            email_to: "you@example.com".to_string(),
+            smtp: Some(config.clone()),
+            ..Default::default()
+        };
+        let mailer = Mailer::new(
+            Provider::Custom(config),
+            &args.email_from,
+            "password".to_string(),
+        )
+        .unwrap();
+        (mailer, args)
+    }
+
+    fn activation_code(id: &str) -> Vec<QrSource> {
+        vec![QrSource::ActivationCode(
+            format!("LPA:1$smdp.example.com${}", id).parse().unwrap(),
+        )]
+    }
+
+    #[test]
+    fn test_mailer_reuses_connection() {
+        let (port, server) = fake_smtp_server(1, usize::MAX);
+        let (mailer, args) = local_mailer(port);
+        assert!(mailer.signs_in_as(
+            &args.email_provider().unwrap(),
+            "me@example.com",
+            "password"
+        ));
+        assert!(!mailer.signs_in_as(&args.email_provider().unwrap(), "me@example.com", "new"));
+
+        for (count, id) in ["A", "B", "C"].into_iter().enumerate() {
+            mailer.send(&args, &activation_code(id), count + 1).unwrap();
+        }
+        drop(mailer);
+
+        assert_eq!(server.join().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn test_mailer_reconnects_when_dropped() {
+        // The server hangs up after every second email
+        let (port, server) = fake_smtp_server(2, 2);
+        let (mailer, args) = local_mailer(port);
+
+        for (count, id) in ["A", "B", "C"].into_iter().enumerate() {
+            mailer.send(&args, &activation_code(id), count + 1).unwrap();
+        }
+        drop(mailer);
+
+        assert_eq!(server.join().unwrap(), vec![2, 1]);
+    }
+
     #[test]
     fn test_provider_display() {
         assert_eq!(Provider::Gmail.to_string(), "Gmail");
         assert_eq!(Provider::Outlook.to_string(), "Outlook");
+        let custom = Provider::Custom(SmtpConfig {
+            host: "smtp.fastmail.com".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(custom.to_string(), "smtp.fastmail.com");
+        assert!(!custom.uses_oauth());
+        assert!(Provider::Gmail.uses_oauth());
+    }
+
+    #[test]
+    fn test_auth_mechanism_conversion() {
+        assert_eq!(Mechanism::from(AuthMechanism::Plain), Mechanism::Plain);
+        assert_eq!(Mechanism::from(AuthMechanism::Login), Mechanism::Login);
     }
 
     #[test]
     fn test_send_email() -> io::Result<()> {
         // Create a temporary test image
-        let temp_dir = std::env::temp_dir();
-        let image_path = temp_dir.join("test_image.png");
-        fs::write(&image_path, b"fake image data")?;
+        let temp_dir = tempfile::tempdir()?;
+        let image_path = temp_dir.path().join("test_image.png");
+        crate::qr::write_test_qr(&image_path, "LPA:1$smdp.example.com$TEST");
 
         let args = Args {
             email_from: "test@gmail.com".to_string(),
@@ -289,13 +1505,23 @@ mod tests {
             data_amount: "1GB".to_string(),
             time_period: "7 days".to_string(),
             location: "TestLocation".to_string(),
+            confirmation_code: None,
+            bundle: false,
+            normalize_images: false,
+            template: None,
+            language: Language::English,
+            variables: Default::default(),
+            numbering: Default::default(),
+            smtp: None,
         };
 
         // Test the function - it should fail when trying to send
-        let result = send_email(&args, "fake_token".to_string(), &image_path, 1);
-
-        // Clean up the temporary file
-        fs::remove_file(image_path)?;
+        let result = send_email(
+            &args,
+            "fake_token".to_string(),
+            &[QrSource::Image(image_path)],
+            1,
+        );
 
         // We expect an error from the SMTP client
         assert!(result.is_err());
@@ -320,24 +1546,76 @@ mod tests {
             data_amount: "1GB".to_string(),
             time_period: "7 days".to_string(),
             location: "TestLocation".to_string(),
+            confirmation_code: None,
+            bundle: false,
+            normalize_images: false,
+            template: None,
+            language: Language::English,
+            variables: Default::default(),
+            numbering: Default::default(),
+            smtp: None,
         };
 
         // Create a temporary test image first
-        let temp_dir = std::env::temp_dir();
-        let image_path = temp_dir.join("test_image2.png");
-        fs::write(&image_path, b"fake image data").unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let image_path = temp_dir.path().join("test_image2.png");
+        crate::qr::write_test_qr(&image_path, "LPA:1$smdp.example.com$TEST");
+
+        let result = send_email(
+            &args,
+            "fake_token".to_string(),
+            &[QrSource::Image(image_path)],
+            1,
+        );
 
-        let result = send_email(&args, "fake_token".to_string(), &image_path, 1);
+        assert!(matches!(
+            result.unwrap_err(),
+            MailerError::ProviderUnsupported(_)
+        ));
+    }
 
-        // Clean up
-        fs::remove_file(image_path).unwrap();
+    #[test]
+    fn test_send_email_rejects_invalid_qr_code() {
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let image_path = tmp.path().join("website.png");
+        crate::qr::write_test_qr(&image_path, "https://example.com");
 
-        assert!(result.is_err());
-        assert!(
-            result
-                .unwrap_err()
-                .to_string()
-                .contains("Unsupported email provider")
+        let result = send_email(
+            &args,
+            "fake_token".to_string(),
+            &[QrSource::Image(image_path)],
+            1,
         );
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            &err,
+            MailerError::AttachmentUnreadable { source, .. } if matches!(**source, QrError::Invalid(_))
+        ));
+        assert!(err.to_string().contains("not an eSIM activation code"));
+    }
+
+    #[test]
+    fn test_send_email_missing_image() {
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let qr_code = QrSource::Image(tmp.path().join("missing.png"));
+
+        let result = send_email(&args, "fake_token".to_string(), &[qr_code], 1);
+
+        assert!(matches!(
+            result.unwrap_err(),
+            MailerError::AttachmentUnreadable { source, .. }
+                if matches!(&*source, QrError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+        ));
     }
 }
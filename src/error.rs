@@ -0,0 +1,292 @@
+use std::io;
+
+use lettre::address::AddressError;
+use lettre::transport::smtp;
+
+use crate::email::{ParseProviderError, Provider, TemplateError};
+use crate::qr::{QrError, QrSource};
+
+/// Why an eSIM email could not be built or sent.
+///
+/// Errors are sorted by what can be done about them: transient ones are
+/// worth retrying, sign-in ones need the user to sign in again, and the rest
+/// only affect the email they happened to, which can be skipped.
+#[derive(Debug, thiserror::Error)]
+pub enum MailerError {
+    #[error("Invalid {field} address '{address}': {source}")]
+    InvalidAddress {
+        /// The header the address was meant for: "From", "To" or "BCC".
+        field: &'static str,
+        address: String,
+        source: AddressError,
+    },
+    #[error(transparent)]
+    ProviderUnsupported(#[from] ParseProviderError),
+    #[error("Could not sign in: {0}")]
+    SignIn(#[source] io::Error),
+    #[error("Could not send email, the sign-in for {email} has expired or was revoked: {source}")]
+    TokenExpired { email: String, source: smtp::Error },
+    #[error("Could not send email, the server rejected the password for {email}: {source}")]
+    AuthRejected { email: String, source: smtp::Error },
+    #[error("Could not send email, the server may accept it later: {0}")]
+    SmtpTransient(#[source] smtp::Error),
+    #[error("Could not send email: {0}")]
+    SmtpPermanent(#[source] smtp::Error),
+    #[error("The connection failed while sending, the email may have been delivered: {0}")]
+    SmtpUncertain(#[source] smtp::Error),
+    #[error("{qr_code}: {source}")]
+    AttachmentUnreadable {
+        qr_code: QrSource,
+        source: Box<QrError>,
+    },
+    #[error(transparent)]
+    Template(#[from] TemplateError),
+    #[error("Missing values for template variables: {}", .0.join(", "))]
+    MissingVariables(Vec<String>),
+    #[error("No QR code to send")]
+    NoQrCode,
+    #[error(
+        "The template shows a single QR code, go through them with {{% for esim in esims %}} \
+         to send several in one email"
+    )]
+    SingleQrTemplate,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl MailerError {
+    /// Sorts an error from the SMTP server sending as `email` through
+    /// `provider`. Rejected logins need a new sign-in, and 4xx replies and
+    /// servers that can't be reached may go away by themselves. A timeout or
+    /// lost connection after that may come once the server has taken the
+    /// email, so it isn't worth the risk of sending an eSIM twice.
+    pub fn from_smtp(error: smtp::Error, provider: &Provider, email: &str) -> Self {
+        let email = email.to_string();
+        match error.status().map(u16::from) {
+            // Authentication required, too weak or failed
+            Some(530 | 534 | 535) if provider.uses_oauth() => Self::TokenExpired {
+                email,
+                source: error,
+            },
+            Some(530 | 534 | 535) => Self::AuthRejected {
+                email,
+                source: error,
+            },
+            _ if error.is_transient() || is_connect_error(&error) => Self::SmtpTransient(error),
+            _ if error.is_permanent() || error.is_tls() || error.is_client() => {
+                Self::SmtpPermanent(error)
+            }
+            // Timeouts, network errors once connected, and servers that hang
+            // up instead of replying
+            _ => Self::SmtpUncertain(error),
+        }
+    }
+
+    /// Whether sending the same email again later may work.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::SmtpTransient(_))
+    }
+
+    /// Whether the saved sign-in or password was rejected, so that it should
+    /// be forgotten and the user asked to sign in again.
+    pub fn needs_sign_in(&self) -> bool {
+        matches!(self, Self::TokenExpired { .. } | Self::AuthRejected { .. })
+    }
+
+    /// Whether the rest of the batch would fail the same way, so there is
+    /// no point in sending it.
+    pub fn stops_batch(&self) -> bool {
+        self.needs_sign_in() || matches!(self, Self::ProviderUnsupported(_) | Self::SignIn(_))
+    }
+}
+
+/// Whether the connection to the server couldn't be opened at all, before
+/// anything was sent. lettre doesn't expose the kind of its errors other
+/// than through their message.
+fn is_connect_error(error: &smtp::Error) -> bool {
+    error.to_string().starts_with("Connection error")
+}
+
+/// Logs in to a fake SMTP server that answers the login with `reply`, and
+/// returns the error lettre makes of it, for tests elsewhere in the crate.
+#[cfg(test)]
+pub(crate) fn smtp_error(reply: &str) -> smtp::Error {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let reply = reply.to_string();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut line = String::new();
+        writer.write_all(b"220 localhost ready\r\n").unwrap();
+        reader.read_line(&mut line).unwrap(); // EHLO
+        writer
+            .write_all(b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n")
+            .unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap(); // AUTH
+        writer
+            .write_all(format!("{}\r\n", reply).as_bytes())
+            .unwrap();
+    });
+
+    let transport = lettre::SmtpTransport::builder_dangerous("127.0.0.1")
+        .port(port)
+        .credentials(lettre::transport::smtp::authentication::Credentials::new(
+            "me@example.com".to_string(),
+            "token".to_string(),
+        ))
+        .authentication(vec![
+            lettre::transport::smtp::authentication::Mechanism::Plain,
+        ])
+        .build();
+    let error = transport.test_connection().unwrap_err();
+    server.join().unwrap();
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::SmtpConfig;
+
+    #[test]
+    fn test_mailer_error_kinds() {
+        let address = "not an address".parse::<lettre::Address>().unwrap_err();
+        let err = MailerError::InvalidAddress {
+            field: "To",
+            address: "not an address".to_string(),
+            source: address,
+        };
+        assert!(
+            err.to_string()
+                .starts_with("Invalid To address 'not an address'")
+        );
+        assert!(!err.is_transient() && !err.needs_sign_in() && !err.stops_batch());
+
+        let err = MailerError::from(io::Error::other("disk full"));
+        assert!(!err.is_transient() && !err.stops_batch());
+
+        let err = MailerError::SignIn(io::Error::other("Failed to open browser"));
+        assert_eq!(err.to_string(), "Could not sign in: Failed to open browser");
+        assert!(err.stops_batch() && !err.needs_sign_in());
+
+        let err = MailerError::from("me@example.com".parse::<Provider>().unwrap_err());
+        assert!(err.stops_batch());
+    }
+
+    #[test]
+    fn test_mailer_error_from_smtp() {
+        let custom = Provider::Custom(SmtpConfig::default());
+        let rejected = smtp_error("535 5.7.8 Authentication credentials invalid");
+        let err = MailerError::from_smtp(rejected, &Provider::Gmail, "me@gmail.com");
+        assert!(matches!(err, MailerError::TokenExpired { .. }));
+        assert!(err.needs_sign_in() && err.stops_batch() && !err.is_transient());
+        assert!(
+            err.to_string()
+                .starts_with("Could not send email, the sign-in for me@gmail.com has expired")
+        );
+
+        let rejected = smtp_error("535 5.7.8 Authentication credentials invalid");
+        let err = MailerError::from_smtp(rejected, &custom, "me@example.com");
+        assert!(matches!(err, MailerError::AuthRejected { .. }));
+        assert!(err.needs_sign_in());
+
+        let busy = smtp_error("454 4.7.0 Temporary authentication failure");
+        let err = MailerError::from_smtp(busy, &custom, "me@example.com");
+        assert!(err.is_transient() && !err.stops_batch());
+
+        let refused = smtp_error("554 5.7.1 Relay access denied");
+        let err = MailerError::from_smtp(refused, &custom, "me@example.com");
+        assert!(matches!(err, MailerError::SmtpPermanent(_)));
+        assert!(!err.is_transient() && !err.stops_batch());
+
+        // Nothing listens on port 1, so connecting fails straight away
+        let transport = lettre::SmtpTransport::builder_dangerous("127.0.0.1")
+            .port(1)
+            .build();
+        let error = transport.test_connection().unwrap_err();
+        let err = MailerError::from_smtp(error, &custom, "me@example.com");
+        assert!(err.is_transient());
+        assert!(!err.stops_batch());
+
+        let err = MailerError::from_smtp(dropped_after_data(), &custom, "me@example.com");
+        assert!(matches!(err, MailerError::SmtpUncertain(_)));
+        assert!(!err.is_transient() && !err.stops_batch());
+        assert!(err.to_string().contains("may have been delivered"));
+    }
+
+    /// `is_connect_error` goes by lettre's error message, so this breaks if a
+    /// lettre upgrade changes it, rather than connect failures quietly no
+    /// longer being retried.
+    #[tokio::test]
+    async fn test_lettre_connect_error_message() {
+        use lettre::{AsyncSmtpTransport, Tokio1Executor};
+
+        // A port that was just free, so nothing is listening on it
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let transport = lettre::SmtpTransport::builder_dangerous("127.0.0.1")
+            .port(port)
+            .build();
+        let error = transport.test_connection().unwrap_err();
+        assert!(is_connect_error(&error), "{}", error);
+
+        let transport: AsyncSmtpTransport<Tokio1Executor> =
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+                .port(port)
+                .build();
+        let error = transport.test_connection().await.unwrap_err();
+        assert!(is_connect_error(&error), "{}", error);
+
+        assert!(!is_connect_error(&dropped_after_data()));
+    }
+
+    /// Sends an email to a fake SMTP server that takes the message and
+    /// hangs up instead of confirming it, and returns the error.
+    fn dropped_after_data() -> smtp::Error {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 localhost ready\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let reply: &[u8] = match line.to_ascii_uppercase().as_str() {
+                    l if l.starts_with("EHLO") => b"250 localhost\r\n",
+                    l if l.starts_with("DATA") => b"354 go ahead\r\n",
+                    ".\r\n" => return,
+                    l if l.starts_with("MAIL") || l.starts_with("RCPT") => b"250 OK\r\n",
+                    _ => b"",
+                };
+                writer.write_all(reply).unwrap();
+                line.clear();
+            }
+        });
+
+        let email = lettre::Message::builder()
+            .from("me@example.com".parse().unwrap())
+            .to("you@example.com".parse().unwrap())
+            .body(String::from("eSIM"))
+            .unwrap();
+        let transport = lettre::SmtpTransport::builder_dangerous("127.0.0.1")
+            .port(port)
+            .build();
+        let error = lettre::Transport::send(&transport, &email).unwrap_err();
+        server.join().unwrap();
+        error
+    }
+}
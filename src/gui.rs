@@ -1,78 +1,121 @@
+use chrono::Utc;
 use eframe::egui;
+use html2text::render::{RichAnnotation, TaggedLine};
 use rfd::FileDialog;
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::email::{self, EmailTemplate};
+use crate::Args;
+use crate::batch::{self, BatchItem, BatchProgress, Outcome, ValidationReport};
+use crate::email::{
+    self, AttachedEsim, AuthMechanism, EmailTemplate, Provider, SmtpConfig, TemplateError, TlsMode,
+};
+use crate::i18n::{self, Text};
+use crate::language::Language;
+use crate::ledger::{self, Duplicate, SendLedger};
 use crate::oauth::OAuthClient;
-use crate::{Args, send_email};
-
-// Trait for email operations to allow mocking in tests
-pub trait EmailOperations: Send + Sync {
-    fn send_email(
-        &self,
-        args: &Args,
-        token: String,
-        path: &Path,
-        count: usize,
-    ) -> std::io::Result<()>;
-    fn get_token(
-        &self,
-        provider: &email::Provider,
-        email: &str,
-    ) -> Result<String, Box<dyn std::error::Error>>;
-}
-
-// Default implementation that uses real email functionality
-pub struct DefaultEmailOperations {
-    oauth_client: Arc<Mutex<OAuthClient>>,
-}
-
-impl DefaultEmailOperations {
-    pub fn new(oauth_client: Arc<Mutex<OAuthClient>>) -> Self {
-        Self { oauth_client }
-    }
-}
-
-impl EmailOperations for DefaultEmailOperations {
-    fn send_email(
-        &self,
-        args: &Args,
-        token: String,
-        path: &Path,
-        count: usize,
-    ) -> std::io::Result<()> {
-        send_email(args, token, path, count)
-    }
-
-    fn get_token(
-        &self,
-        provider: &email::Provider,
-        email: &str,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let mut client = self.oauth_client.lock().unwrap();
-        Ok(client.get_or_refresh_token(provider, email)?)
-    }
-}
+use crate::qr::{self, LineError, QrCheck, QrSource};
+use crate::rate_limit::{MAX_CONNECTIONS, QuotaWarning, RateLimit};
+use crate::retry::RetryPolicy;
+use crate::sender::{self, CancelToken, DefaultEmailOperations, EmailOperations, SendEvent};
+use crate::templates::{BUILT_IN, TemplateStore};
 
 // Separate state management
 #[derive(Default, serde::Deserialize, serde::Serialize)]
 pub struct AppState {
     pub args: Args,
 
+    /// Language of the interface, independent of the language of the emails.
+    #[serde(default)]
+    pub ui_language: Language,
+
     #[serde(skip)]
     pub image_paths: Vec<PathBuf>,
 
+    #[serde(skip)]
+    pub csv_import: Option<ValidationReport>,
+
+    #[serde(skip)]
+    pub activation_codes: String,
+
+    #[serde(skip)]
+    pub code_errors: Vec<LineError>,
+
+    #[serde(skip)]
+    pub qr_checks: Vec<QrCheck>,
+
+    #[serde(skip)]
+    pub template_names: Vec<String>,
+
+    /// The selected template with any edits, as used for the preview.
+    #[serde(skip)]
+    pub template: EmailTemplate,
+
+    /// The selected template as saved, to tell whether it has been edited.
+    #[serde(skip)]
+    pub saved_template: EmailTemplate,
+
+    /// Name to save the edited template under.
+    #[serde(skip)]
+    pub template_name: String,
+
+    /// The variables of the edited template as typed, separated by commas.
+    #[serde(skip)]
+    pub template_variables: String,
+
+    /// Why the edited template can't be used, if it can't.
+    #[serde(skip)]
+    pub template_error: Option<String>,
+
+    #[serde(skip)]
+    pub smtp_password: String,
+
+    /// Sending limits set by the user, by provider name, in place of
+    /// [`Provider::default_rate_limit`].
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimit>,
+
+    #[serde(skip)]
+    pub pending_duplicates: Option<Vec<Duplicate>>,
+
     #[serde(skip)]
     pub status: Arc<Mutex<String>>,
 
+    /// The subject and source of the previewed email, or why it can't be
+    /// rendered.
     #[serde(skip)]
     pub email_preview: String,
 
+    /// The previewed email, laid out for display.
+    #[serde(skip)]
+    pub preview: Option<EmailPreview>,
+
+    /// Position in the batch of the previewed email.
+    #[serde(skip)]
+    pub preview_index: usize,
+
+    /// Number of emails in the batch, to move through them in the preview.
+    #[serde(skip)]
+    pub preview_count: usize,
+
+    /// Whether an email of the batch carries several QR codes.
+    #[serde(skip)]
+    pub bundles_codes: bool,
+
+    /// Set when the batch would take the account over its daily limit.
+    #[serde(skip)]
+    pub quota_warning: Option<QuotaWarning>,
+
     #[serde(skip)]
     pub is_sending: Arc<Mutex<bool>>,
+
+    /// Whether a batch was being sent at the last frame, to notice when it
+    /// finishes.
+    #[serde(skip)]
+    pub was_sending: bool,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -82,6 +125,289 @@ pub struct EsimMailerApp {
 
     #[serde(skip)]
     email_ops: Arc<dyn EmailOperations>,
+
+    #[serde(skip)]
+    ledger: Arc<Mutex<SendLedger>>,
+
+    #[serde(skip)]
+    progress: Arc<Mutex<BatchProgress>>,
+
+    #[serde(skip)]
+    templates: Option<TemplateStore>,
+
+    /// Thumbnails of the QR codes in the preview, or `None` for those that
+    /// couldn't be loaded.
+    #[serde(skip)]
+    qr_thumbnails: Vec<(QrSource, Option<egui::TextureHandle>)>,
+
+    /// How emails that failed for a transient reason are sent again.
+    #[serde(skip)]
+    retry: RetryPolicy,
+
+    /// Cancels the batch being sent.
+    #[serde(skip)]
+    cancel: Arc<Mutex<CancelToken>>,
+}
+
+/// One email of the batch, as the preview shows it.
+#[derive(Debug, Clone)]
+pub struct EmailPreview {
+    pub email_to: String,
+    pub subject: String,
+    pub language: Language,
+    /// The body laid out as text, with the formatting that egui can show.
+    pub lines: Vec<TaggedLine<Vec<RichAnnotation>>>,
+    /// The QR codes attached to the email, by Content-ID.
+    pub qr_codes: Vec<(String, QrSource)>,
+}
+
+/// Width the preview body is laid out to, leaving egui to wrap it further.
+const PREVIEW_WIDTH: usize = 200;
+
+/// Size of the QR code thumbnails in the preview.
+const THUMBNAIL_SIZE: f32 = 160.0;
+
+/// A text field of the email form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormField {
+    From,
+    To,
+    Bcc,
+    Provider,
+    Name,
+    DataAmount,
+    TimePeriod,
+    Location,
+    ConfirmationCode,
+}
+
+impl FormField {
+    const ALL: [Self; 9] = [
+        Self::From,
+        Self::To,
+        Self::Bcc,
+        Self::Provider,
+        Self::Name,
+        Self::DataAmount,
+        Self::TimePeriod,
+        Self::Location,
+        Self::ConfirmationCode,
+    ];
+
+    fn label(self) -> Text {
+        match self {
+            Self::From => Text::From,
+            Self::To => Text::To,
+            Self::Bcc => Text::Bcc,
+            Self::Provider => Text::Provider,
+            Self::Name => Text::Name,
+            Self::DataAmount => Text::DataAmount,
+            Self::TimePeriod => Text::TimePeriod,
+            Self::Location => Text::Location,
+            Self::ConfirmationCode => Text::ConfirmationCode,
+        }
+    }
+
+    fn value(self, args: &Args) -> &str {
+        match self {
+            Self::From => &args.email_from,
+            Self::To => &args.email_to,
+            Self::Bcc => args.bcc.as_deref().unwrap_or_default(),
+            Self::Provider => &args.provider,
+            Self::Name => &args.name,
+            Self::DataAmount => &args.data_amount,
+            Self::TimePeriod => &args.time_period,
+            Self::Location => &args.location,
+            Self::ConfirmationCode => args.confirmation_code.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+/// Interface text in `language`, ready to show.
+fn tr(language: Language, text: Text) -> String {
+    i18n::display(text.get(language))
+}
+
+/// Describes a QR code that was sent before, or that is repeated in the
+/// batch, in `language`.
+fn duplicate_text(language: Language, duplicate: &Duplicate) -> String {
+    match &duplicate.previous {
+        Some(entry) => Text::AlreadySent.format(
+            language,
+            &[
+                &duplicate.qr,
+                &entry.recipient,
+                &entry.sent_at.format("%Y-%m-%d %H:%M UTC"),
+            ],
+        ),
+        None => Text::RepeatedInBatch.format(language, &[&duplicate.qr]),
+    }
+}
+
+/// Lays widgets out in a row, starting from the right in right-to-left
+/// languages.
+fn row<R>(ui: &mut egui::Ui, language: Language, add: impl FnOnce(&mut egui::Ui) -> R) -> R {
+    let layout = if language.is_rtl() {
+        egui::Layout::right_to_left(egui::Align::Center)
+    } else {
+        egui::Layout::left_to_right(egui::Align::Center)
+    };
+    let size = egui::vec2(
+        ui.available_size_before_wrap().x,
+        ui.spacing().interact_size.y,
+    );
+    ui.allocate_ui_with_layout(size, layout, add).inner
+}
+
+/// Lays out the body of `preview` line by line, showing the QR codes in
+/// place of the images that point at them.
+fn preview_body_ui(
+    ui: &mut egui::Ui,
+    preview: &EmailPreview,
+    thumbnails: &[(QrSource, Option<egui::TextureHandle>)],
+) {
+    let layout = if preview.language.is_rtl() {
+        egui::Layout::right_to_left(egui::Align::Min).with_main_wrap(true)
+    } else {
+        egui::Layout::left_to_right(egui::Align::Min).with_main_wrap(true)
+    };
+    for line in &preview.lines {
+        let spans: Vec<_> = line.tagged_strings().collect();
+        if spans.iter().all(|span| span.s.trim().is_empty()) {
+            ui.add_space(ui.spacing().item_spacing.y);
+            continue;
+        }
+        // html2text marks headings and list items with a prefix
+        let (heading, bullet) = match spans.first().map(|span| span.s.as_str()) {
+            Some(first) => (
+                first.starts_with('#') && first.trim_start_matches('#').starts_with(' '),
+                first.starts_with("* "),
+            ),
+            None => (false, false),
+        };
+        ui.with_layout(layout, |ui| {
+            ui.spacing_mut().item_spacing.x = 0.0;
+            let mut shown_image = None;
+            for (position, span) in spans.iter().enumerate() {
+                let mut text = span.s.as_str();
+                if position == 0 && heading {
+                    text = text.trim_start_matches('#').trim_start();
+                }
+                let image = span.tag.iter().find_map(|annotation| match annotation {
+                    RichAnnotation::Image(src) => Some(src.as_str()),
+                    _ => None,
+                });
+                if let Some(src) = image {
+                    let texture = preview
+                        .qr_codes
+                        .iter()
+                        .find(|(cid, _)| src.strip_prefix("cid:") == Some(cid.as_str()))
+                        .and_then(|(_, qr_code)| {
+                            thumbnails.iter().find(|(loaded, _)| loaded == qr_code)
+                        })
+                        .and_then(|(_, texture)| texture.as_ref());
+                    if let Some(texture) = texture {
+                        // The alt text can be split over several spans
+                        if shown_image != Some(src) {
+                            ui.image((texture.id(), egui::vec2(THUMBNAIL_SIZE, THUMBNAIL_SIZE)));
+                            shown_image = Some(src);
+                        }
+                        continue;
+                    }
+                }
+                let text = if position == 0 && bullet {
+                    format!("• {}", &text[2..])
+                } else {
+                    text.to_string()
+                };
+                let mut rich = egui::RichText::new(i18n::display(&text));
+                if heading {
+                    rich = rich.heading();
+                }
+                let mut link = None;
+                for annotation in &span.tag {
+                    rich = match annotation {
+                        RichAnnotation::Strong => rich.strong(),
+                        RichAnnotation::Emphasis => rich.italics(),
+                        RichAnnotation::Strikeout => rich.strikethrough(),
+                        RichAnnotation::Code | RichAnnotation::Preformat(_) => rich.code(),
+                        RichAnnotation::Link(url) => {
+                            link = Some(url);
+                            rich
+                        }
+                        _ => rich,
+                    };
+                }
+                match link {
+                    Some(url) => ui.hyperlink_to(rich, url),
+                    None => ui.label(rich),
+                };
+            }
+        });
+    }
+}
+
+/// Loads `qr_code` as a texture, scaled down to a thumbnail.
+fn load_thumbnail(ctx: &egui::Context, qr_code: &QrSource) -> Option<egui::TextureHandle> {
+    let data = qr_code.load().ok()?.data;
+    let image = image::load_from_memory(&data)
+        .ok()?
+        .thumbnail(THUMBNAIL_SIZE as u32 * 2, THUMBNAIL_SIZE as u32 * 2)
+        .to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    let image = egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw());
+    Some(ctx.load_texture(qr_code.to_string(), image, egui::TextureOptions::NEAREST))
+}
+
+/// System fonts with Arabic letters, as the fonts built into egui have none.
+const ARABIC_FONTS: &[&str] = &[
+    "/usr/share/fonts/truetype/noto/NotoSansArabic-Regular.ttf",
+    "/usr/share/fonts/noto/NotoSansArabic-Regular.ttf",
+    "/usr/share/fonts/google-noto/NotoSansArabic-Regular.ttf",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "C:\\Windows\\Fonts\\segoeui.ttf",
+    "C:\\Windows\\Fonts\\tahoma.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+    "/System/Library/Fonts/Supplemental/Arial Unicode.ttf",
+    "/Library/Fonts/Arial Unicode.ttf",
+    "/System/Library/Fonts/Supplemental/Tahoma.ttf",
+];
+
+/// Adds the first Arabic font found to egui's fonts, after the built-in ones
+/// so that Latin text keeps its look. `ESIM_MAILER_FONT` can point at
+/// another font file.
+fn load_arabic_font(ctx: &egui::Context) {
+    let custom = std::env::var_os("ESIM_MAILER_FONT").map(PathBuf::from);
+    let Some(data) = custom
+        .into_iter()
+        .chain(ARABIC_FONTS.iter().map(PathBuf::from))
+        .find_map(|path| std::fs::read(path).ok())
+    else {
+        eprintln!("No font with Arabic letters found, Arabic text can't be shown");
+        return;
+    };
+    let mut fonts = egui::FontDefinitions::default();
+    fonts.font_data.insert(
+        "arabic".to_string(),
+        Arc::new(egui::FontData::from_owned(data)),
+    );
+    for family in [egui::FontFamily::Proportional, egui::FontFamily::Monospace] {
+        fonts
+            .families
+            .entry(family)
+            .or_default()
+            .push("arabic".to_string());
+    }
+    ctx.set_fonts(fonts);
+}
+
+/// What to do with QR codes that were sent before.
+enum DuplicateChoice {
+    Skip,
+    SendAnyway,
+    Cancel,
 }
 
 impl Default for EsimMailerApp {
@@ -91,6 +417,12 @@ impl Default for EsimMailerApp {
         let mut app = Self {
             state: AppState::default(),
             email_ops: Arc::new(DefaultEmailOperations::new(oauth_client)),
+            ledger: Arc::default(),
+            progress: Arc::default(),
+            templates: None,
+            qr_thumbnails: Vec::new(),
+            retry: RetryPolicy::default(),
+            cancel: Arc::default(),
         };
         app.generate_preview(); // Generate preview with loaded args
         app
@@ -101,324 +433,1612 @@ impl Default for EsimMailerApp {
 impl EsimMailerApp {
     #[cfg(test)]
     pub fn new_with_email_ops(email_ops: Arc<dyn EmailOperations>) -> Self {
+        // Tests send as fast as they can
+        let rate_limits = ["Gmail", "Outlook"]
+            .map(|provider| (provider.to_string(), RateLimit::default()))
+            .into();
         Self {
-            state: AppState::default(),
+            state: AppState {
+                rate_limits,
+                ..AppState::default()
+            },
             email_ops,
+            ledger: Arc::default(),
+            progress: Arc::default(),
+            templates: None,
+            qr_thumbnails: Vec::new(),
+            retry: RetryPolicy::immediate(3),
+            cancel: Arc::default(),
         }
     }
 
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        // This is also where you can customize the look and feel of egui using
-        // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
+        load_arabic_font(&cc.egui_ctx);
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            let mut app: EsimMailerApp =
-                eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
-            app.email_ops = Arc::new(DefaultEmailOperations::new(Arc::new(Mutex::new(
-                OAuthClient::default(),
-            ))));
-            app.generate_preview();
-            return app;
+        let mut app: EsimMailerApp = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+        // Remember sign-ins across restarts.
+        app.email_ops = Arc::new(DefaultEmailOperations::new(Arc::new(Mutex::new(
+            OAuthClient::with_file_storage(),
+        ))));
+        match SendLedger::open_default() {
+            Ok(ledger) => app.ledger = Arc::new(Mutex::new(ledger)),
+            Err(e) => {
+                *app.state.status.lock().unwrap() =
+                    Text::ErrorOpeningHistory.format(app.state.ui_language, &[&e])
+            }
         }
-
-        Default::default()
+        match BatchProgress::open_default() {
+            Ok(progress) => app.progress = Arc::new(Mutex::new(progress)),
+            Err(e) => {
+                *app.state.status.lock().unwrap() =
+                    Text::ErrorOpeningBatch.format(app.state.ui_language, &[&e])
+            }
+        }
+        match TemplateStore::open_default() {
+            Ok(templates) => app.templates = Some(templates),
+            Err(e) => {
+                *app.state.status.lock().unwrap() =
+                    Text::ErrorOpeningTemplates.format(app.state.ui_language, &[&e])
+            }
+        }
+        app.load_templates();
+        app
     }
 
+    /// Renders the email for the item of the batch at `preview_index`, or
+    /// for the form alone when there is nothing to send yet.
     fn generate_preview(&mut self) {
-        let template = EmailTemplate::new();
-        let subject = template.subject(&self.state.args, 1);
-        let body = template.body(&self.state.args);
-        self.state.email_preview = format!("Subject: {}\n\nBody:\n{}", subject, body);
+        let language = self.state.ui_language;
+        let items = self.batch_items();
+        self.state.preview_count = items.len();
+        self.state.bundles_codes = items.iter().any(|item| item.qr_codes.len() > 1);
+        self.update_quota_warning();
+        self.state.preview_index = self.state.preview_index.min(items.len().saturating_sub(1));
+        let (args, qr_codes) = match items.get(self.state.preview_index) {
+            Some(item) => (&item.args, item.qr_codes.as_slice()),
+            None => (&self.state.args, &[][..]),
+        };
+        // Images point at made-up Content-IDs, which the preview shows the
+        // QR codes in place of.
+        let cids: Vec<String> = (1..=qr_codes.len())
+            .map(|n| format!("preview-qr-{}", n))
+            .collect();
+        let mut esims: Vec<AttachedEsim> = qr_codes
+            .iter()
+            .zip(&cids)
+            .map(|(qr_code, cid)| AttachedEsim {
+                activation_code: self
+                    .state
+                    .qr_checks
+                    .iter()
+                    .find(|check| &check.source == qr_code)
+                    .and_then(|check| check.result.as_ref().ok()),
+                qr_cid: cid,
+            })
+            .collect();
+        if esims.is_empty() {
+            esims.push(AttachedEsim {
+                activation_code: None,
+                qr_cid: "{{QR_CID}}",
+            });
+        }
+
+        let template = &self.state.template;
+        let preview = template.check().and_then(|()| {
+            let subject = template.subject(args, self.state.preview_index + 1)?;
+            let body = template.body_with_esims(args, &esims)?;
+            let text = template.text_with_esims(args, &esims, &body)?;
+            let lines = html2text::from_read_rich(body.as_bytes(), PREVIEW_WIDTH)
+                .map_err(TemplateError::PlainText)?;
+            let smdp_address = esims
+                .iter()
+                .filter_map(|esim| esim.activation_code)
+                .map(|code| {
+                    format!(
+                        "\n{} {}",
+                        Text::SmdpAddress.get(language),
+                        code.smdp_address
+                    )
+                })
+                .collect::<String>();
+            let source = format!(
+                "{} {}{}\n\n{}\n{}\n\n{}\n{}",
+                Text::Subject.get(language),
+                subject,
+                smdp_address,
+                Text::Body.get(language),
+                body,
+                Text::PlainText.get(language),
+                text
+            );
+            let preview = EmailPreview {
+                email_to: args.email_to.clone(),
+                subject,
+                language: args.language,
+                lines,
+                qr_codes: cids.iter().cloned().zip(qr_codes.iter().cloned()).collect(),
+            };
+            Ok((source, preview))
+        });
+        match preview {
+            Ok((source, preview)) => {
+                self.qr_thumbnails.retain(|(qr_code, _)| {
+                    preview.qr_codes.iter().any(|(_, shown)| shown == qr_code)
+                });
+                self.state.email_preview = source;
+                self.state.preview = Some(preview);
+                self.state.template_error = None;
+            }
+            Err(e) => {
+                self.state.email_preview =
+                    Text::TemplateError.format(self.state.ui_language, &[&e]);
+                self.state.preview = None;
+                self.state.template_error = Some(e.to_string());
+            }
+        }
     }
 
-    fn send_email_async(&self) {
-        let status = Arc::clone(&self.state.status);
-        let is_sending = Arc::clone(&self.state.is_sending);
-        let email_ops = Arc::clone(&self.email_ops);
-        *is_sending.lock().unwrap() = true;
+    /// Shows the email at `index` in the batch in the preview.
+    fn show_preview(&mut self, index: usize) {
+        self.state.preview_index = index;
+        self.generate_preview();
+    }
 
-        let args = self.state.args.clone();
-        let image_paths = self.state.image_paths.clone();
+    /// Reloads the list of saved templates and the selected template.
+    fn load_templates(&mut self) {
+        self.state.template_names = match &self.templates {
+            Some(templates) => templates.names().unwrap_or_else(|e| {
+                *self.state.status.lock().unwrap() =
+                    Text::ErrorListingTemplates.format(self.state.ui_language, &[&e]);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let name = self
+            .state
+            .args
+            .template
+            .clone()
+            .unwrap_or_else(|| BUILT_IN.to_string());
+        self.select_template(name);
+    }
 
-        let email_provider: email::Provider =
-            args.email_from.parse().expect("Invalid email provider");
+    /// Switches the language of the email, along with the version of the
+    /// template used.
+    fn select_language(&mut self, language: Language) {
+        self.state.args.language = language;
+        let name = self
+            .state
+            .args
+            .template
+            .clone()
+            .unwrap_or_else(|| BUILT_IN.to_string());
+        self.select_template(name);
+    }
 
-        thread::spawn(move || {
-            let token = email_ops.get_token(&email_provider, &args.email_from);
-
-            match token {
-                Ok(token) => {
-                    for (index, path) in image_paths.iter().enumerate() {
-                        match email_ops.send_email(&args, token.clone(), path, index + 1) {
-                            Ok(_) => {
-                                let mut status_lock = status.lock().unwrap();
-                                *status_lock =
-                                    format!("{} eSIM emails sent successfully.", index + 1);
-                            }
-                            Err(e) => {
-                                let mut status_lock = status.lock().unwrap();
-                                *status_lock = format!("Error sending email: {}", e);
-                                break;
-                            }
-                        }
-                    }
-                    *is_sending.lock().unwrap() = false;
-                }
-                Err(e) => {
-                    let mut status_lock = status.lock().unwrap();
-                    *status_lock = format!("Error getting OAuth token: {}", e);
-                    *is_sending.lock().unwrap() = false;
-                }
+    /// Switches to the named template in the selected language, falling back
+    /// to the built-in one if it can't be loaded.
+    fn select_template(&mut self, name: String) {
+        let language = self.state.args.language;
+        let loaded = match &self.templates {
+            _ if name == BUILT_IN => Ok(EmailTemplate::built_in(language)),
+            Some(templates) => templates.load(&name, language),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                Text::NoTemplatesDirectory.get(self.state.ui_language),
+            )),
+        };
+        let (name, template) = match loaded {
+            Ok(template) => (name, template),
+            Err(e) => {
+                *self.state.status.lock().unwrap() =
+                    Text::ErrorLoadingTemplate.format(self.state.ui_language, &[&e]);
+                (BUILT_IN.to_string(), EmailTemplate::built_in(language))
             }
-        });
+        };
+        self.state.args.template = (name != BUILT_IN).then(|| name.clone());
+        self.state.template_name = name;
+        self.state.template_variables = template.variables.join(", ");
+        self.state.saved_template = template.clone();
+        self.state.template = template;
+        self.generate_preview();
     }
 
-    pub fn update_form_field(&mut self, field: &str, value: String) -> bool {
-        let mut changed = false;
-        match field {
-            "From" => {
-                if self.state.args.email_from != value {
-                    self.state.args.email_from = value;
-                    changed = true;
-                }
-            }
-            "To" => {
-                if self.state.args.email_to != value {
-                    self.state.args.email_to = value;
-                    changed = true;
-                }
-            }
-            "BCC" => {
-                if self.state.args.bcc.as_deref().unwrap_or("") != value {
-                    self.state.args.bcc = Some(value);
-                    changed = true;
-                }
-            }
-            "Provider" => {
-                if self.state.args.provider != value {
-                    self.state.args.provider = value;
-                    changed = true;
-                }
-            }
-            "Name" => {
-                if self.state.args.name != value {
-                    self.state.args.name = value;
-                    changed = true;
-                }
-            }
-            "Data Amount" => {
-                if self.state.args.data_amount != value {
-                    self.state.args.data_amount = value;
-                    changed = true;
-                }
-            }
-            "Time Period" => {
-                if self.state.args.time_period != value {
-                    self.state.args.time_period = value;
-                    changed = true;
-                }
-            }
-            "Location" => {
-                if self.state.args.location != value {
-                    self.state.args.location = value;
-                    changed = true;
-                }
-            }
-            _ => {}
-        }
-        if changed {
-            self.generate_preview();
+    fn save_template(&mut self) {
+        let Some(templates) = &self.templates else {
+            return;
+        };
+        if let Err(e) = self.state.template.check() {
+            *self.state.status.lock().unwrap() =
+                Text::TemplateNotSaved.format(self.state.ui_language, &[&e]);
+            return;
         }
-        changed
+        let name = self.state.template_name.trim().to_string();
+        let language = self.state.args.language;
+        let status = match templates.save(&name, language, &self.state.template) {
+            Ok(()) => {
+                self.state.args.template = Some(name.clone());
+                self.load_templates();
+                Text::TemplateSaved
+                    .format(self.state.ui_language, &[&name, &language.native_name()])
+            }
+            Err(e) => Text::ErrorSavingTemplate.format(self.state.ui_language, &[&e]),
+        };
+        *self.state.status.lock().unwrap() = status;
     }
 
-    #[cfg(test)]
-    pub fn get_form_state(&self) -> Args {
-        self.state.args.clone()
+    fn delete_template(&mut self) {
+        let (Some(templates), Some(name)) = (&self.templates, self.state.args.template.clone())
+        else {
+            return;
+        };
+        let status = match templates.delete(&name) {
+            Ok(()) => {
+                self.state.args.template = None;
+                self.load_templates();
+                Text::TemplateDeleted.format(self.state.ui_language, &[&name])
+            }
+            Err(e) => Text::ErrorDeletingTemplate.format(self.state.ui_language, &[&e]),
+        };
+        *self.state.status.lock().unwrap() = status;
     }
 
-    #[cfg(test)]
-    pub fn get_preview(&self) -> String {
-        self.state.email_preview.clone()
+    /// Decodes the QR codes about to be sent so that images that aren't eSIM
+    /// activation codes are caught before sending. Images checked before
+    /// aren't decoded again.
+    fn check_qr_codes(&mut self) {
+        let previous = std::mem::take(&mut self.state.qr_checks);
+        self.state.qr_checks = self
+            .batch_items()
+            .iter()
+            .flat_map(|item| &item.qr_codes)
+            .map(|qr_code| {
+                previous
+                    .iter()
+                    .find(|check| &check.source == qr_code)
+                    .cloned()
+                    .unwrap_or_else(|| QrCheck::new(qr_code))
+            })
+            .collect();
+        self.state.code_errors = qr::parse_activation_codes(&self.state.activation_codes).1;
+        self.generate_preview();
     }
-}
-
-impl eframe::App for EsimMailerApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        egui::CentralPanel::default().show(ctx, |ui| {
-            egui::ScrollArea::vertical().show(ui, |ui| {
-                ui.heading("eSIM Mailer");
-                ui.add_space(10.0);
 
-                egui::Grid::new("email_form")
-                    .num_columns(1)
-                    .spacing([0.0, 10.0])
-                    .show(ui, |ui| {
-                        let fields = [
-                            ("From", self.state.args.email_from.clone()),
-                            ("To", self.state.args.email_to.clone()),
-                            ("BCC", self.state.args.bcc.clone().unwrap_or_default()),
-                            ("Provider", self.state.args.provider.clone()),
-                            ("Name", self.state.args.name.clone()),
-                            ("Data Amount", self.state.args.data_amount.clone()),
-                            ("Time Period", self.state.args.time_period.clone()),
-                            ("Location", self.state.args.location.clone()),
-                        ];
-
-                        for (label, value) in fields.iter() {
-                            let mut current_value = value.clone();
-                            ui.horizontal(|ui| {
-                                ui.label(format!("{}:", label));
-                                if ui
-                                    .add(
-                                        egui::TextEdit::singleline(&mut current_value)
-                                            .desired_width(f32::INFINITY),
-                                    )
-                                    .changed()
-                                {
-                                    self.update_form_field(label, current_value);
-                                }
-                            });
-                            ui.end_row();
-                        }
-                    });
+    fn select_qr_codes(&mut self, paths: Vec<PathBuf>) {
+        self.state.image_paths = paths;
+        self.state.qr_checks.clear();
+        self.check_qr_codes();
+    }
 
-                ui.add_space(10.0);
+    fn update_activation_codes(&mut self, text: String) {
+        self.state.activation_codes = text;
+        self.check_qr_codes();
+    }
 
-                if ui.button("Select QR codes").clicked() {
-                    if let Some(paths) = FileDialog::new()
-                        .add_filter("Image Files", &["png", "jpg", "jpeg", "gif"])
-                        .pick_files()
-                    {
-                        self.state.image_paths = paths;
-                    }
+    /// Adds the activation codes in a text file to the pasted ones.
+    fn load_activation_codes(&mut self, path: &Path) {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let mut codes = std::mem::take(&mut self.state.activation_codes);
+                if !codes.is_empty() && !codes.ends_with('\n') {
+                    codes.push('\n');
                 }
+                codes.push_str(&text);
+                self.update_activation_codes(codes);
+            }
+            Err(e) => {
+                *self.state.status.lock().unwrap() =
+                    Text::ErrorReadingActivationCodes.format(self.state.ui_language, &[&e]);
+            }
+        }
+    }
 
-                ui.label(format!(
-                    "Selected QR codes: {}",
-                    self.state.image_paths.len()
-                ));
-
-                ui.add_space(10.0);
-
-                ui.group(|ui| {
-                    ui.label("Email Preview:");
-                    ui.add(
-                        egui::TextEdit::multiline(&mut self.state.email_preview)
-                            .desired_width(f32::INFINITY)
-                            .desired_rows(10)
-                            .interactive(false),
-                    );
-                });
+    /// The emails to send: one per imported CSV row if a file was imported,
+    /// otherwise one per selected QR code or pasted activation code to the
+    /// recipient in the form.
+    fn batch_items(&self) -> Vec<BatchItem> {
+        match &self.state.csv_import {
+            Some(report) => report.items.clone(),
+            None => {
+                let (codes, _) = qr::parse_activation_codes(&self.state.activation_codes);
+                let qr_codes: Vec<QrSource> = self
+                    .state
+                    .image_paths
+                    .iter()
+                    .cloned()
+                    .map(QrSource::Image)
+                    .chain(codes.into_iter().map(QrSource::ActivationCode))
+                    .collect();
+                BatchItem::for_qr_codes(&self.state.args, &qr_codes)
+            }
+        }
+    }
 
-                ui.add_space(10.0);
+    fn can_send(&self) -> bool {
+        self.state
+            .csv_import
+            .as_ref()
+            .is_none_or(ValidationReport::is_ok)
+            && self.state.code_errors.is_empty()
+            && self
+                .state
+                .qr_checks
+                .iter()
+                .all(|check| check.result.is_ok())
+            && self.state.saved_template.check().is_ok()
+            && (self.state.csv_import.is_some() || self.missing_variables().is_empty())
+            && !self.needs_esims_loop()
+    }
 
-                ui.horizontal(|ui| {
-                    if !*self.state.is_sending.lock().unwrap() {
-                        if ui.button("Send Email").clicked() {
-                            self.send_email_async();
-                        }
-                    } else {
-                        ui.add(egui::Spinner::new());
-                        ui.label("Sending email...");
-                    }
-                });
+    /// Whether several codes are about to be bundled in one email but the
+    /// selected template only has room for one.
+    fn needs_esims_loop(&self) -> bool {
+        self.state.bundles_codes && !self.state.saved_template.lists_esims()
+    }
 
-                ui.add_space(10.0);
+    /// The sending limits for `provider`, as set by the user or by default.
+    fn rate_limit(&self, provider: &Provider) -> RateLimit {
+        self.state
+            .rate_limits
+            .get(&provider.to_string())
+            .copied()
+            .unwrap_or_else(|| provider.default_rate_limit())
+    }
 
-                if !*self.state.is_sending.lock().unwrap() {
-                    let status = self.state.status.lock().unwrap().clone();
-                    if !status.is_empty() {
-                        ui.label(status);
-                    }
-                }
-            });
+    /// Warns if sending the batch would go over the daily limit of the
+    /// account. Called whenever the batch, the account, its limits or the
+    /// emails it sent change, rather than scanning the history every frame.
+    fn update_quota_warning(&mut self) {
+        self.state.quota_warning = self.state.args.email_provider().ok().and_then(|provider| {
+            let limit = self.rate_limit(&provider);
+            let account = &self.state.args.email_from;
+            let sent = self
+                .ledger
+                .lock()
+                .unwrap()
+                .sent_in_last_day(account, Utc::now());
+            limit.check_quota(account, sent, self.state.preview_count)
         });
     }
 
-    fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        eframe::set_value(storage, eframe::APP_KEY, self)
+    /// Notices when the batch being sent finishes, which adds to the emails
+    /// sent from the account.
+    fn poll_sending(&mut self) {
+        let is_sending = *self.state.is_sending.lock().unwrap();
+        if self.state.was_sending && !is_sending {
+            self.update_quota_warning();
+        }
+        self.state.was_sending = is_sending;
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Mutex;
 
-    // Mock email operations for testing
-    struct MockEmailOperations {
-        send_count: Arc<Mutex<usize>>,
-        should_fail: bool,
+    /// The variables of the selected template left empty in the form.
+    fn missing_variables(&self) -> Vec<&str> {
+        self.state
+            .saved_template
+            .missing_variables(&self.state.args)
     }
 
-    impl MockEmailOperations {
-        fn new(should_fail: bool) -> Self {
-            Self {
-                send_count: Arc::new(Mutex::new(0)),
-                should_fail,
+    fn import_csv(&mut self, path: &Path) {
+        match batch::import_csv(path, &self.state.args) {
+            Ok(report) => self.state.csv_import = Some(report),
+            Err(e) => {
+                self.state.csv_import = None;
+                *self.state.status.lock().unwrap() =
+                    Text::ErrorImportingCsv.format(self.state.ui_language, &[&e]);
             }
         }
+        self.check_qr_codes();
     }
 
-    impl EmailOperations for MockEmailOperations {
-        fn send_email(
-            &self,
-            _args: &Args,
-            _token: String,
-            _path: &Path,
-            _count: usize,
-        ) -> std::io::Result<()> {
-            if self.should_fail {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Mock error"));
+    fn save_smtp_password(&mut self) {
+        let password = std::mem::take(&mut self.state.smtp_password);
+        let status = match self.state.args.email_provider() {
+            Ok(provider) => {
+                match self
+                    .email_ops
+                    .save_password(&provider, &self.state.args.email_from, password)
+                {
+                    Ok(()) => Text::PasswordSaved.get(self.state.ui_language).to_string(),
+                    Err(e) => Text::ErrorSavingPassword.format(self.state.ui_language, &[&e]),
+                }
             }
-            let mut count = self.send_count.lock().unwrap();
-            *count += 1;
-            Ok(())
-        }
-
-        fn get_token(
-            &self,
-            _provider: &email::Provider,
-            _email: &str,
-        ) -> Result<String, Box<dyn std::error::Error>> {
-            if self.should_fail {
-                return Err("Mock token error".into());
+            Err(e) => e.to_string(),
+        };
+        *self.state.status.lock().unwrap() = status;
+    }
+
+    /// Writes the batch to `.eml` files in `dir` instead of sending it.
+    fn export_eml(&mut self, dir: &Path) {
+        let language = self.state.ui_language;
+        let items = self.batch_items();
+        for (index, item) in items.iter().enumerate() {
+            if let Err(e) = email::write_eml(&item.args, &item.qr_codes, index + 1, dir) {
+                *self.state.status.lock().unwrap() =
+                    Text::ErrorWritingEml.format(language, &[&item.args.email_to, &e]);
+                return;
             }
-            Ok("mock_token".to_string())
         }
+        *self.state.status.lock().unwrap() =
+            Text::EmlWritten.format(language, &[&items.len(), &dir.display()]);
     }
 
-    #[test]
-    fn test_esim_mailer_app_default() {
-        let app = EsimMailerApp::default();
-        assert_eq!(app.state.args, Args::default());
-        assert!(app.state.image_paths.is_empty());
-        assert_eq!(app.state.status.lock().unwrap().as_str(), "");
-        assert_ne!(app.state.email_preview, "");
-        assert!(!(*app.state.is_sending.lock().unwrap()));
+    /// Starts sending, unless some of the QR codes were sent before, in which
+    /// case the user is asked what to do about them first.
+    fn request_send(&mut self) {
+        let items = self.batch_items();
+        let duplicates = self.ledger.lock().unwrap().find_duplicates(&items);
+        if duplicates.is_empty() {
+            self.send_email_async(items);
+        } else {
+            self.state.pending_duplicates = Some(duplicates);
+        }
     }
 
-    #[test]
-    fn test_generate_preview() {
-        let mut app = EsimMailerApp::default();
-        app.state.args = Args {
-            email_from: "from@example.com".to_string(),
-            email_to: "to@example.com".to_string(),
-            bcc: Some("bcc@example.com".to_string()),
-            provider: "TestProvider".to_string(),
-            name: "John".to_string(),
-            data_amount: "5GB".to_string(),
-            time_period: "30 days".to_string(),
-            location: "Egypt".to_string(),
+    fn resolve_duplicates(&mut self, choice: DuplicateChoice) {
+        let Some(duplicates) = self.state.pending_duplicates.take() else {
+            return;
         };
+        let items = self.batch_items();
+        let items = match choice {
+            DuplicateChoice::Cancel => return,
+            DuplicateChoice::SendAnyway => items,
+            DuplicateChoice::Skip => ledger::without_duplicates(items, &duplicates),
+        };
+        if items.is_empty() {
+            *self.state.status.lock().unwrap() =
+                Text::NothingToSend.get(self.state.ui_language).to_string();
+            return;
+        }
+        self.send_email_async(items);
+    }
 
-        app.generate_preview();
+    /// Starts a new batch with `items`, replacing the previous one.
+    fn send_email_async(&self, items: Vec<BatchItem>) {
+        if let Err(e) = self.progress.lock().unwrap().start(items) {
+            eprintln!("Error saving batch progress: {}", e);
+        }
+        self.send_pending();
+    }
 
-        assert!(
-            app.state
+    /// Queues the failed items of the batch again and sends them.
+    fn retry_failed(&self) {
+        if let Err(e) = self.progress.lock().unwrap().retry_failed() {
+            eprintln!("Error saving batch progress: {}", e);
+        }
+        self.send_pending();
+    }
+
+    /// Sends every item of the batch that hasn't been sent yet. A failed email
+    /// is recorded and the rest of the batch carries on.
+    fn send_pending(&self) {
+        let status = Arc::clone(&self.state.status);
+        let is_sending = Arc::clone(&self.state.is_sending);
+        let email_ops = Arc::clone(&self.email_ops);
+        let ledger = Arc::clone(&self.ledger);
+        let progress = Arc::clone(&self.progress);
+        let language = self.state.ui_language;
+        let retry = self.retry;
+
+        let email_provider = match self.state.args.email_provider() {
+            Ok(provider) => provider,
+            Err(e) => {
+                *status.lock().unwrap() = e.to_string();
+                return;
+            }
+        };
+        let limit = self.rate_limit(&email_provider);
+        let pending = progress.lock().unwrap().pending();
+        if pending.is_empty() {
+            *status.lock().unwrap() = Text::NothingLeftToSend.get(language).to_string();
+            return;
+        }
+        *is_sending.lock().unwrap() = true;
+
+        let email_from = self.state.args.email_from.clone();
+        let cancel = CancelToken::default();
+        *self.cancel.lock().unwrap() = cancel.clone();
+
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    *status.lock().unwrap() = Text::BatchStopped.format(language, &[&e]);
+                    *is_sending.lock().unwrap() = false;
+                    return;
+                }
+            };
+            runtime.block_on(async {
+                let token = match email_ops
+                    .get_token_async(&email_provider, &email_from)
+                    .await
+                {
+                    Ok(token) => token,
+                    Err(e) => {
+                        *status.lock().unwrap() = Text::ErrorGettingToken.format(language, &[&e]);
+                        *is_sending.lock().unwrap() = false;
+                        return;
+                    }
+                };
+
+                // Position in this run and item of each email, by position
+                // in the batch
+                let items: HashMap<usize, (usize, BatchItem)> = pending
+                    .iter()
+                    .enumerate()
+                    .map(|(position, (index, item))| (*index, (position, item.clone())))
+                    .collect();
+                let total = pending.len();
+                let mut finished = 0;
+                *status.lock().unwrap() = Text::SendingProgress.format(language, &[&1, &total]);
+                // Emails are numbered by position in the batch so that
+                // resuming keeps the subjects the same
+                let stopped = sender::send_batch(
+                    Arc::clone(&email_ops),
+                    pending,
+                    token,
+                    limit,
+                    retry,
+                    cancel.clone(),
+                    |event| match event {
+                        SendEvent::Retrying {
+                            index,
+                            attempt,
+                            error,
+                            delay,
+                        } => {
+                            *status.lock().unwrap() = Text::RetryingProgress.format(
+                                language,
+                                &[
+                                    &(items[&index].0 + 1),
+                                    &total,
+                                    &(attempt + 1),
+                                    &delay.as_secs(),
+                                    &error,
+                                ],
+                            );
+                        }
+                        SendEvent::Finished {
+                            index,
+                            result,
+                            attempts,
+                        } => {
+                            let outcome = match result {
+                                Ok(message_id) => {
+                                    if let Err(e) = ledger.lock().unwrap().record_sent(
+                                        &items[&index].1,
+                                        &message_id,
+                                        &email_provider,
+                                    ) {
+                                        eprintln!("Error recording sent email: {}", e);
+                                    }
+                                    Outcome::Sent {
+                                        message_id,
+                                        attempts,
+                                    }
+                                }
+                                Err(e) => Outcome::Failed {
+                                    error: e.to_string(),
+                                    attempts,
+                                },
+                            };
+                            if let Err(e) = progress.lock().unwrap().set_outcome(index, outcome) {
+                                eprintln!("Error saving batch progress: {}", e);
+                            }
+                            finished += 1;
+                            *status.lock().unwrap() = Text::SendingProgress
+                                .format(language, &[&(finished + 1).min(total), &total]);
+                        }
+                    },
+                )
+                .await;
+
+                // The email that stopped the batch and the rest stay pending,
+                // to be resumed once the problem is solved
+                if let Some(e) = &stopped
+                    && e.needs_sign_in()
+                {
+                    email_ops.forget_token(&email_provider, &email_from);
+                }
+                let summary = progress.lock().unwrap().summary();
+                let batch_summary = Text::BatchSummary.format(
+                    language,
+                    &[&summary.sent, &summary.failed, &summary.pending],
+                );
+                *status.lock().unwrap() = if let Some(e) = stopped {
+                    let text = if e.needs_sign_in() {
+                        Text::SignInAgain
+                    } else {
+                        Text::BatchStopped
+                    };
+                    text.format(language, &[&e])
+                } else if cancel.is_cancelled() {
+                    Text::SendingCancelled.format(language, &[&batch_summary])
+                } else if summary.failed == 0 && summary.pending == 0 {
+                    Text::EmailsSent.format(language, &[&summary.sent])
+                } else {
+                    Text::ErrorSendingSome.format(language, &[&batch_summary])
+                };
+                *is_sending.lock().unwrap() = false;
+            });
+        });
+    }
+
+    /// Stops sending the batch once the emails on their way are sent.
+    fn cancel_sending(&self) {
+        self.cancel.lock().unwrap().cancel();
+        *self.state.status.lock().unwrap() =
+            Text::Cancelling.get(self.state.ui_language).to_string();
+    }
+
+    pub fn update_form_field(&mut self, field: FormField, value: String) -> bool {
+        if field.value(&self.state.args) == value {
+            return false;
+        }
+        let args = &mut self.state.args;
+        match field {
+            FormField::From => args.email_from = value,
+            FormField::To => args.email_to = value,
+            FormField::Bcc => args.bcc = Some(value),
+            FormField::Provider => args.provider = value,
+            FormField::Name => args.name = value,
+            FormField::DataAmount => args.data_amount = value,
+            FormField::TimePeriod => args.time_period = value,
+            FormField::Location => args.location = value,
+            FormField::ConfirmationCode => args.confirmation_code = Some(value),
+        }
+        self.generate_preview();
+        true
+    }
+
+    /// Sets the value of a template variable, returning whether it changed.
+    pub fn update_variable(&mut self, name: &str, value: String) -> bool {
+        if self.state.args.variables.get(name) == Some(&value) {
+            return false;
+        }
+        self.state.args.variables.insert(name.to_string(), value);
+        self.generate_preview();
+        true
+    }
+
+    /// Declares the template variables typed into the editor.
+    fn update_template_variables(&mut self, text: String) {
+        self.state.template.variables = text
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        self.state.template_variables = text;
+        self.generate_preview();
+    }
+
+    #[cfg(test)]
+    pub fn get_form_state(&self) -> Args {
+        self.state.args.clone()
+    }
+
+    #[cfg(test)]
+    pub fn get_preview(&self) -> String {
+        self.state.email_preview.clone()
+    }
+}
+
+impl EsimMailerApp {
+    fn mail_server_ui(&mut self, ui: &mut egui::Ui) {
+        let language = self.state.ui_language;
+        let oauth = tr(language, Text::OAuthProviders);
+        let custom_server = tr(language, Text::OtherSmtpServer);
+
+        row(ui, language, |ui| {
+            ui.label(tr(language, Text::MailServer));
+            let mut custom = self.state.args.smtp.is_some();
+            egui::ComboBox::from_id_salt("mail_server")
+                .selected_text(if custom { &custom_server } else { &oauth })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut custom, false, &oauth);
+                    ui.selectable_value(&mut custom, true, &custom_server);
+                });
+            if custom != self.state.args.smtp.is_some() {
+                self.state.args.smtp = custom.then(SmtpConfig::default);
+            }
+        });
+
+        let Some(config) = self.state.args.smtp.as_mut() else {
+            return;
+        };
+        let mut save_password = false;
+        ui.group(|ui| {
+            egui::Grid::new("smtp_settings")
+                .num_columns(1)
+                .spacing([0.0, 10.0])
+                .show(ui, |ui| {
+                    row(ui, language, |ui| {
+                        ui.label(tr(language, Text::Host));
+                        ui.add(
+                            egui::TextEdit::singleline(&mut config.host)
+                                .desired_width(f32::INFINITY),
+                        );
+                    });
+                    ui.end_row();
+
+                    row(ui, language, |ui| {
+                        ui.label(tr(language, Text::Security));
+                        egui::ComboBox::from_id_salt("smtp_tls")
+                            .selected_text(config.tls.to_string())
+                            .show_ui(ui, |ui| {
+                                for mode in [TlsMode::StartTls, TlsMode::Tls, TlsMode::None] {
+                                    if ui
+                                        .selectable_value(&mut config.tls, mode, mode.to_string())
+                                        .changed()
+                                    {
+                                        config.port = mode.default_port();
+                                    }
+                                }
+                            });
+                    });
+                    ui.end_row();
+
+                    row(ui, language, |ui| {
+                        ui.label(tr(language, Text::Port));
+                        ui.add(egui::DragValue::new(&mut config.port).range(1..=u16::MAX));
+                    });
+                    ui.end_row();
+
+                    row(ui, language, |ui| {
+                        ui.label(tr(language, Text::Login));
+                        egui::ComboBox::from_id_salt("smtp_auth")
+                            .selected_text(config.auth.to_string())
+                            .show_ui(ui, |ui| {
+                                for mechanism in [AuthMechanism::Plain, AuthMechanism::Login] {
+                                    ui.selectable_value(
+                                        &mut config.auth,
+                                        mechanism,
+                                        mechanism.to_string(),
+                                    );
+                                }
+                            });
+                    });
+                    ui.end_row();
+
+                    row(ui, language, |ui| {
+                        ui.label(tr(language, Text::Password));
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.state.smtp_password)
+                                .password(true),
+                        );
+                        save_password = ui
+                            .add_enabled(
+                                !self.state.smtp_password.is_empty(),
+                                egui::Button::new(tr(language, Text::SavePassword)),
+                            )
+                            .clicked();
+                    });
+                    ui.end_row();
+                });
+        });
+        if save_password {
+            self.save_smtp_password();
+        }
+    }
+
+    fn rate_limit_ui(&mut self, ui: &mut egui::Ui) {
+        let language = self.state.ui_language;
+        let Ok(provider) = self.state.args.email_provider() else {
+            return;
+        };
+        let mut limit = self.rate_limit(&provider);
+        row(ui, language, |ui| {
+            ui.label(i18n::display(
+                &Text::SendingLimits.format(language, &[&provider]),
+            ));
+            ui.add(egui::DragValue::new(&mut limit.per_minute));
+            ui.label(tr(language, Text::PerMinute));
+            ui.add(egui::DragValue::new(&mut limit.per_day));
+            ui.label(tr(language, Text::PerDay));
+            ui.add(egui::DragValue::new(&mut limit.connections).range(1..=MAX_CONNECTIONS));
+            ui.label(tr(language, Text::AtOnce));
+        });
+        if limit != self.rate_limit(&provider) {
+            self.state.rate_limits.insert(provider.to_string(), limit);
+            self.update_quota_warning();
+        }
+    }
+}
+
+impl EsimMailerApp {
+    fn template_ui(&mut self, ui: &mut egui::Ui) {
+        let ui_language = self.state.ui_language;
+        let selected = self
+            .state
+            .args
+            .template
+            .clone()
+            .unwrap_or_else(|| BUILT_IN.to_string());
+        let template_label = |name: &str| {
+            if name == BUILT_IN {
+                tr(ui_language, Text::BuiltIn)
+            } else {
+                i18n::display(name)
+            }
+        };
+        let mut choice = selected.clone();
+        row(ui, ui_language, |ui| {
+            ui.label(tr(ui_language, Text::Template));
+            egui::ComboBox::from_id_salt("template")
+                .selected_text(template_label(&choice))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(
+                        &mut choice,
+                        BUILT_IN.to_string(),
+                        template_label(BUILT_IN),
+                    );
+                    for name in &self.state.template_names {
+                        ui.selectable_value(&mut choice, name.clone(), template_label(name));
+                    }
+                });
+
+            ui.label(tr(ui_language, Text::EmailLanguage));
+            let mut language = self.state.args.language;
+            egui::ComboBox::from_id_salt("language")
+                .selected_text(i18n::display(language.native_name()))
+                .show_ui(ui, |ui| {
+                    for &option in Language::ALL {
+                        ui.selectable_value(
+                            &mut language,
+                            option,
+                            i18n::display(option.native_name()),
+                        );
+                    }
+                });
+            if language != self.state.args.language {
+                self.select_language(language);
+            }
+        });
+        if choice != selected {
+            self.select_template(choice);
+            return;
+        }
+
+        let numbering = &mut self.state.args.numbering;
+        let mut numbering_changed = false;
+        row(ui, ui_language, |ui| {
+            numbering_changed |= ui
+                .checkbox(&mut numbering.enabled, tr(ui_language, Text::NumberEmails))
+                .changed();
+            ui.add_enabled_ui(numbering.enabled, |ui| {
+                ui.label(tr(ui_language, Text::NumberStart));
+                numbering_changed |= ui.add(egui::DragValue::new(&mut numbering.start)).changed();
+                ui.label(tr(ui_language, Text::NumberWidth));
+                numbering_changed |= ui
+                    .add(egui::DragValue::new(&mut numbering.width).range(0..=9))
+                    .changed();
+            });
+        });
+        if numbering_changed {
+            self.generate_preview();
+        }
+
+        let mut changed = false;
+        let mut save = false;
+        let mut revert = false;
+        let mut delete = false;
+        let mut set_variables = None;
+        egui::CollapsingHeader::new(tr(ui_language, Text::EditTemplate))
+            .id_salt("edit_template")
+            .show(ui, |ui| {
+                ui.label(i18n::display(
+                    &Text::EditingVersion
+                        .format(ui_language, &[&self.state.args.language.native_name()]),
+                ));
+                ui.label(i18n::display(&Text::Placeholders.format(
+                    ui_language,
+                    &[&"{{name}}, {{provider}}, {{location}}, {{data_amount}}, \
+                       {{time_period}}, {{smdp_address}}, {{activation_code}}, \
+                       {{confirmation_code}}, {{lang}}, {{dir}}, {{number}}"],
+                )));
+                egui::Grid::new("template_editor")
+                    .num_columns(1)
+                    .spacing([0.0, 10.0])
+                    .show(ui, |ui| {
+                        ui.label(tr(ui_language, Text::Subject));
+                        ui.end_row();
+                        changed |= ui
+                            .add(
+                                egui::TextEdit::singleline(
+                                    &mut self.state.template.subject_template,
+                                )
+                                .desired_width(f32::INFINITY),
+                            )
+                            .changed();
+                        ui.end_row();
+
+                        ui.label(tr(ui_language, Text::BodyHtml));
+                        ui.end_row();
+                        changed |= ui
+                            .add(
+                                egui::TextEdit::multiline(&mut self.state.template.body_template)
+                                    .code_editor()
+                                    .desired_width(f32::INFINITY)
+                                    .desired_rows(10),
+                            )
+                            .changed();
+                        ui.end_row();
+
+                        ui.label(tr(ui_language, Text::PlainText));
+                        ui.end_row();
+                        let template = &mut self.state.template;
+                        let mut custom = template.text_template.is_some();
+                        if ui
+                            .checkbox(&mut custom, tr(ui_language, Text::WritePlainText))
+                            .changed()
+                        {
+                            // Start from the converted HTML, placeholders included
+                            template.text_template = custom.then(|| {
+                                html2text::from_read(
+                                    template.body_template.as_bytes(),
+                                    email::TEXT_WIDTH,
+                                )
+                                .unwrap_or_default()
+                            });
+                            changed = true;
+                        }
+                        ui.end_row();
+                        if let Some(text) = &mut template.text_template {
+                            changed |= ui
+                                .add(
+                                    egui::TextEdit::multiline(text)
+                                        .code_editor()
+                                        .desired_width(f32::INFINITY)
+                                        .desired_rows(6),
+                                )
+                                .changed();
+                            ui.end_row();
+                        }
+
+                        let mut variables = self.state.template_variables.clone();
+                        row(ui, ui_language, |ui| {
+                            ui.label(tr(ui_language, Text::Variables));
+                            if ui
+                                .add(
+                                    egui::TextEdit::singleline(&mut variables)
+                                        .hint_text(tr(ui_language, Text::VariablesHint))
+                                        .desired_width(f32::INFINITY),
+                                )
+                                .changed()
+                            {
+                                set_variables = Some(variables);
+                            }
+                        });
+                        ui.end_row();
+
+                        row(ui, ui_language, |ui| {
+                            ui.label(tr(ui_language, Text::SaveAs));
+                            ui.add(
+                                egui::TextEdit::singleline(&mut self.state.template_name)
+                                    .desired_width(f32::INFINITY),
+                            );
+                        });
+                        ui.end_row();
+                    });
+
+                if let Some(error) = &self.state.template_error {
+                    ui.colored_label(ui.visuals().error_fg_color, i18n::display(error));
+                }
+                let edited = self.state.template != self.state.saved_template;
+                let name = self.state.template_name.trim();
+                row(ui, ui_language, |ui| {
+                    save = ui
+                        .add_enabled(
+                            self.templates.is_some() && !name.is_empty() && name != BUILT_IN,
+                            egui::Button::new(tr(ui_language, Text::Save)),
+                        )
+                        .clicked();
+                    revert = ui
+                        .add_enabled(edited, egui::Button::new(tr(ui_language, Text::Revert)))
+                        .clicked();
+                    delete = ui
+                        .add_enabled(
+                            selected != BUILT_IN,
+                            egui::Button::new(tr(ui_language, Text::Delete)),
+                        )
+                        .clicked();
+                });
+                if edited {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        tr(ui_language, Text::UnsavedChanges),
+                    );
+                }
+            });
+        if let Some(variables) = set_variables {
+            self.update_template_variables(variables);
+        } else if changed {
+            self.generate_preview();
+        }
+        if save {
+            self.save_template();
+        } else if revert {
+            self.select_template(selected);
+        } else if delete {
+            self.delete_template();
+        }
+    }
+
+    /// The email about to be sent, laid out as the recipient will see it,
+    /// with buttons to go through the batch.
+    fn preview_ui(&mut self, ui: &mut egui::Ui) {
+        let language = self.state.ui_language;
+        if let Some(preview) = &self.state.preview {
+            for (_, qr_code) in &preview.qr_codes {
+                if !self
+                    .qr_thumbnails
+                    .iter()
+                    .any(|(loaded, _)| loaded == qr_code)
+                {
+                    let thumbnail = load_thumbnail(ui.ctx(), qr_code);
+                    self.qr_thumbnails.push((qr_code.clone(), thumbnail));
+                }
+            }
+        }
+
+        let mut show = None;
+        ui.group(|ui| {
+            row(ui, language, |ui| {
+                ui.label(tr(language, Text::EmailPreview));
+                let index = self.state.preview_index;
+                let count = self.state.preview_count;
+                if count > 1 {
+                    if ui
+                        .add_enabled(index > 0, egui::Button::new(tr(language, Text::Previous)))
+                        .clicked()
+                    {
+                        show = Some(index - 1);
+                    }
+                    ui.label(i18n::display(
+                        &Text::PreviewPosition.format(language, &[&(index + 1), &count]),
+                    ));
+                    if ui
+                        .add_enabled(
+                            index + 1 < count,
+                            egui::Button::new(tr(language, Text::Next)),
+                        )
+                        .clicked()
+                    {
+                        show = Some(index + 1);
+                    }
+                }
+            });
+
+            match &self.state.preview {
+                Some(preview) => {
+                    ui.label(i18n::display(
+                        &Text::PreviewRecipient.format(language, &[&preview.email_to]),
+                    ));
+                    ui.label(egui::RichText::new(i18n::display(&preview.subject)).strong());
+                    ui.separator();
+                    egui::ScrollArea::vertical()
+                        .id_salt("email_preview")
+                        .max_height(300.0)
+                        .show(ui, |ui| preview_body_ui(ui, preview, &self.qr_thumbnails));
+                }
+                None => {
+                    ui.colored_label(
+                        ui.visuals().error_fg_color,
+                        i18n::display(&self.state.email_preview),
+                    );
+                }
+            }
+
+            if self.state.preview.is_some() {
+                ui.collapsing(tr(language, Text::HtmlSource), |ui| {
+                    // Shaped for display only, the source itself stays searchable
+                    let mut source = i18n::display(&self.state.email_preview);
+                    ui.add(
+                        egui::TextEdit::multiline(&mut source)
+                            .desired_width(f32::INFINITY)
+                            .desired_rows(10)
+                            .interactive(false),
+                    );
+                });
+            }
+        });
+        if let Some(index) = show {
+            self.show_preview(index);
+        }
+    }
+
+    fn activation_codes_ui(&mut self, ui: &mut egui::Ui) {
+        let language = self.state.ui_language;
+        egui::CollapsingHeader::new(tr(language, Text::ActivationCodes))
+            .id_salt("activation_codes")
+            .show(ui, |ui| {
+                ui.label(tr(language, Text::PasteActivationCodes));
+                let mut text = self.state.activation_codes.clone();
+                if ui
+                    .add(
+                        egui::TextEdit::multiline(&mut text)
+                            .hint_text("LPA:1$smdp.example.com$MATCHING-ID")
+                            .desired_width(f32::INFINITY)
+                            .desired_rows(4),
+                    )
+                    .changed()
+                {
+                    self.update_activation_codes(text);
+                }
+                if ui.button(tr(language, Text::LoadFromFile)).clicked()
+                    && let Some(path) = FileDialog::new()
+                        .add_filter(Text::TextFiles.get(language), &["txt"])
+                        .pick_file()
+                {
+                    self.load_activation_codes(&path);
+                }
+                for error in &self.state.code_errors {
+                    let text = Text::LineError.format(language, &[&error.line, &error.error]);
+                    ui.colored_label(ui.visuals().error_fg_color, i18n::display(&text));
+                }
+            });
+    }
+
+    fn batch_ui(&mut self, ui: &mut egui::Ui) {
+        let language = self.state.ui_language;
+        let is_sending = *self.state.is_sending.lock().unwrap();
+        let mut resume = false;
+        let mut retry = false;
+        let mut clear = false;
+        {
+            let progress = self.progress.lock().unwrap();
+            if progress.items().is_empty() {
+                return;
+            }
+            let summary = progress.summary();
+            ui.group(|ui| {
+                let summary_text = Text::BatchSummary.format(
+                    language,
+                    &[&summary.sent, &summary.failed, &summary.pending],
+                );
+                ui.label(i18n::display(
+                    &Text::LastBatch.format(language, &[&summary_text]),
+                ));
+                egui::ScrollArea::vertical()
+                    .id_salt("batch_progress")
+                    .max_height(150.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("batch_progress_grid")
+                            .num_columns(3)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for tracked in progress.items() {
+                                    let qr_codes: Vec<String> = tracked
+                                        .item
+                                        .qr_codes
+                                        .iter()
+                                        .map(|qr_code| match qr_code {
+                                            QrSource::Image(path) => path
+                                                .file_name()
+                                                .unwrap_or_default()
+                                                .to_string_lossy()
+                                                .into_owned(),
+                                            QrSource::ActivationCode(code) => {
+                                                code.matching_id.clone()
+                                            }
+                                        })
+                                        .collect();
+                                    ui.label(qr_codes.join(", "));
+                                    ui.label(&tracked.item.args.email_to);
+                                    match &tracked.outcome {
+                                        Outcome::Pending => ui.label(tr(language, Text::Waiting)),
+                                        Outcome::Sent { attempts: 1, .. } => {
+                                            ui.label(tr(language, Text::Sent))
+                                        }
+                                        Outcome::Sent { attempts, .. } => ui.label(i18n::display(
+                                            &Text::SentAfter.format(language, &[attempts]),
+                                        )),
+                                        Outcome::Failed { error, attempts } => {
+                                            let text = match attempts {
+                                                1 => Text::Failed.format(language, &[error]),
+                                                _ => Text::FailedAfter
+                                                    .format(language, &[attempts, error]),
+                                            };
+                                            ui.colored_label(
+                                                ui.visuals().error_fg_color,
+                                                i18n::display(&text),
+                                            )
+                                        }
+                                    };
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                if !is_sending {
+                    row(ui, language, |ui| {
+                        if summary.pending > 0 {
+                            resume = ui.button(tr(language, Text::Resume)).clicked();
+                        }
+                        if summary.failed > 0 {
+                            retry = ui.button(tr(language, Text::RetryFailed)).clicked();
+                        }
+                        clear = ui.button(tr(language, Text::Clear)).clicked();
+                    });
+                }
+            });
+        }
+        if resume {
+            self.send_pending();
+        } else if retry {
+            self.retry_failed();
+        } else if clear && let Err(e) = self.progress.lock().unwrap().clear() {
+            *self.state.status.lock().unwrap() = Text::ErrorClearingBatch.format(language, &[&e]);
+        }
+    }
+
+    fn history_ui(&self, ui: &mut egui::Ui) {
+        let language = self.state.ui_language;
+        let ledger = self.ledger.lock().unwrap();
+        let entries = ledger.entries();
+        let heading = Text::SendHistory.format(language, &[&entries.len()]);
+        ui.collapsing(i18n::display(&heading), |ui| {
+            egui::ScrollArea::vertical()
+                .id_salt("send_history")
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("send_history_grid")
+                        .num_columns(4)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for heading in [Text::SentAt, Text::To, Text::Via, Text::MessageId] {
+                                ui.strong(tr(language, heading));
+                            }
+                            ui.end_row();
+                            for entry in entries.iter().rev() {
+                                ui.label(
+                                    entry
+                                        .sent_at
+                                        .with_timezone(&chrono::Local)
+                                        .format("%Y-%m-%d %H:%M")
+                                        .to_string(),
+                                );
+                                ui.label(&entry.recipient);
+                                ui.label(&entry.provider);
+                                ui.label(&entry.message_id);
+                                ui.end_row();
+                            }
+                        });
+                });
+        });
+    }
+
+    fn interface_language_ui(&mut self, ui: &mut egui::Ui) {
+        let mut language = self.state.ui_language;
+        row(ui, language, |ui| {
+            ui.label(tr(language, Text::InterfaceLanguage));
+            egui::ComboBox::from_id_salt("ui_language")
+                .selected_text(i18n::display(language.native_name()))
+                .show_ui(ui, |ui| {
+                    for &option in Language::ALL {
+                        ui.selectable_value(
+                            &mut language,
+                            option,
+                            i18n::display(option.native_name()),
+                        );
+                    }
+                });
+        });
+        if language != self.state.ui_language {
+            self.state.ui_language = language;
+            self.generate_preview();
+        }
+    }
+}
+
+impl eframe::App for EsimMailerApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let language = self.state.ui_language;
+                let align = if language.is_rtl() {
+                    egui::Align::Max
+                } else {
+                    egui::Align::Min
+                };
+                ui.with_layout(egui::Layout::top_down(align), |ui| {
+                    self.main_ui(ui);
+                });
+            });
+        });
+    }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, eframe::APP_KEY, self)
+    }
+}
+
+impl EsimMailerApp {
+    fn main_ui(&mut self, ui: &mut egui::Ui) {
+        self.poll_sending();
+        let language = self.state.ui_language;
+        ui.heading(tr(language, Text::AppName));
+        self.interface_language_ui(ui);
+        ui.add_space(10.0);
+
+        egui::Grid::new("email_form")
+            .num_columns(1)
+            .spacing([0.0, 10.0])
+            .show(ui, |ui| {
+                for field in FormField::ALL {
+                    let mut current_value = field.value(&self.state.args).to_string();
+                    row(ui, language, |ui| {
+                        ui.label(i18n::display(&format!("{}:", field.label().get(language))));
+                        if ui
+                            .add(
+                                egui::TextEdit::singleline(&mut current_value)
+                                    .desired_width(f32::INFINITY),
+                            )
+                            .changed()
+                        {
+                            self.update_form_field(field, current_value);
+                        }
+                    });
+                    ui.end_row();
+                }
+                for name in self.state.template.variables.clone() {
+                    let mut current_value = self
+                        .state
+                        .args
+                        .variables
+                        .get(&name)
+                        .cloned()
+                        .unwrap_or_default();
+                    row(ui, language, |ui| {
+                        ui.label(format!("{}:", name));
+                        if ui
+                            .add(
+                                egui::TextEdit::singleline(&mut current_value)
+                                    .desired_width(f32::INFINITY),
+                            )
+                            .changed()
+                        {
+                            self.update_variable(&name, current_value);
+                        }
+                    });
+                    ui.end_row();
+                }
+            });
+
+        let missing = self.missing_variables();
+        if self.state.csv_import.is_none() && !missing.is_empty() {
+            ui.colored_label(
+                ui.visuals().warn_fg_color,
+                i18n::display(&Text::MissingVariables.format(language, &[&missing.join(", ")])),
+            );
+        }
+
+        ui.add_space(10.0);
+
+        self.mail_server_ui(ui);
+        self.rate_limit_ui(ui);
+
+        ui.add_space(10.0);
+
+        self.template_ui(ui);
+
+        ui.checkbox(
+            &mut self.state.args.normalize_images,
+            tr(language, Text::NormalizeImages),
+        );
+        if ui
+            .checkbox(
+                &mut self.state.args.bundle,
+                tr(language, Text::BundleQrCodes),
+            )
+            .changed()
+        {
+            self.generate_preview();
+        }
+        if self.needs_esims_loop() {
+            ui.colored_label(
+                ui.visuals().warn_fg_color,
+                tr(language, Text::NeedsEsimsLoop),
+            );
+        }
+
+        ui.add_space(10.0);
+
+        row(ui, language, |ui| {
+            if ui.button(tr(language, Text::SelectQrCodes)).clicked()
+                && let Some(paths) = FileDialog::new()
+                    .add_filter(Text::ImageFiles.get(language), email::IMAGE_EXTENSIONS)
+                    .pick_files()
+            {
+                self.select_qr_codes(paths);
+            }
+
+            if ui.button(tr(language, Text::ImportCsv)).clicked()
+                && let Some(path) = FileDialog::new()
+                    .add_filter(Text::CsvFiles.get(language), &["csv"])
+                    .pick_file()
+            {
+                self.import_csv(&path);
+            }
+        });
+
+        if let Some(report) = &self.state.csv_import {
+            let mut clear = false;
+            ui.group(|ui| {
+                row(ui, language, |ui| {
+                    ui.label(i18n::display(
+                        &Text::ImportedRecipients.format(language, &[&report.items.len()]),
+                    ));
+                    clear = ui.button(tr(language, Text::ClearImport)).clicked();
+                });
+                for column in &report.ignored_columns {
+                    ui.label(i18n::display(
+                        &Text::IgnoredColumn.format(language, &[column]),
+                    ));
+                }
+                for error in &report.errors {
+                    let text = Text::RowError.format(language, &[&error.line, &error.message]);
+                    ui.colored_label(ui.visuals().error_fg_color, i18n::display(&text));
+                }
+            });
+            if clear {
+                self.state.csv_import = None;
+                self.check_qr_codes();
+            }
+        } else {
+            ui.label(i18n::display(
+                &Text::SelectedQrCodes.format(language, &[&self.state.image_paths.len()]),
+            ));
+            for check in &self.state.qr_checks {
+                if let Err(e) = &check.result {
+                    let text = Text::UnreadableQrCode.format(language, &[&check.source, e]);
+                    ui.colored_label(ui.visuals().error_fg_color, i18n::display(&text));
+                }
+            }
+            self.activation_codes_ui(ui);
+        }
+
+        ui.add_space(10.0);
+
+        self.preview_ui(ui);
+
+        ui.add_space(10.0);
+
+        if let Some(duplicates) = &self.state.pending_duplicates {
+            let mut choice = None;
+            ui.group(|ui| {
+                ui.colored_label(ui.visuals().warn_fg_color, tr(language, Text::SentBefore));
+                for duplicate in duplicates {
+                    ui.label(i18n::display(&duplicate_text(language, duplicate)));
+                }
+                row(ui, language, |ui| {
+                    if ui.button(tr(language, Text::SkipThem)).clicked() {
+                        choice = Some(DuplicateChoice::Skip);
+                    }
+                    if ui.button(tr(language, Text::SendAnyway)).clicked() {
+                        choice = Some(DuplicateChoice::SendAnyway);
+                    }
+                    if ui.button(tr(language, Text::Cancel)).clicked() {
+                        choice = Some(DuplicateChoice::Cancel);
+                    }
+                });
+            });
+            if let Some(choice) = choice {
+                self.resolve_duplicates(choice);
+            }
+        }
+
+        if let Some(warning) = &self.state.quota_warning {
+            ui.colored_label(
+                ui.visuals().warn_fg_color,
+                i18n::display(&Text::OverDailyLimit.format(
+                    language,
+                    &[
+                        &warning.batch,
+                        &warning.account,
+                        &warning.per_day,
+                        &warning.sent,
+                    ],
+                )),
+            );
+        }
+
+        row(ui, language, |ui| {
+            if !*self.state.is_sending.lock().unwrap() {
+                let enabled = self.can_send() && self.state.pending_duplicates.is_none();
+                if ui
+                    .add_enabled(enabled, egui::Button::new(tr(language, Text::SendEmail)))
+                    .clicked()
+                {
+                    self.request_send();
+                }
+                if ui
+                    .add_enabled(enabled, egui::Button::new(tr(language, Text::SaveAsEml)))
+                    .clicked()
+                    && let Some(dir) = FileDialog::new().pick_folder()
+                {
+                    self.export_eml(&dir);
+                }
+            } else {
+                ui.add(egui::Spinner::new());
+                // Progress and retries are reported through the status
+                let status = self.state.status.lock().unwrap().clone();
+                if status.is_empty() {
+                    ui.label(tr(language, Text::SendingEmail));
+                } else {
+                    ui.label(i18n::display(&status));
+                }
+                if ui.button(tr(language, Text::Cancel)).clicked() {
+                    self.cancel_sending();
+                }
+            }
+        });
+
+        ui.add_space(10.0);
+
+        if !*self.state.is_sending.lock().unwrap() {
+            let status = self.state.status.lock().unwrap().clone();
+            if !status.is_empty() {
+                ui.label(i18n::display(&status));
+            }
+        }
+
+        ui.add_space(10.0);
+
+        self.batch_ui(ui);
+
+        ui.add_space(10.0);
+
+        self.history_ui(ui);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MailerError;
+    use crate::sender::mock::{MockEmailOperations, mock_error};
+
+    #[test]
+    fn test_esim_mailer_app_default() {
+        let app = EsimMailerApp::default();
+        assert_eq!(app.state.args, Args::default());
+        assert!(app.state.image_paths.is_empty());
+        assert_eq!(app.state.status.lock().unwrap().as_str(), "");
+        assert_ne!(app.state.email_preview, "");
+        assert!(!(*app.state.is_sending.lock().unwrap()));
+    }
+
+    #[test]
+    fn test_generate_preview() {
+        let mut app = EsimMailerApp::default();
+        app.state.args = Args {
+            email_from: "from@example.com".to_string(),
+            email_to: "to@example.com".to_string(),
+            bcc: Some("bcc@example.com".to_string()),
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            data_amount: "5GB".to_string(),
+            time_period: "30 days".to_string(),
+            location: "Egypt".to_string(),
+            confirmation_code: None,
+            bundle: false,
+            normalize_images: false,
+            template: None,
+            language: Language::English,
+            variables: Default::default(),
+            numbering: Default::default(),
+            smtp: None,
+        };
+
+        app.generate_preview();
+
+        assert!(
+            app.state
                 .email_preview
                 .contains("Subject: [TestProvider] Egypt eSIM")
         );
@@ -437,12 +2057,12 @@ mod tests {
         app.state.args.email_from = "test@gmail.com".to_string();
         app.state.image_paths = vec![PathBuf::from("test.png")];
 
-        app.send_email_async();
+        app.send_email_async(app.batch_items());
 
         // Give the async operation time to complete
         std::thread::sleep(std::time::Duration::from_millis(100));
 
-        assert_eq!(*mock_ops.send_count.lock().unwrap(), 1);
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 1);
         assert!(
             app.state
                 .status
@@ -461,7 +2081,7 @@ mod tests {
         app.state.args.email_from = "test@gmail.com".to_string();
         app.state.image_paths = vec![PathBuf::from("test.png")];
 
-        app.send_email_async();
+        app.send_email_async(app.batch_items());
 
         // Give the async operation time to complete
         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -469,26 +2089,621 @@ mod tests {
         assert!(app.state.status.lock().unwrap().contains("Error"));
     }
 
+    #[test]
+    fn test_quota_warning() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops);
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.update_activation_codes(
+            "LPA:1$smdp.example.com$ONE\nLPA:1$smdp.example.com$TWO\n".to_string(),
+        );
+        assert_eq!(app.state.quota_warning, None);
+
+        app.state.rate_limits.remove("Gmail");
+        assert_eq!(app.rate_limit(&Provider::Gmail).per_day, 500);
+        app.state.rate_limits.insert(
+            "Gmail".to_string(),
+            RateLimit {
+                per_day: 3,
+                ..RateLimit::default()
+            },
+        );
+        app.update_quota_warning();
+        assert_eq!(app.state.quota_warning, None);
+
+        // Sending counts against the limit once the batch finishes
+        app.send_email_async(app.batch_items());
+        app.poll_sending();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(app.state.quota_warning, None);
+        app.poll_sending();
+        let warning = app.state.quota_warning.clone().unwrap();
+        assert_eq!((warning.sent, warning.batch, warning.per_day), (2, 2, 3));
+    }
+
+    #[test]
+    fn test_send_email_retries_transient_failures() {
+        let mock_ops = MockEmailOperations::new(false);
+        mock_ops.fail(
+            1,
+            || {
+                let error = crate::error::smtp_error("421 4.7.0 Try again later");
+                MailerError::from_smtp(error, &email::Provider::Gmail, "test@gmail.com")
+            },
+            usize::MAX,
+        );
+        let mock_ops = Arc::new(mock_ops);
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.state.image_paths = ["a.png", "b.png"].map(PathBuf::from).to_vec();
+        app.send_email_async(app.batch_items());
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 1);
+        let items = app.progress.lock().unwrap().items().to_vec();
+        let Outcome::Failed { error, attempts } = &items[0].outcome else {
+            panic!("expected the first email to fail");
+        };
+        assert_eq!(*attempts, 4);
+        assert!(error.contains("may accept it later"));
+        assert!(matches!(
+            items[1].outcome,
+            Outcome::Sent { attempts: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn test_send_email_continues_and_retries_failed() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        mock_ops.fail(2, mock_error, usize::MAX);
+
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.state.image_paths = ["a.png", "b.png", "c.png"].map(PathBuf::from).to_vec();
+
+        app.send_email_async(app.batch_items());
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 2);
+        let summary = app.progress.lock().unwrap().summary();
+        assert_eq!((summary.sent, summary.failed, summary.pending), (2, 1, 0));
+        assert_eq!(
+            app.progress.lock().unwrap().items()[1].outcome,
+            Outcome::Failed {
+                error: "Mock error".to_string(),
+                attempts: 1
+            }
+        );
+        assert!(app.state.status.lock().unwrap().contains("1 failed"));
+
+        *mock_ops.failing.lock().unwrap() = None;
+        app.retry_failed();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 3);
+        assert_eq!(app.progress.lock().unwrap().summary().sent, 3);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .contains("3 eSIM emails sent successfully")
+        );
+    }
+
+    #[test]
+    fn test_send_email_stops_when_sign_in_rejected() {
+        let mock_ops = MockEmailOperations::new(false);
+        mock_ops.fail(
+            2,
+            || {
+                let error = crate::error::smtp_error("535 5.7.8 Token expired");
+                MailerError::from_smtp(error, &email::Provider::Gmail, "test@gmail.com")
+            },
+            usize::MAX,
+        );
+        let mock_ops = Arc::new(mock_ops);
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.state.image_paths = ["a.png", "b.png", "c.png"].map(PathBuf::from).to_vec();
+
+        app.send_email_async(app.batch_items());
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 1);
+        let summary = app.progress.lock().unwrap().summary();
+        assert_eq!((summary.sent, summary.failed, summary.pending), (1, 0, 2));
+        assert_eq!(*mock_ops.forgotten.lock().unwrap(), vec!["test@gmail.com"]);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .contains("Click Resume to sign in again")
+        );
+    }
+
+    #[test]
+    fn test_cancel_sending() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.state.image_paths = ["a.png", "b.png", "c.png"].map(PathBuf::from).to_vec();
+        // One email a second leaves time to cancel after the first
+        app.state.rate_limits.insert(
+            "Gmail".to_string(),
+            RateLimit {
+                per_minute: 60,
+                ..RateLimit::default()
+            },
+        );
+
+        app.send_email_async(app.batch_items());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        app.cancel_sending();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        assert!(!*app.state.is_sending.lock().unwrap());
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 1);
+        let summary = app.progress.lock().unwrap().summary();
+        assert_eq!((summary.sent, summary.failed, summary.pending), (1, 0, 2));
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .contains("Sending cancelled")
+        );
+    }
+
+    #[test]
+    fn test_send_pending_resumes_batch() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "test@gmail.com".to_string();
+        let items = BatchItem::for_qr_codes(
+            &app.state.args,
+            &["a.png", "b.png"].map(|file| QrSource::Image(PathBuf::from(file))),
+        );
+        {
+            let mut progress = app.progress.lock().unwrap();
+            progress.start(items).unwrap();
+            progress
+                .set_outcome(
+                    0,
+                    Outcome::Sent {
+                        message_id: "<earlier@mock>".to_string(),
+                        attempts: 1,
+                    },
+                )
+                .unwrap();
+        }
+
+        app.send_pending();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 1);
+        assert_eq!(app.progress.lock().unwrap().summary().sent, 2);
+
+        app.send_pending();
+        assert_eq!(
+            app.state.status.lock().unwrap().as_str(),
+            "Nothing left to send."
+        );
+    }
+
+    #[test]
+    fn test_send_email_csv_import() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.state.image_paths = vec![PathBuf::from("ignored.png")];
+        let row = |to: &str, path: &str| BatchItem {
+            args: Args {
+                email_from: "test@gmail.com".to_string(),
+                email_to: to.to_string(),
+                ..Default::default()
+            },
+            qr_codes: vec![QrSource::Image(PathBuf::from(path))],
+        };
+        app.state.csv_import = Some(ValidationReport {
+            items: vec![
+                row("one@example.com", "a.png"),
+                row("two@example.com", "b.png"),
+            ],
+            ..Default::default()
+        });
+        assert!(app.can_send());
+
+        app.send_email_async(app.batch_items());
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_cannot_send_invalid_csv_import() {
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        assert!(app.can_send());
+
+        app.state.csv_import = Some(ValidationReport {
+            errors: vec![batch::RowError {
+                line: 2,
+                message: "missing recipient".to_string(),
+            }],
+            ..Default::default()
+        });
+        assert!(!app.can_send());
+    }
+
+    #[test]
+    fn test_send_email_unsupported_provider() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "test@unsupported.com".to_string();
+        app.state.image_paths = vec![PathBuf::from("test.png")];
+
+        app.send_email_async(app.batch_items());
+
+        assert!(!*app.state.is_sending.lock().unwrap());
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 0);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .contains("No supported email provider")
+        );
+    }
+
+    #[test]
+    fn test_send_email_custom_smtp() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "me@example.org".to_string();
+        app.state.args.smtp = Some(SmtpConfig {
+            host: "mail.example.org".to_string(),
+            ..Default::default()
+        });
+        app.state.image_paths = vec![PathBuf::from("test.png")];
+
+        app.send_email_async(app.batch_items());
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_save_smtp_password() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "me@example.org".to_string();
+        app.state.args.smtp = Some(SmtpConfig::default());
+        app.state.smtp_password = "secret".to_string();
+
+        app.save_smtp_password();
+
+        assert!(app.state.smtp_password.is_empty());
+        assert_eq!(*mock_ops.saved_passwords.lock().unwrap(), vec!["secret"]);
+        assert_eq!(app.state.status.lock().unwrap().as_str(), "Password saved.");
+    }
+
+    #[test]
+    fn test_arabic_interface() {
+        let email_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(email_ops);
+        app.state.ui_language = Language::Arabic;
+        app.state.args.smtp = Some(SmtpConfig::default());
+        app.state.smtp_password = "secret".to_string();
+        app.save_smtp_password();
+        assert_eq!(
+            app.state.status.lock().unwrap().as_str(),
+            "تم حفظ كلمة المرور."
+        );
+
+        // Email texts keep the language of the email, around Arabic labels
+        app.update_form_field(FormField::Location, "Gaza".to_string());
+        assert!(app.get_preview().contains("الموضوع: [] Gaza eSIM - 1"));
+        assert!(app.get_preview().contains("\nنص الرسالة:\n"));
+
+        let duplicate = Duplicate {
+            index: 1,
+            qr: QrSource::Image(PathBuf::from("a.png")),
+            previous: None,
+        };
+        assert_eq!(
+            duplicate_text(Language::Arabic, &duplicate),
+            "يتكرر a.png أكثر من مرة في هذه الدفعة"
+        );
+        assert_eq!(FormField::Location.label().get(Language::Arabic), "الموقع");
+    }
+
+    #[test]
+    fn test_select_qr_codes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let valid = dir.join("valid.png");
+        crate::qr::write_test_qr(&valid, "LPA:1$rsp.example.com$ABC");
+        let invalid = dir.join("invalid.png");
+        crate::qr::write_test_qr(&invalid, "hello");
+
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        app.select_qr_codes(vec![valid.clone()]);
+        assert!(app.can_send());
+        assert!(
+            app.get_preview()
+                .contains("SM-DP+ address: rsp.example.com")
+        );
+
+        app.select_qr_codes(vec![valid, invalid]);
+        assert!(!app.can_send());
+        assert!(app.state.qr_checks[1].result.is_err());
+    }
+
+    #[test]
+    fn test_activation_codes() {
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        app.update_activation_codes(
+            "LPA:1$rsp.example.com$ONE\n\nLPA:1$rsp.example.com$TWO\n".to_string(),
+        );
+
+        assert!(app.can_send());
+        let items = app.batch_items();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[1].qr_codes,
+            vec![QrSource::ActivationCode(
+                "LPA:1$rsp.example.com$TWO".parse().unwrap()
+            )]
+        );
+        assert!(
+            app.get_preview()
+                .contains("SM-DP+ address: rsp.example.com")
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("codes.txt");
+        std::fs::write(&path, "rsp.example.com$THREE\n").unwrap();
+        app.load_activation_codes(&path);
+
+        assert!(!app.can_send());
+        assert_eq!(app.batch_items().len(), 2);
+        assert_eq!(app.state.code_errors[0].line, 4);
+    }
+
+    #[test]
+    fn test_preview_navigation() {
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        app.state.args.email_to = "friend@example.com".to_string();
+        app.update_activation_codes(
+            "LPA:1$rsp.example.com$ONE\nLPA:1$rsp.example.com$TWO\n".to_string(),
+        );
+        assert_eq!(app.state.preview_count, 2);
+        let preview = app.state.preview.clone().unwrap();
+        assert_eq!(preview.email_to, "friend@example.com");
+        assert!(preview.subject.ends_with(" - 1"));
+
+        app.show_preview(1);
+        let preview = app.state.preview.clone().unwrap();
+        assert!(preview.subject.ends_with(" - 2"));
+        assert_eq!(
+            preview.qr_codes,
+            vec![(
+                "preview-qr-1".to_string(),
+                QrSource::ActivationCode("LPA:1$rsp.example.com$TWO".parse().unwrap())
+            )]
+        );
+        let spans: Vec<_> = preview
+            .lines
+            .iter()
+            .flat_map(|line| line.tagged_strings())
+            .collect();
+        assert!(
+            spans
+                .iter()
+                .any(|span| span.s == "TWO" && span.tag.contains(&RichAnnotation::Strong))
+        );
+        assert!(spans.iter().any(|span| {
+            span.tag
+                .contains(&RichAnnotation::Image("cid:preview-qr-1".to_string()))
+        }));
+        assert!(!app.get_preview().contains("<b>ONE</b>"));
+
+        let ctx = egui::Context::default();
+        assert!(load_thumbnail(&ctx, &preview.qr_codes[0].1).is_some());
+        assert!(load_thumbnail(&ctx, &QrSource::Image(PathBuf::from("missing.png"))).is_none());
+
+        // Going past the end stays on the last email
+        app.show_preview(5);
+        assert_eq!(app.state.preview_index, 1);
+        app.update_activation_codes("LPA:1$rsp.example.com$ONE\n".to_string());
+        assert_eq!(app.state.preview_index, 0);
+    }
+
+    #[test]
+    fn test_export_eml() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+        app.state.args.email_from = "sender@example.com".to_string();
+        app.state.args.email_to = "recipient@example.com".to_string();
+        app.update_activation_codes(
+            "LPA:1$rsp.example.com$ONE\nLPA:1$rsp.example.com$TWO\n".to_string(),
+        );
+
+        app.export_eml(dir);
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .starts_with("2 emails written")
+        );
+        assert_eq!(mock_ops.sent.lock().unwrap().len(), 0);
+
+        app.state.args.email_to = "not an address".to_string();
+        app.update_activation_codes("LPA:1$rsp.example.com$ONE\n".to_string());
+        app.export_eml(dir);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .starts_with("Could not write the email to not an address")
+        );
+    }
+
+    #[test]
+    fn test_bundle_qr_codes() {
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        app.update_activation_codes(
+            "LPA:1$rsp.example.com$ONE\nLPA:1$rsp.example.com$TWO\n".to_string(),
+        );
+        assert!(!app.get_preview().contains("eSIM 2"));
+
+        app.state.args.bundle = true;
+        app.generate_preview();
+        let items = app.batch_items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].qr_codes.len(), 2);
+        assert!(app.get_preview().contains("eSIM 2"));
+        assert!(app.get_preview().contains("<b>TWO</b>"));
+        assert!(app.can_send());
+
+        app.state.saved_template.body_template = "<img src=\"cid:{{QR_CID}}\">".to_string();
+        assert!(app.needs_esims_loop());
+        assert!(!app.can_send());
+    }
+
+    #[test]
+    fn test_edit_and_select_templates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        app.templates = Some(TemplateStore::open(dir).unwrap());
+        app.state.args.location = "Gaza".to_string();
+        app.load_templates();
+        assert_eq!(app.state.template_name, BUILT_IN);
+
+        app.state.template.subject_template = "Your {{location}} eSIM".to_string();
+        app.state.template_name = "Short".to_string();
+        app.save_template();
+
+        assert_eq!(app.state.template_names, vec!["Short"]);
+        assert_eq!(app.state.args.template.as_deref(), Some("Short"));
+        assert_eq!(app.state.template, app.state.saved_template);
+        assert!(app.get_preview().contains("Subject: Your Gaza eSIM - 1"));
+
+        app.select_template(BUILT_IN.to_string());
+        assert_eq!(app.state.args.template, None);
+        assert!(app.get_preview().contains("Subject: [] Gaza eSIM - 1"));
+        app.state.args.numbering.start = 7;
+        app.state.args.numbering.width = 2;
+        app.generate_preview();
+        assert!(app.get_preview().contains("Subject: [] Gaza eSIM - 07"));
+        app.state.args.numbering = Default::default();
+
+        app.select_template("Short".to_string());
+        app.select_language(Language::Arabic);
+        assert_eq!(app.state.args.template.as_deref(), Some("Short"));
+        assert_eq!(
+            app.state.template.subject_template,
+            "Your {{location}} eSIM"
+        );
+        app.state.template.subject_template = "شريحة eSIM في {{location}}".to_string();
+        app.save_template();
+        assert!(
+            app.get_preview()
+                .contains("Subject: شريحة eSIM في Gaza - 1")
+        );
+        app.select_language(Language::English);
+        assert!(app.get_preview().contains("Subject: Your Gaza eSIM - 1"));
+
+        app.delete_template();
+        assert!(app.state.template_names.is_empty());
+        assert_eq!(app.state.template, EmailTemplate::new());
+
+        app.state.template.body_template = "Hi {{nmae}}".to_string();
+        app.generate_preview();
+        assert!(
+            app.state
+                .template_error
+                .as_ref()
+                .is_some_and(|e| e.contains("did you mean {{name}}?"))
+        );
+        app.state.template_name = "Typo".to_string();
+        app.save_template();
+        assert!(app.state.template_names.is_empty());
+
+        app.select_template("Missing".to_string());
+        assert_eq!(app.state.args.template, None);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .contains("using the built-in one")
+        );
+    }
+
+    #[test]
+    fn test_template_variables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut app = EsimMailerApp::new_with_email_ops(Arc::new(MockEmailOperations::new(false)));
+        app.templates = Some(TemplateStore::open(dir).unwrap());
+        app.state.image_paths = vec![PathBuf::from("qr.png")];
+        app.load_templates();
+
+        app.update_template_variables("iccid, apn".to_string());
+        assert_eq!(app.state.template.variables, vec!["iccid", "apn"]);
+        app.state.template.body_template = "<p>{{iccid}} / {{apn}}</p>".to_string();
+        app.state.template_name = "Vendor".to_string();
+        app.save_template();
+        assert_eq!(app.state.template_variables, "iccid, apn");
+        assert_eq!(app.missing_variables(), vec!["iccid", "apn"]);
+        assert!(app.get_preview().contains("<p>{{iccid}} / {{apn}}</p>"));
+
+        assert!(app.update_variable("iccid", "8901".to_string()));
+        assert!(!app.update_variable("iccid", "8901".to_string()));
+        app.update_variable("apn", "internet".to_string());
+        assert!(app.missing_variables().is_empty());
+        assert!(app.get_preview().contains("<p>8901 / internet</p>"));
+
+        app.update_template_variables("iccid, support link".to_string());
+        assert!(
+            app.state
+                .template_error
+                .as_ref()
+                .is_some_and(|e| e.contains("'support link'"))
+        );
+    }
+
     #[test]
     fn test_form_field_updates() {
         let email_ops = Arc::new(MockEmailOperations::new(false));
         let mut app = EsimMailerApp::new_with_email_ops(email_ops);
 
         // Test email from update
-        assert!(app.update_form_field("From", "test@example.com".to_string()));
+        assert!(app.update_form_field(FormField::From, "test@example.com".to_string()));
         assert_eq!(app.get_form_state().email_from, "test@example.com");
 
         // Test no change when same value
-        assert!(!app.update_form_field("From", "test@example.com".to_string()));
+        assert!(!app.update_form_field(FormField::From, "test@example.com".to_string()));
 
         // Test multiple field updates
-        assert!(app.update_form_field("To", "recipient@example.com".to_string()));
-        assert!(app.update_form_field("Name", "John Doe".to_string()));
-        assert!(app.update_form_field("Provider", "TestProvider".to_string()));
-        assert!(app.update_form_field("Data Amount", "10GB".to_string()));
-        assert!(app.update_form_field("Time Period", "60 days".to_string()));
-        assert!(app.update_form_field("Location", "Japan".to_string()));
-        assert!(app.update_form_field("BCC", "bcc@example.com".to_string()));
+        assert!(app.update_form_field(FormField::To, "recipient@example.com".to_string()));
+        assert!(app.update_form_field(FormField::Name, "John Doe".to_string()));
+        assert!(app.update_form_field(FormField::Provider, "TestProvider".to_string()));
+        assert!(app.update_form_field(FormField::DataAmount, "10GB".to_string()));
+        assert!(app.update_form_field(FormField::TimePeriod, "60 days".to_string()));
+        assert!(app.update_form_field(FormField::Location, "Japan".to_string()));
+        assert!(app.update_form_field(FormField::Bcc, "bcc@example.com".to_string()));
+        assert!(app.update_form_field(FormField::ConfirmationCode, "1234".to_string()));
 
         let state = app.get_form_state();
         assert_eq!(state.email_to, "recipient@example.com");
@@ -498,15 +2713,16 @@ mod tests {
         assert_eq!(state.time_period, "60 days");
         assert_eq!(state.location, "Japan");
         assert_eq!(state.bcc, Some("bcc@example.com".to_string()));
+        assert_eq!(state.confirmation_code, Some("1234".to_string()));
 
         // Test no change when setting same values again
-        assert!(!app.update_form_field("Data Amount", "10GB".to_string()));
-        assert!(!app.update_form_field("Time Period", "60 days".to_string()));
-        assert!(!app.update_form_field("Location", "Japan".to_string()));
-        assert!(!app.update_form_field("BCC", "bcc@example.com".to_string()));
+        assert!(!app.update_form_field(FormField::DataAmount, "10GB".to_string()));
+        assert!(!app.update_form_field(FormField::TimePeriod, "60 days".to_string()));
+        assert!(!app.update_form_field(FormField::Location, "Japan".to_string()));
+        assert!(!app.update_form_field(FormField::Bcc, "bcc@example.com".to_string()));
 
         // Test empty BCC
-        assert!(app.update_form_field("BCC", "".to_string()));
+        assert!(app.update_form_field(FormField::Bcc, "".to_string()));
         assert_eq!(app.get_form_state().bcc, Some("".to_string()));
 
         // Verify preview is updated with all fields
@@ -0,0 +1,662 @@
+use std::fmt::Display;
+
+use unicode_bidi::ParagraphBidiInfo;
+
+use crate::language::Language;
+
+/// A piece of text shown in the GUI, looked up in the catalog of the
+/// interface language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Text {
+    AppName,
+    InterfaceLanguage,
+
+    // Email form
+    From,
+    To,
+    Bcc,
+    Provider,
+    Name,
+    DataAmount,
+    TimePeriod,
+    Location,
+    ConfirmationCode,
+
+    // Mail server
+    MailServer,
+    OAuthProviders,
+    OtherSmtpServer,
+    Host,
+    Security,
+    Port,
+    Login,
+    Password,
+    SavePassword,
+    SendingLimits,
+    PerMinute,
+    PerDay,
+    AtOnce,
+
+    // Templates
+    Template,
+    EmailLanguage,
+    BuiltIn,
+    NumberEmails,
+    NumberStart,
+    NumberWidth,
+    EditTemplate,
+    EditingVersion,
+    Placeholders,
+    Subject,
+    BodyHtml,
+    PlainText,
+    WritePlainText,
+    Variables,
+    VariablesHint,
+    MissingVariables,
+    SaveAs,
+    Save,
+    Revert,
+    Delete,
+    UnsavedChanges,
+
+    // QR codes and recipients
+    NormalizeImages,
+    BundleQrCodes,
+    NeedsEsimsLoop,
+    OverDailyLimit,
+    SelectQrCodes,
+    ImportCsv,
+    ImageFiles,
+    CsvFiles,
+    TextFiles,
+    ImportedRecipients,
+    ClearImport,
+    IgnoredColumn,
+    RowError,
+    SelectedQrCodes,
+    UnreadableQrCode,
+    ActivationCodes,
+    PasteActivationCodes,
+    LoadFromFile,
+    LineError,
+    EmailPreview,
+    PreviewPosition,
+    PreviewRecipient,
+    Previous,
+    Next,
+    HtmlSource,
+    SmdpAddress,
+    Body,
+
+    // Sending
+    SentBefore,
+    AlreadySent,
+    RepeatedInBatch,
+    SkipThem,
+    SendAnyway,
+    Cancel,
+    SendEmail,
+    SaveAsEml,
+    SendingEmail,
+    LastBatch,
+    BatchSummary,
+    Waiting,
+    Sent,
+    SentAfter,
+    Failed,
+    FailedAfter,
+    Resume,
+    RetryFailed,
+    Clear,
+    SendHistory,
+    SentAt,
+    Via,
+    MessageId,
+
+    // Status messages
+    ErrorOpeningHistory,
+    ErrorOpeningBatch,
+    ErrorOpeningTemplates,
+    TemplateError,
+    ErrorListingTemplates,
+    NoTemplatesDirectory,
+    ErrorLoadingTemplate,
+    TemplateNotSaved,
+    TemplateSaved,
+    ErrorSavingTemplate,
+    TemplateDeleted,
+    ErrorDeletingTemplate,
+    ErrorReadingActivationCodes,
+    ErrorImportingCsv,
+    PasswordSaved,
+    ErrorSavingPassword,
+    NothingToSend,
+    NothingLeftToSend,
+    EmlWritten,
+    ErrorWritingEml,
+    ErrorGettingToken,
+    SendingProgress,
+    RetryingProgress,
+    Cancelling,
+    SendingCancelled,
+    EmailsSent,
+    ErrorSendingSome,
+    SignInAgain,
+    BatchStopped,
+    ErrorClearingBatch,
+}
+
+impl Text {
+    /// The text in `language`, with `{}` where [`Text::format`] puts values.
+    pub fn get(self, language: Language) -> &'static str {
+        match language {
+            Language::English => self.english(),
+            Language::Arabic => self.arabic(),
+        }
+    }
+
+    /// The text in `language` with each `{}` replaced by the next of `args`.
+    pub fn format(self, language: Language, args: &[&dyn Display]) -> String {
+        let mut args = args.iter();
+        let mut pieces = self.get(language).split("{}");
+        let mut text = pieces.next().unwrap_or_default().to_string();
+        for piece in pieces {
+            if let Some(arg) = args.next() {
+                text.push_str(&arg.to_string());
+            }
+            text.push_str(piece);
+        }
+        text
+    }
+
+    fn english(self) -> &'static str {
+        match self {
+            Self::AppName => "eSIM Mailer",
+            Self::InterfaceLanguage => "Interface language:",
+
+            Self::From => "From",
+            Self::To => "To",
+            Self::Bcc => "BCC",
+            Self::Provider => "Provider",
+            Self::Name => "Name",
+            Self::DataAmount => "Data Amount",
+            Self::TimePeriod => "Time Period",
+            Self::Location => "Location",
+            Self::ConfirmationCode => "Confirmation Code",
+
+            Self::MailServer => "Mail server:",
+            Self::OAuthProviders => "Gmail / Outlook",
+            Self::OtherSmtpServer => "Other SMTP server",
+            Self::Host => "Host:",
+            Self::Security => "Security:",
+            Self::Port => "Port:",
+            Self::Login => "Login:",
+            Self::Password => "Password:",
+            Self::SavePassword => "Save password",
+            Self::SendingLimits => "Limits for {} (0 for none):",
+            Self::PerMinute => "per minute",
+            Self::PerDay => "per day",
+            Self::AtOnce => "at once",
+
+            Self::Template => "Template:",
+            Self::EmailLanguage => "Language:",
+            Self::BuiltIn => "Built-in",
+            Self::NumberEmails => "Number the subjects",
+            Self::NumberStart => "Start at:",
+            Self::NumberWidth => "Digits:",
+            Self::EditTemplate => "Edit template",
+            Self::EditingVersion => "Editing the version in {}.",
+            Self::Placeholders => {
+                "Placeholders: {}. Conditions and loops use {% if %} and {% for %}."
+            }
+            Self::Subject => "Subject:",
+            Self::BodyHtml => "Body (HTML):",
+            Self::PlainText => "Plain text:",
+            Self::WritePlainText => "Write it instead of converting the HTML",
+            Self::Variables => "Variables:",
+            Self::VariablesHint => "Extra placeholders, separated by commas",
+            Self::MissingVariables => "Missing values for template variables: {}",
+            Self::SaveAs => "Save as:",
+            Self::Save => "Save",
+            Self::Revert => "Revert",
+            Self::Delete => "Delete",
+            Self::UnsavedChanges => "Unsaved changes are only used for the preview.",
+
+            Self::NormalizeImages => "Convert QR code images to PNG and shrink large photos",
+            Self::BundleQrCodes => "Send all the QR codes in a single email",
+            Self::NeedsEsimsLoop => {
+                "The template shows a single QR code. Add a {% for esim in esims %} loop to bundle several."
+            }
+            Self::OverDailyLimit => {
+                "Sending {} emails would take {} over its limit of {} a day, {} were sent in the last 24 hours. Emails past the limit will likely be rejected until tomorrow."
+            }
+            Self::SelectQrCodes => "Select QR codes",
+            Self::ImportCsv => "Import CSV",
+            Self::ImageFiles => "Image Files",
+            Self::CsvFiles => "CSV Files",
+            Self::TextFiles => "Text Files",
+            Self::ImportedRecipients => "Imported recipients: {}",
+            Self::ClearImport => "Clear import",
+            Self::IgnoredColumn => "Ignored unknown column '{}'",
+            Self::RowError => "Row {}: {}",
+            Self::SelectedQrCodes => "Selected QR codes: {}",
+            Self::UnreadableQrCode => "Can't send {}: {}",
+            Self::ActivationCodes => "Activation codes",
+            Self::PasteActivationCodes => {
+                "Paste codes given as text, one per line, to send them as QR codes."
+            }
+            Self::LoadFromFile => "Load from file",
+            Self::LineError => "Line {}: {}",
+            Self::EmailPreview => "Email Preview:",
+            Self::PreviewPosition => "Email {} of {}",
+            Self::PreviewRecipient => "To: {}",
+            Self::Previous => "Previous",
+            Self::Next => "Next",
+            Self::HtmlSource => "HTML source",
+            Self::SmdpAddress => "SM-DP+ address:",
+            Self::Body => "Body:",
+
+            Self::SentBefore => "These QR codes were sent before and may already be activated:",
+            Self::AlreadySent => "{} was already sent to {} on {}",
+            Self::RepeatedInBatch => "{} appears more than once in this batch",
+            Self::SkipThem => "Skip them",
+            Self::SendAnyway => "Send anyway",
+            Self::Cancel => "Cancel",
+            Self::SendEmail => "Send Email",
+            Self::SaveAsEml => "Save as .eml files",
+            Self::SendingEmail => "Sending email...",
+            Self::LastBatch => "Last batch: {}",
+            Self::BatchSummary => "{} sent, {} failed, {} waiting",
+            Self::Waiting => "Waiting",
+            Self::Sent => "Sent",
+            Self::SentAfter => "Sent after {} attempts",
+            Self::Failed => "Failed: {}",
+            Self::FailedAfter => "Failed after {} attempts: {}",
+            Self::Resume => "Resume",
+            Self::RetryFailed => "Retry failed",
+            Self::Clear => "Clear",
+            Self::SendHistory => "Send history ({})",
+            Self::SentAt => "Sent",
+            Self::Via => "Via",
+            Self::MessageId => "Message-ID",
+
+            Self::ErrorOpeningHistory => {
+                "Error opening send history, duplicates can't be detected: {}"
+            }
+            Self::ErrorOpeningBatch => "Error opening unfinished batch: {}",
+            Self::ErrorOpeningTemplates => "Error opening templates: {}",
+            Self::TemplateError => "Template error: {}",
+            Self::ErrorListingTemplates => "Error listing templates: {}",
+            Self::NoTemplatesDirectory => "No templates directory",
+            Self::ErrorLoadingTemplate => "Error loading template, using the built-in one: {}",
+            Self::TemplateNotSaved => "Template not saved: {}",
+            Self::TemplateSaved => "Template '{}' saved ({}).",
+            Self::ErrorSavingTemplate => "Error saving template: {}",
+            Self::TemplateDeleted => "Template '{}' deleted.",
+            Self::ErrorDeletingTemplate => "Error deleting template: {}",
+            Self::ErrorReadingActivationCodes => "Error reading activation codes: {}",
+            Self::ErrorImportingCsv => "Error importing CSV: {}",
+            Self::PasswordSaved => "Password saved.",
+            Self::ErrorSavingPassword => "Error saving password: {}",
+            Self::NothingToSend => "Nothing to send, every QR code was sent before.",
+            Self::NothingLeftToSend => "Nothing left to send.",
+            Self::EmlWritten => "{} emails written to {}. Nothing was sent.",
+            Self::ErrorWritingEml => "Could not write the email to {}: {}",
+            Self::ErrorGettingToken => "Error getting OAuth token: {}",
+            Self::SendingProgress => "Sending {} of {}...",
+            Self::RetryingProgress => "Sending {} of {}, attempt {} in {} seconds: {}",
+            Self::Cancelling => "Cancelling once the emails on their way are sent...",
+            Self::SendingCancelled => "Sending cancelled: {}. Click Resume to send the rest.",
+            Self::EmailsSent => "{} eSIM emails sent successfully.",
+            Self::ErrorSendingSome => "Error sending some emails: {}.",
+            Self::SignInAgain => "{}. Click Resume to sign in again and send the rest.",
+            Self::BatchStopped => "Sending stopped: {}. Click Resume to send the rest.",
+            Self::ErrorClearingBatch => "Error clearing batch: {}",
+        }
+    }
+
+    fn arabic(self) -> &'static str {
+        match self {
+            Self::AppName => "مرسل eSIM",
+            Self::InterfaceLanguage => "لغة الواجهة:",
+
+            Self::From => "من",
+            Self::To => "إلى",
+            Self::Bcc => "نسخة مخفية",
+            Self::Provider => "مزود الخدمة",
+            Self::Name => "الاسم",
+            Self::DataAmount => "حجم البيانات",
+            Self::TimePeriod => "المدة",
+            Self::Location => "الموقع",
+            Self::ConfirmationCode => "رمز التأكيد",
+
+            Self::MailServer => "خادم البريد:",
+            Self::OAuthProviders => "Gmail / Outlook",
+            Self::OtherSmtpServer => "خادم SMTP آخر",
+            Self::Host => "الخادم:",
+            Self::Security => "الأمان:",
+            Self::Port => "المنفذ:",
+            Self::Login => "تسجيل الدخول:",
+            Self::Password => "كلمة المرور:",
+            Self::SavePassword => "حفظ كلمة المرور",
+            Self::SendingLimits => "حدود {} (0 بلا حد):",
+            Self::PerMinute => "في الدقيقة",
+            Self::PerDay => "في اليوم",
+            Self::AtOnce => "في وقت واحد",
+
+            Self::Template => "القالب:",
+            Self::EmailLanguage => "اللغة:",
+            Self::BuiltIn => "المدمج",
+            Self::NumberEmails => "ترقيم العناوين",
+            Self::NumberStart => "البدء من:",
+            Self::NumberWidth => "عدد الخانات:",
+            Self::EditTemplate => "تعديل القالب",
+            Self::EditingVersion => "تعديل النسخة باللغة: {}",
+            Self::Placeholders => "المتغيرات: {}. استخدم {% if %} و{% for %} للشروط والتكرار.",
+            Self::Subject => "الموضوع:",
+            Self::BodyHtml => "نص الرسالة (HTML):",
+            Self::PlainText => "نص عادي:",
+            Self::WritePlainText => "كتابته بدلا من تحويله من HTML",
+            Self::Variables => "متغيرات إضافية:",
+            Self::VariablesHint => "متغيرات إضافية مفصولة بفواصل",
+            Self::MissingVariables => "قيم ناقصة لمتغيرات القالب: {}",
+            Self::SaveAs => "حفظ باسم:",
+            Self::Save => "حفظ",
+            Self::Revert => "تراجع",
+            Self::Delete => "حذف",
+            Self::UnsavedChanges => "التغييرات غير المحفوظة تظهر في المعاينة فقط.",
+
+            Self::NormalizeImages => "تحويل صور رموز QR إلى PNG وتصغير الصور الكبيرة",
+            Self::BundleQrCodes => "إرسال كل رموز QR في رسالة واحدة",
+            Self::NeedsEsimsLoop => {
+                "القالب يعرض رمز QR واحدًا فقط. أضف حلقة {% for esim in esims %} لإرسال عدة رموز معًا."
+            }
+            Self::OverDailyLimit => {
+                "إرسال {} رسالة سيتجاوز بالحساب {} حده البالغ {} في اليوم، فقد أرسل {} خلال آخر 24 ساعة. من المرجح أن ترفض الرسائل الزائدة حتى الغد."
+            }
+            Self::SelectQrCodes => "اختيار رموز QR",
+            Self::ImportCsv => "استيراد CSV",
+            Self::ImageFiles => "ملفات الصور",
+            Self::CsvFiles => "ملفات CSV",
+            Self::TextFiles => "ملفات نصية",
+            Self::ImportedRecipients => "المستلمون المستوردون: {}",
+            Self::ClearImport => "مسح الاستيراد",
+            Self::IgnoredColumn => "تم تجاهل العمود غير المعروف '{}'",
+            Self::RowError => "الصف {}: {}",
+            Self::SelectedQrCodes => "رموز QR المختارة: {}",
+            Self::UnreadableQrCode => "لا يمكن إرسال {}: {}",
+            Self::ActivationCodes => "رموز التفعيل",
+            Self::PasteActivationCodes => "الصق الرموز النصية، رمزا في كل سطر، لإرسالها كرموز QR.",
+            Self::LoadFromFile => "تحميل من ملف",
+            Self::LineError => "السطر {}: {}",
+            Self::EmailPreview => "معاينة الرسالة:",
+            Self::PreviewPosition => "الرسالة {} من {}",
+            Self::PreviewRecipient => "إلى: {}",
+            Self::Previous => "السابق",
+            Self::Next => "التالي",
+            Self::HtmlSource => "مصدر HTML",
+            Self::SmdpAddress => "عنوان SM-DP+:",
+            Self::Body => "نص الرسالة:",
+
+            Self::SentBefore => "أرسلت رموز QR هذه من قبل وقد تكون مفعلة بالفعل:",
+            Self::AlreadySent => "أرسل {} من قبل إلى {} في {}",
+            Self::RepeatedInBatch => "يتكرر {} أكثر من مرة في هذه الدفعة",
+            Self::SkipThem => "تخطيها",
+            Self::SendAnyway => "إرسالها على أي حال",
+            Self::Cancel => "إلغاء",
+            Self::SendEmail => "إرسال البريد",
+            Self::SaveAsEml => "حفظ كملفات ‎.eml",
+            Self::SendingEmail => "جار إرسال البريد...",
+            Self::LastBatch => "آخر دفعة: {}",
+            Self::BatchSummary => "أرسل {}، فشل {}، في الانتظار {}",
+            Self::Waiting => "في الانتظار",
+            Self::Sent => "أرسل",
+            Self::SentAfter => "أرسل بعد {} محاولات",
+            Self::Failed => "فشل: {}",
+            Self::FailedAfter => "فشل بعد {} محاولات: {}",
+            Self::Resume => "استئناف",
+            Self::RetryFailed => "إعادة محاولة الفاشلة",
+            Self::Clear => "مسح",
+            Self::SendHistory => "سجل الإرسال ({})",
+            Self::SentAt => "وقت الإرسال",
+            Self::Via => "عبر",
+            Self::MessageId => "Message-ID",
+
+            Self::ErrorOpeningHistory => "خطأ في فتح سجل الإرسال، لا يمكن اكتشاف التكرار: {}",
+            Self::ErrorOpeningBatch => "خطأ في فتح الدفعة غير المكتملة: {}",
+            Self::ErrorOpeningTemplates => "خطأ في فتح القوالب: {}",
+            Self::TemplateError => "خطأ في القالب: {}",
+            Self::ErrorListingTemplates => "خطأ في عرض القوالب: {}",
+            Self::NoTemplatesDirectory => "لا يوجد مجلد للقوالب",
+            Self::ErrorLoadingTemplate => "خطأ في تحميل القالب، سيستخدم القالب المدمج: {}",
+            Self::TemplateNotSaved => "لم يحفظ القالب: {}",
+            Self::TemplateSaved => "تم حفظ القالب '{}' ({}).",
+            Self::ErrorSavingTemplate => "خطأ في حفظ القالب: {}",
+            Self::TemplateDeleted => "تم حذف القالب '{}'.",
+            Self::ErrorDeletingTemplate => "خطأ في حذف القالب: {}",
+            Self::ErrorReadingActivationCodes => "خطأ في قراءة رموز التفعيل: {}",
+            Self::ErrorImportingCsv => "خطأ في استيراد CSV: {}",
+            Self::PasswordSaved => "تم حفظ كلمة المرور.",
+            Self::ErrorSavingPassword => "خطأ في حفظ كلمة المرور: {}",
+            Self::NothingToSend => "لا يوجد ما يرسل، فكل رموز QR أرسلت من قبل.",
+            Self::NothingLeftToSend => "لم يتبق ما يرسل.",
+            Self::EmlWritten => "تمت كتابة {} رسائل في {}. لم يرسل شيء.",
+            Self::ErrorWritingEml => "تعذرت كتابة الرسالة إلى {}: {}",
+            Self::ErrorGettingToken => "خطأ في الحصول على رمز OAuth: {}",
+            Self::SendingProgress => "جار إرسال {} من {}...",
+            Self::RetryingProgress => "جار إرسال {} من {}، المحاولة {} بعد {} ثوان: {}",
+            Self::Cancelling => "جار الإلغاء بعد إرسال الرسائل الجارية...",
+            Self::SendingCancelled => "ألغي الإرسال: {}. انقر على استئناف لإرسال الباقي.",
+            Self::EmailsSent => "تم إرسال {} رسالة eSIM بنجاح.",
+            Self::ErrorSendingSome => "خطأ في إرسال بعض الرسائل: {}.",
+            Self::SignInAgain => "{}. انقر على استئناف لتسجيل الدخول من جديد وإرسال الباقي.",
+            Self::BatchStopped => "توقف الإرسال: {}. انقر على استئناف لإرسال الباقي.",
+            Self::ErrorClearingBatch => "خطأ في مسح الدفعة: {}",
+        }
+    }
+}
+
+/// Prepares text for egui, which neither joins Arabic letters nor lays out
+/// right-to-left text: letters are replaced by their joined presentation
+/// forms and each line is put in visual order. Text without Arabic letters
+/// is returned unchanged.
+pub fn display(text: &str) -> String {
+    if !text.chars().any(is_arabic) {
+        return text.to_string();
+    }
+    text.split('\n')
+        .map(|line| visual_order(&shape(line)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn is_arabic(c: char) -> bool {
+    matches!(c, '\u{0600}'..='\u{06FF}')
+}
+
+/// Arabic letters with the first of their presentation forms and how many
+/// forms they have: 4 for letters that join on both sides (isolated, final,
+/// initial, medial), 2 for letters that only join the letter before them
+/// (isolated, final), 1 for letters that never join.
+const LETTERS: &[(char, u32, u32)] = &[
+    ('\u{0621}', 0xFE80, 1),
+    ('\u{0622}', 0xFE81, 2),
+    ('\u{0623}', 0xFE83, 2),
+    ('\u{0624}', 0xFE85, 2),
+    ('\u{0625}', 0xFE87, 2),
+    ('\u{0626}', 0xFE89, 4),
+    ('\u{0627}', 0xFE8D, 2),
+    ('\u{0628}', 0xFE8F, 4),
+    ('\u{0629}', 0xFE93, 2),
+    ('\u{062A}', 0xFE95, 4),
+    ('\u{062B}', 0xFE99, 4),
+    ('\u{062C}', 0xFE9D, 4),
+    ('\u{062D}', 0xFEA1, 4),
+    ('\u{062E}', 0xFEA5, 4),
+    ('\u{062F}', 0xFEA9, 2),
+    ('\u{0630}', 0xFEAB, 2),
+    ('\u{0631}', 0xFEAD, 2),
+    ('\u{0632}', 0xFEAF, 2),
+    ('\u{0633}', 0xFEB1, 4),
+    ('\u{0634}', 0xFEB5, 4),
+    ('\u{0635}', 0xFEB9, 4),
+    ('\u{0636}', 0xFEBD, 4),
+    ('\u{0637}', 0xFEC1, 4),
+    ('\u{0638}', 0xFEC5, 4),
+    ('\u{0639}', 0xFEC9, 4),
+    ('\u{063A}', 0xFECD, 4),
+    ('\u{0641}', 0xFED1, 4),
+    ('\u{0642}', 0xFED5, 4),
+    ('\u{0643}', 0xFED9, 4),
+    ('\u{0644}', 0xFEDD, 4),
+    ('\u{0645}', 0xFEE1, 4),
+    ('\u{0646}', 0xFEE5, 4),
+    ('\u{0647}', 0xFEE9, 4),
+    ('\u{0648}', 0xFEED, 2),
+    ('\u{0649}', 0xFEEF, 2),
+    ('\u{064A}', 0xFEF1, 4),
+];
+
+const TATWEEL: char = '\u{0640}';
+const LAM: char = '\u{0644}';
+
+/// Lam followed by an alef is written as one ligature, given here as the
+/// alef and the ligature's isolated form (the final form follows it).
+const LAM_ALEF: &[(char, u32)] = &[
+    ('\u{0622}', 0xFEF5),
+    ('\u{0623}', 0xFEF7),
+    ('\u{0625}', 0xFEF9),
+    ('\u{0627}', 0xFEFB),
+];
+
+fn forms(c: char) -> Option<(u32, u32)> {
+    LETTERS
+        .iter()
+        .find(|(letter, _, _)| *letter == c)
+        .map(|&(_, first, count)| (first, count))
+}
+
+fn joins_next(c: char) -> bool {
+    c == TATWEEL || forms(c).is_some_and(|(_, count)| count == 4)
+}
+
+fn joins_previous(c: char) -> bool {
+    c == TATWEEL || forms(c).is_some_and(|(_, count)| count > 1)
+}
+
+/// Vowel marks are drawn over or under letters and don't break joining.
+fn is_mark(c: char) -> bool {
+    matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}')
+}
+
+/// Replaces Arabic letters by the form matching the letters around them.
+fn shape(line: &str) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut shaped = String::with_capacity(line.len());
+    let mut skip_alef = false;
+    for (i, &c) in chars.iter().enumerate() {
+        let Some((first, count)) = forms(c) else {
+            shaped.push(c);
+            continue;
+        };
+        let previous = chars[..i].iter().rev().copied().find(|c| !is_mark(*c));
+        let next_index = (i + 1..chars.len()).find(|&j| !is_mark(chars[j]));
+        let joined_before = previous.is_some_and(joins_next);
+        if std::mem::take(&mut skip_alef) && previous == Some(LAM) {
+            continue;
+        }
+        if c == LAM
+            && let Some(&(_, ligature)) =
+                next_index.and_then(|j| LAM_ALEF.iter().find(|(alef, _)| *alef == chars[j]))
+        {
+            shaped.extend(char::from_u32(ligature + u32::from(joined_before)));
+            skip_alef = true;
+            continue;
+        }
+        let joined_after = count == 4 && next_index.is_some_and(|j| joins_previous(chars[j]));
+        let joined_before = joined_before && count > 1;
+        let offset = match (joined_before, joined_after) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+        };
+        shaped.extend(char::from_u32(first + offset));
+    }
+    shaped
+}
+
+/// Puts a line in the order it's displayed in, mirroring brackets inside
+/// right-to-left runs.
+fn visual_order(line: &str) -> String {
+    let info = ParagraphBidiInfo::new(line, None);
+    let mirrored: String = line
+        .char_indices()
+        .map(|(i, c)| match c {
+            _ if !info.levels[i].is_rtl() => c,
+            '(' => ')',
+            ')' => '(',
+            '[' => ']',
+            ']' => '[',
+            '{' => '}',
+            '}' => '{',
+            '<' => '>',
+            '>' => '<',
+            _ => c,
+        })
+        .collect();
+    let info = ParagraphBidiInfo::new(&mirrored, None);
+    info.reorder_line(0..mirrored.len()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            Text::SendingProgress.format(Language::English, &[&2, &5]),
+            "Sending 2 of 5..."
+        );
+        assert_eq!(
+            Text::SendingProgress.format(Language::Arabic, &[&2, &5]),
+            "جار إرسال 2 من 5..."
+        );
+        // Values containing braces are left alone
+        assert_eq!(
+            Text::TemplateError.format(Language::English, &[&"unknown {{nmae}}"]),
+            "Template error: unknown {{nmae}}"
+        );
+        assert_eq!(
+            Text::Placeholders.format(Language::English, &[&"{{name}}"]),
+            "Placeholders: {{name}}. Conditions and loops use {% if %} and {% for %}."
+        );
+    }
+
+    #[test]
+    fn test_display_leaves_other_text_alone() {
+        assert_eq!(display("Send Email (3)"), "Send Email (3)");
+    }
+
+    #[test]
+    fn test_display_joins_arabic_letters() {
+        // Meem, noon: initial meem then final noon, reversed for display
+        assert_eq!(display("من"), "\u{FEE6}\u{FEE3}");
+        // Lam and alef become one ligature
+        assert_eq!(display("لا"), "\u{FEFB}");
+        // Alef doesn't join the next letter, seen does
+        assert_eq!(display("الا"), "\u{FEFB}\u{FE8D}");
+        assert_eq!(display("سلا"), "\u{FEFC}\u{FEB3}");
+    }
+
+    #[test]
+    fn test_display_orders_mixed_text() {
+        // The number stays left to right and the brackets are mirrored
+        assert_eq!(display("سجل (12)"), format!("(12) {}", display("سجل")));
+        let line = display("إرسال eSIM");
+        assert!(line.starts_with("eSIM "));
+        assert_eq!(display("من\nإلى").lines().count(), 2);
+    }
+}
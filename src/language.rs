@@ -0,0 +1,112 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// A language emails can be written in.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+pub enum Language {
+    #[default]
+    #[serde(rename = "en")]
+    #[value(name = "en", alias = "english")]
+    English,
+    #[serde(rename = "ar")]
+    #[value(name = "ar", alias = "arabic")]
+    Arabic,
+}
+
+/// An error which can be returned when parsing a language.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Unsupported language '{0}', use one of: en, ar")]
+pub struct ParseLanguageError(String);
+
+impl Language {
+    pub const ALL: &[Self] = &[Self::English, Self::Arabic];
+
+    /// The ISO 639-1 code, as used in `lang` attributes and file names.
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::English => "en",
+            Self::Arabic => "ar",
+        }
+    }
+
+    /// The name of the language in the language itself.
+    pub fn native_name(self) -> &'static str {
+        match self {
+            Self::English => "English",
+            Self::Arabic => "العربية",
+        }
+    }
+
+    /// Whether text in the language is written right to left.
+    pub fn is_rtl(self) -> bool {
+        matches!(self, Self::Arabic)
+    }
+
+    /// The value for HTML `dir` attributes.
+    pub fn dir(self) -> &'static str {
+        if self.is_rtl() { "rtl" } else { "ltr" }
+    }
+}
+
+impl FromStr for Language {
+    type Err = ParseLanguageError;
+
+    /// Accepts the code or the English name, in any case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "en" | "english" => Ok(Self::English),
+            "ar" | "arabic" => Ok(Self::Arabic),
+            _ => Err(ParseLanguageError(s.to_string())),
+        }
+    }
+}
+
+impl Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::English => write!(f, "English"),
+            Self::Arabic => write!(f, "Arabic"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_language() {
+        assert_eq!("ar".parse(), Ok(Language::Arabic));
+        assert_eq!("Arabic".parse(), Ok(Language::Arabic));
+        assert_eq!(" EN ".parse(), Ok(Language::English));
+        assert_eq!(
+            "fr".parse::<Language>(),
+            Err(ParseLanguageError("fr".to_string()))
+        );
+        for language in Language::ALL {
+            assert_eq!(language.code().parse(), Ok(*language));
+        }
+    }
+
+    #[test]
+    fn test_language_direction() {
+        assert_eq!(Language::English.dir(), "ltr");
+        assert_eq!(Language::Arabic.dir(), "rtl");
+        assert_eq!(serde_json::to_string(&Language::Arabic).unwrap(), "\"ar\"");
+    }
+}
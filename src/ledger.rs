@@ -0,0 +1,445 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::batch::BatchItem;
+use crate::email::Provider;
+use crate::qr::{QrSource, decode_qr_data};
+
+/// A record of one QR code that was emailed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// SHA-256 of the QR code, used to recognize it when picked again.
+    pub qr_hash: String,
+    pub recipient: String,
+    pub sent_at: DateTime<Utc>,
+    pub message_id: String,
+    pub provider: String,
+    /// The address the email was sent from.
+    pub account: String,
+}
+
+/// A QR code in a batch that has been sent before.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    /// Position of the item holding the QR code in the batch.
+    pub index: usize,
+    pub qr: QrSource,
+    /// The earlier send, or `None` if the same QR code appears earlier in
+    /// the batch itself.
+    pub previous: Option<LedgerEntry>,
+}
+
+impl Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.previous {
+            Some(entry) => write!(
+                f,
+                "{} was already sent to {} on {}",
+                self.qr,
+                entry.recipient,
+                entry.sent_at.format("%Y-%m-%d %H:%M UTC")
+            ),
+            None => write!(f, "{} appears more than once in this batch", self.qr),
+        }
+    }
+}
+
+/// A persistent log of every QR code sent, consulted before sending so that
+/// single-use eSIMs aren't given out twice.
+///
+/// Entries are appended to a JSON Lines file, one per email.
+#[derive(Debug, Default)]
+pub struct SendLedger {
+    path: Option<PathBuf>,
+    entries: Vec<LedgerEntry>,
+}
+
+impl SendLedger {
+    const FILE_NAME: &str = "sent.jsonl";
+
+    /// Opens the ledger in the user's config directory.
+    pub fn open_default() -> io::Result<Self> {
+        Self::open(&crate::paths::app_dir()?.join(Self::FILE_NAME))
+    }
+
+    /// Opens the ledger stored at `path`, which is created on the first send.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        match fs::File::open(path) {
+            Ok(file) => {
+                for (number, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => eprintln!(
+                            "Skipping invalid line {} of {}: {}",
+                            number + 1,
+                            path.display(),
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            entries,
+        })
+    }
+
+    /// All recorded sends, oldest first.
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    /// The most recent send of the QR code with the given hash.
+    pub fn find(&self, qr_hash: &str) -> Option<&LedgerEntry> {
+        self.entries.iter().rev().find(|e| e.qr_hash == qr_hash)
+    }
+
+    /// Number of emails sent from `account` in the 24 hours before `now`,
+    /// which is what providers hold against their daily limits.
+    pub fn sent_in_last_day(&self, account: &str, now: DateTime<Utc>) -> usize {
+        let since = now - TimeDelta::days(1);
+        let mut message_ids: Vec<&str> = self
+            .entries
+            .iter()
+            .filter(|e| e.account == account && e.sent_at > since)
+            .map(|e| e.message_id.as_str())
+            .collect();
+        // Bundled QR codes have one entry each but were one email
+        message_ids.dedup();
+        message_ids.len()
+    }
+
+    /// Returns the items of a batch whose QR codes were sent before or occur
+    /// more than once. Unreadable images are left for the send to report.
+    pub fn find_duplicates(&self, items: &[BatchItem]) -> Vec<Duplicate> {
+        let mut seen = HashMap::new();
+        let mut duplicates = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            for qr in &item.qr_codes {
+                let Ok(qr_hash) = hash_qr_code(qr) else {
+                    continue;
+                };
+                let previous = self.find(&qr_hash).cloned();
+                let repeated = seen.insert(qr_hash, index).is_some();
+                if previous.is_some() || repeated {
+                    duplicates.push(Duplicate {
+                        index,
+                        qr: qr.clone(),
+                        previous,
+                    });
+                }
+            }
+        }
+        duplicates
+    }
+
+    /// Records that `item` was sent, with one entry per QR code saved
+    /// straight away.
+    pub fn record_sent(
+        &mut self,
+        item: &BatchItem,
+        message_id: &str,
+        provider: &Provider,
+    ) -> io::Result<()> {
+        for qr in &item.qr_codes {
+            self.record(LedgerEntry {
+                qr_hash: hash_qr_code(qr)?,
+                recipient: item.args.email_to.clone(),
+                sent_at: Utc::now(),
+                message_id: message_id.to_string(),
+                provider: provider.to_string(),
+                account: item.args.email_from.clone(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, entry: LedgerEntry) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let mut line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+            line.push('\n');
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(line.as_bytes())?;
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+/// Takes the duplicate QR codes out of `items`, leaving out emails with no
+/// QR code left.
+pub fn without_duplicates(items: Vec<BatchItem>, duplicates: &[Duplicate]) -> Vec<BatchItem> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, mut item)| {
+            item.qr_codes.retain(|qr| {
+                !duplicates
+                    .iter()
+                    .any(|duplicate| duplicate.index == index && duplicate.qr == *qr)
+            });
+            (!item.qr_codes.is_empty()).then_some(item)
+        })
+        .collect()
+}
+
+/// Hashes the activation code a QR code holds, so that the same eSIM is
+/// recognized as an image, a re-encoded copy of it or typed text. Images
+/// that can't be decoded are hashed by their contents.
+pub fn hash_qr_code(qr: &QrSource) -> io::Result<String> {
+    let code = match qr {
+        QrSource::Image(path) => {
+            let data = fs::read(path)?;
+            match decode_qr_data(&data) {
+                Ok(code) => code,
+                Err(_) => return Ok(hash_bytes(&data)),
+            }
+        }
+        QrSource::ActivationCode(code) => code.clone(),
+    };
+    Ok(hash_bytes(code.to_string().as_bytes()))
+}
+
+fn hash_bytes(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use crate::qr::write_test_qr;
+
+    /// A directory of files that look like images but can't be decoded, so
+    /// that they are hashed by their contents.
+    fn undecodable_dir(files: &[(&str, &[u8])]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, data) in files {
+            fs::write(dir.path().join(file), data).unwrap();
+        }
+        dir
+    }
+
+    fn item(dir: &Path, file: &str) -> BatchItem {
+        BatchItem {
+            args: Args {
+                email_to: "recipient@example.com".to_string(),
+                ..Default::default()
+            },
+            qr_codes: vec![QrSource::Image(dir.join(file))],
+        }
+    }
+
+    #[test]
+    fn test_record_and_reopen() {
+        let tmp = undecodable_dir(&[("a.png", b"qr a")]);
+        let dir = tmp.path();
+        let path = dir.join("sent.jsonl");
+
+        let mut ledger = SendLedger::open(&path).unwrap();
+        assert!(ledger.entries().is_empty());
+        ledger
+            .record_sent(&item(dir, "a.png"), "<id@gmail.com>", &Provider::Gmail)
+            .unwrap();
+
+        let reopened = SendLedger::open(&path).unwrap();
+        assert_eq!(reopened.entries(), ledger.entries());
+        let entry = &reopened.entries()[0];
+        assert_eq!(entry.recipient, "recipient@example.com");
+        assert_eq!(entry.message_id, "<id@gmail.com>");
+        assert_eq!(entry.provider, "Gmail");
+        assert_eq!(
+            entry.qr_hash,
+            hash_qr_code(&QrSource::Image(dir.join("a.png"))).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sent_in_last_day() {
+        let bundle = |from: &str| BatchItem {
+            args: Args {
+                email_from: from.to_string(),
+                ..Default::default()
+            },
+            qr_codes: ["A", "B"]
+                .map(|id| {
+                    QrSource::ActivationCode(
+                        format!("LPA:1$smdp.example.com${}", id).parse().unwrap(),
+                    )
+                })
+                .to_vec(),
+        };
+        let mut ledger = SendLedger::default();
+        ledger
+            .record_sent(&bundle("me@gmail.com"), "<1>", &Provider::Gmail)
+            .unwrap();
+        ledger
+            .record_sent(&bundle("me@gmail.com"), "<2>", &Provider::Gmail)
+            .unwrap();
+        ledger
+            .record_sent(&bundle("other@gmail.com"), "<3>", &Provider::Gmail)
+            .unwrap();
+
+        let now = Utc::now();
+        assert_eq!(ledger.sent_in_last_day("me@gmail.com", now), 2);
+        assert_eq!(ledger.sent_in_last_day("other@gmail.com", now), 1);
+        assert_eq!(
+            ledger.sent_in_last_day("me@gmail.com", now + TimeDelta::days(1)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let tmp = undecodable_dir(&[
+            ("sent.png", b"qr sent"),
+            ("copy_of_sent.png", b"qr sent"),
+            ("new.png", b"qr new"),
+            ("new_again.png", b"qr new"),
+            ("other.png", b"qr other"),
+        ]);
+        let dir = tmp.path();
+        let mut ledger = SendLedger::default();
+        ledger
+            .record_sent(&item(dir, "sent.png"), "<id>", &Provider::Outlook)
+            .unwrap();
+
+        let items = [
+            item(dir, "copy_of_sent.png"),
+            item(dir, "new.png"),
+            item(dir, "other.png"),
+            item(dir, "new_again.png"),
+            item(dir, "missing.png"),
+        ];
+        let duplicates = ledger.find_duplicates(&items);
+
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].index, 0);
+        assert_eq!(duplicates[0].previous.as_ref().unwrap().provider, "Outlook");
+        assert!(duplicates[0].to_string().contains("already sent to"));
+        assert_eq!(duplicates[1].index, 3);
+        assert_eq!(duplicates[1].previous, None);
+        assert!(duplicates[1].to_string().contains("more than once"));
+    }
+
+    #[test]
+    fn test_find_duplicate_activation_codes() {
+        let code = |id: &str| BatchItem {
+            args: Args::default(),
+            qr_codes: vec![QrSource::ActivationCode(
+                format!("LPA:1$smdp.example.com${}", id).parse().unwrap(),
+            )],
+        };
+        let mut ledger = SendLedger::default();
+        ledger
+            .record_sent(&code("SENT"), "<id>", &Provider::Gmail)
+            .unwrap();
+
+        let duplicates = ledger.find_duplicates(&[code("NEW"), code("SENT")]);
+
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].index, 1);
+        assert!(
+            duplicates[0]
+                .to_string()
+                .starts_with("LPA:1$smdp.example.com$SENT was already sent to")
+        );
+    }
+
+    #[test]
+    fn test_find_same_code_in_another_form() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let lpa = "LPA:1$smdp.example.com$SENT";
+        write_test_qr(&dir.join("sent.png"), lpa);
+        write_test_qr(&dir.join("copy.jpg"), lpa);
+        let mut ledger = SendLedger::default();
+        ledger
+            .record_sent(&item(dir, "sent.png"), "<id>", &Provider::Gmail)
+            .unwrap();
+
+        let typed = BatchItem {
+            args: Args::default(),
+            qr_codes: vec![QrSource::ActivationCode(lpa.parse().unwrap())],
+        };
+        let duplicates = ledger.find_duplicates(&[typed, item(dir, "copy.jpg")]);
+
+        assert_eq!(duplicates.len(), 2);
+        assert!(duplicates.iter().all(|d| d.previous.is_some()));
+    }
+
+    #[test]
+    fn test_bundled_qr_codes() {
+        let tmp = undecodable_dir(&[("a.png", b"qr a"), ("b.png", b"qr b"), ("c.png", b"qr c")]);
+        let dir = tmp.path();
+        let bundle = |files: &[&str]| BatchItem {
+            args: Args::default(),
+            qr_codes: files
+                .iter()
+                .map(|file| QrSource::Image(dir.join(file)))
+                .collect(),
+        };
+        let mut ledger = SendLedger::default();
+        ledger
+            .record_sent(&bundle(&["a.png", "b.png"]), "<id>", &Provider::Gmail)
+            .unwrap();
+        assert_eq!(ledger.entries().len(), 2);
+
+        let items = vec![bundle(&["b.png", "c.png"]), bundle(&["a.png"])];
+        let duplicates = ledger.find_duplicates(&items);
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].qr, QrSource::Image(dir.join("b.png")));
+
+        let remaining = without_duplicates(items, &duplicates);
+        assert_eq!(remaining, vec![bundle(&["c.png"])]);
+    }
+
+    #[test]
+    fn test_open_skips_invalid_lines() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("sent.jsonl");
+        let entry = LedgerEntry {
+            qr_hash: "abc".to_string(),
+            recipient: "recipient@example.com".to_string(),
+            sent_at: Utc::now(),
+            message_id: "<id>".to_string(),
+            provider: "Gmail".to_string(),
+            account: "me@gmail.com".to_string(),
+        };
+        let mut no_account = serde_json::to_value(&entry).unwrap();
+        no_account.as_object_mut().unwrap().remove("account");
+        fs::write(
+            &path,
+            format!(
+                "{}\nnot json\n\n{}\n",
+                serde_json::to_string(&entry).unwrap(),
+                no_account
+            ),
+        )
+        .unwrap();
+
+        let ledger = SendLedger::open(&path).unwrap();
+        assert_eq!(ledger.entries(), &[entry]);
+        assert!(ledger.find("abc").is_some());
+        assert!(ledger.find("def").is_none());
+    }
+}
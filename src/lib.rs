@@ -1,10 +1,23 @@
 pub mod args;
+pub mod batch;
+pub mod cli;
 pub mod email;
 mod embedded;
+pub mod error;
 pub mod gui;
+pub mod i18n;
+pub mod language;
+pub mod ledger;
 pub mod oauth;
+pub mod paths;
+pub mod qr;
+pub mod rate_limit;
+pub mod retry;
+pub mod sender;
+pub mod templates;
 
 // Re-export commonly used items
 pub use args::Args;
 pub use email::send_email;
+pub use error::MailerError;
 pub use oauth::OAuthClient;
@@ -3,28 +3,30 @@ use crate::embedded::{GMAIL_CLIENT_ID, GMAIL_SECRET, NONCE, OUTLOOK_CLIENT_ID, S
 use aes_gcm::aead::{Aead, KeyInit};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use oauth2::basic::BasicClient;
+use oauth2::reqwest::Client as AsyncHttpClient;
 use oauth2::reqwest::blocking::Client as BlockingHttpClient;
 use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointNotSet, EndpointSet,
     PkceCodeChallenge, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
 };
-use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::fs;
 use std::io::{self, BufRead, BufReader, Write};
 use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use url::Url;
 use webbrowser;
 
-#[derive(Serialize, Deserialize)]
-struct CachedToken {
-    refresh_token: String,
-}
-
 // Trait for token storage
 pub trait TokenStorage: Send + Sync {
     fn get_token(&self, key: &str) -> Option<String>;
     fn set_token(&mut self, key: &str, token: String);
+    /// Forgets the token saved under `key`. By default it is overwritten with
+    /// an empty one, which [`OAuthClient`] treats as missing.
+    fn remove_token(&mut self, key: &str) {
+        self.set_token(key, String::new());
+    }
 }
 
 // In-memory implementation of TokenStorage
@@ -41,6 +43,85 @@ impl TokenStorage for MemoryTokenStorage {
     fn set_token(&mut self, key: &str, token: String) {
         self.tokens.insert(key.to_string(), token);
     }
+
+    fn remove_token(&mut self, key: &str) {
+        self.tokens.remove(key);
+    }
+}
+
+/// Token storage that keeps tokens in the user's config directory, so
+/// signing in survives restarts of the app.
+///
+/// The tokens are saved as plain JSON. On Unix the file is readable only by
+/// the current user; anyone who can act as that user can read them.
+pub struct FileTokenStorage {
+    path: PathBuf,
+    tokens: HashMap<String, String>,
+}
+
+impl FileTokenStorage {
+    const TOKENS_FILE: &str = "tokens.json";
+
+    /// Opens the storage for the current user profile.
+    pub fn open_default() -> io::Result<Self> {
+        Self::open(&crate::paths::app_dir()?)
+    }
+
+    /// Opens the storage kept in `dir`, creating the directory if needed.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(Self::TOKENS_FILE);
+
+        let tokens = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self { path, tokens })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.tokens).map_err(io::Error::other)?;
+
+        // Write to a temporary file first so a crash can't leave a torn file.
+        let tmp_path = self.path.with_extension("json.tmp");
+        write_private(&tmp_path, &data)?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+impl TokenStorage for FileTokenStorage {
+    fn get_token(&self, key: &str) -> Option<String> {
+        self.tokens.get(key).cloned()
+    }
+
+    fn set_token(&mut self, key: &str, token: String) {
+        self.tokens.insert(key.to_string(), token);
+        if let Err(e) = self.save() {
+            eprintln!("Error saving tokens to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn remove_token(&mut self, key: &str) {
+        if self.tokens.remove(key).is_some()
+            && let Err(e) = self.save()
+        {
+            eprintln!("Error saving tokens to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Writes a file that only the current user can read.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)
 }
 
 // Trait for browser interaction
@@ -53,7 +134,7 @@ pub struct DefaultBrowserOpener;
 
 impl BrowserOpener for DefaultBrowserOpener {
     fn open_url(&self, url: &str) -> io::Result<()> {
-        webbrowser::open(url).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        webbrowser::open(url).map_err(io::Error::other)
     }
 }
 
@@ -97,10 +178,7 @@ impl OAuthCodeReceiver for LocalServerCodeReceiver {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Failed to get authorization code",
-        ))
+        Err(io::Error::other("Failed to get authorization code"))
     }
 }
 
@@ -122,6 +200,25 @@ impl Default for OAuthClient {
 }
 
 impl OAuthClient {
+    /// Creates a client that remembers sign-ins across restarts, falling
+    /// back to in-memory storage if the token file can't be opened.
+    pub fn with_file_storage() -> Self {
+        let token_storage: Box<dyn TokenStorage> = match FileTokenStorage::open_default() {
+            Ok(storage) => Box::new(storage),
+            Err(e) => {
+                eprintln!(
+                    "Error opening token storage, sign-ins won't be saved: {}",
+                    e
+                );
+                Box::new(MemoryTokenStorage::default())
+            }
+        };
+        Self {
+            token_storage,
+            ..Self::default()
+        }
+    }
+
     pub fn new(
         token_storage: Box<dyn TokenStorage>,
         browser_opener: Box<dyn BrowserOpener>,
@@ -134,33 +231,101 @@ impl OAuthClient {
         }
     }
 
+    /// Returns an access token for the account, or the saved password for a
+    /// custom SMTP server, which is used in place of one.
     pub fn get_or_refresh_token(
         &mut self,
         email_provider: &email::Provider,
         email: &str,
     ) -> io::Result<String> {
-        let email_hash = format!("{:x}", Sha256::digest(email.as_bytes()));
-        let cache_key = format!("{}_{}", email_provider, email_hash);
+        let cache_key = cache_key(email_provider, email);
+
+        if let email::Provider::Custom(config) = email_provider {
+            return self.saved_token(&cache_key).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No password saved for {} on {}", email, config.host),
+                )
+            });
+        }
 
-        if let Some(refresh_token) = self.token_storage.get_token(&cache_key) {
-            if let Ok((access_token, new_refresh_token)) =
+        if let Some(refresh_token) = self.saved_token(&cache_key)
+            && let Ok((access_token, new_refresh_token)) =
                 self.refresh_oauth_token(email_provider, &refresh_token)
-            {
-                if new_refresh_token != refresh_token {
-                    self.token_storage.set_token(&cache_key, new_refresh_token);
-                }
-                return Ok(access_token);
+        {
+            if new_refresh_token != refresh_token {
+                self.token_storage.set_token(&cache_key, new_refresh_token);
             }
+            return Ok(access_token);
         }
 
+        self.sign_in(email_provider, email)
+    }
+
+    /// Signs in to the account through the browser, saves the refresh token
+    /// and returns an access token.
+    pub fn sign_in(&mut self, email_provider: &email::Provider, email: &str) -> io::Result<String> {
         let (access_token, refresh_token) = self.perform_oauth(email_provider)?;
-        self.token_storage.set_token(&cache_key, refresh_token);
+        self.token_storage
+            .set_token(&cache_key(email_provider, email), refresh_token);
         Ok(access_token)
     }
 
+    /// The refresh token saved for an OAuth account, which
+    /// [`refresh_oauth_token_async`] exchanges for an access token.
+    pub fn saved_refresh_token(
+        &self,
+        email_provider: &email::Provider,
+        email: &str,
+    ) -> Option<String> {
+        if !email_provider.uses_oauth() {
+            return None;
+        }
+        self.saved_token(&cache_key(email_provider, email))
+    }
+
+    /// The token saved under `key`, unless it was removed.
+    fn saved_token(&self, key: &str) -> Option<String> {
+        self.token_storage
+            .get_token(key)
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Saves the refresh token the provider handed out in place of the old one.
+    pub fn update_refresh_token(
+        &mut self,
+        email_provider: &email::Provider,
+        email: &str,
+        refresh_token: String,
+    ) {
+        let cache_key = cache_key(email_provider, email);
+        if self.token_storage.get_token(&cache_key).as_ref() != Some(&refresh_token) {
+            self.token_storage.set_token(&cache_key, refresh_token);
+        }
+    }
+
+    /// Saves the password for an account on a custom SMTP server alongside
+    /// the OAuth refresh tokens.
+    pub fn save_password(
+        &mut self,
+        email_provider: &email::Provider,
+        email: &str,
+        password: String,
+    ) {
+        self.token_storage
+            .set_token(&cache_key(email_provider, email), password);
+    }
+
+    /// Forgets the refresh token or password saved for the account, so that
+    /// the next email signs in again.
+    pub fn forget_token(&mut self, email_provider: &email::Provider, email: &str) {
+        self.token_storage
+            .remove_token(&cache_key(email_provider, email));
+    }
+
     fn perform_oauth(&self, email_provider: &email::Provider) -> io::Result<(String, String)> {
-        let config = get_provider_config(email_provider);
-        let client = create_oauth_client(email_provider);
+        let config = get_provider_config(email_provider)?;
+        let client = create_oauth_client(email_provider)?;
 
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
@@ -178,13 +343,13 @@ impl OAuthClient {
             .exchange_code(AuthorizationCode::new(code))
             .set_pkce_verifier(pkce_verifier)
             .request(&BlockingHttpClient::new())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .map_err(io::Error::other)?;
 
         let access_token = token.access_token().secret().clone();
         let refresh_token = token
             .refresh_token()
             .map(|rt| rt.secret().clone())
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No refresh token provided"))?;
+            .ok_or_else(|| io::Error::other("No refresh token provided"))?;
 
         Ok((access_token, refresh_token))
     }
@@ -194,23 +359,50 @@ impl OAuthClient {
         email_provider: &email::Provider,
         refresh_token: &str,
     ) -> io::Result<(String, String)> {
-        let client = create_oauth_client(email_provider);
+        let client = create_oauth_client(email_provider)?;
 
         let token_result = client
             .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
             .request(&BlockingHttpClient::new())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-        let access_token = token_result.access_token().secret().clone();
-        let refresh_token = token_result
-            .refresh_token()
-            .map(|rt| rt.secret().clone())
-            .unwrap_or_else(|| refresh_token.to_string());
+            .map_err(io::Error::other)?;
 
-        Ok((access_token, refresh_token))
+        Ok(refreshed_tokens(&token_result, refresh_token))
     }
 }
 
+/// Exchanges a refresh token for an access token without blocking, and
+/// returns it with the refresh token to keep.
+pub async fn refresh_oauth_token_async(
+    email_provider: &email::Provider,
+    refresh_token: &str,
+) -> io::Result<(String, String)> {
+    let client = create_oauth_client(email_provider)?;
+
+    let token_result = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request_async(&AsyncHttpClient::new())
+        .await
+        .map_err(io::Error::other)?;
+
+    Ok(refreshed_tokens(&token_result, refresh_token))
+}
+
+/// The access token of a refresh, and the refresh token that replaces
+/// `refresh_token` if the provider handed out a new one.
+fn refreshed_tokens(token_result: &impl TokenResponse, refresh_token: &str) -> (String, String) {
+    let access_token = token_result.access_token().secret().clone();
+    let refresh_token = token_result
+        .refresh_token()
+        .map(|rt| rt.secret().clone())
+        .unwrap_or_else(|| refresh_token.to_string());
+    (access_token, refresh_token)
+}
+
+fn cache_key(email_provider: &email::Provider, email: &str) -> String {
+    let email_hash = format!("{:x}", Sha256::digest(email.as_bytes()));
+    format!("{}_{}", email_provider, email_hash)
+}
+
 pub fn extract_code(request: &str) -> Option<String> {
     request
         .lines()
@@ -233,8 +425,10 @@ struct ProviderConfig {
     scope: &'static str,
 }
 
-fn get_provider_config(email_provider: &email::Provider) -> ProviderConfig {
-    match email_provider {
+/// The OAuth endpoints of `email_provider`, or an `Unsupported` error for
+/// custom SMTP servers, which authenticate with a saved password.
+fn get_provider_config(email_provider: &email::Provider) -> io::Result<ProviderConfig> {
+    Ok(match email_provider {
         email::Provider::Gmail => ProviderConfig {
             client_id: GMAIL_CLIENT_ID,
             encrypted_client_secret: Some(GMAIL_SECRET),
@@ -251,13 +445,20 @@ fn get_provider_config(email_provider: &email::Provider) -> ProviderConfig {
             redirect_uri: "http://localhost:9999",
             scope: "https://outlook.office.com/SMTP.Send offline_access",
         },
-    }
+        email::Provider::Custom(config) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} signs in with a password, not OAuth", config.host),
+            ));
+        }
+    })
 }
 
 fn create_oauth_client(
     email_provider: &email::Provider,
-) -> BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet> {
-    let config = get_provider_config(email_provider);
+) -> io::Result<BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>>
+{
+    let config = get_provider_config(email_provider)?;
     let client_secret = config.encrypted_client_secret.map(decrypt_client_secret);
 
     let mut client = BasicClient::new(ClientId::new(config.client_id.to_string()))
@@ -269,7 +470,7 @@ fn create_oauth_client(
         client = client.set_client_secret(secret);
     }
 
-    client
+    Ok(client)
 }
 
 fn decrypt_client_secret(encrypted_secret: &[u8]) -> String {
@@ -302,6 +503,10 @@ mod tests {
         fn set_token(&mut self, key: &str, token: String) {
             self.tokens.write().unwrap().insert(key.to_string(), token);
         }
+
+        fn remove_token(&mut self, key: &str) {
+            self.tokens.write().unwrap().remove(key);
+        }
     }
 
     struct MockBrowserOpener {
@@ -324,7 +529,7 @@ mod tests {
     impl OAuthCodeReceiver for MockCodeReceiver {
         fn receive_code(&self) -> io::Result<String> {
             if self.should_fail {
-                Err(io::Error::new(io::ErrorKind::Other, "Failed to get code"))
+                Err(io::Error::other("Failed to get code"))
             } else {
                 Ok(self.code.clone())
             }
@@ -381,10 +586,7 @@ mod tests {
         struct FailingBrowserOpener;
         impl BrowserOpener for FailingBrowserOpener {
             fn open_url(&self, _url: &str) -> io::Result<()> {
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Failed to open browser",
-                ))
+                Err(io::Error::other("Failed to open browser"))
             }
         }
 
@@ -469,16 +671,65 @@ mod tests {
         assert!(result2.unwrap_err().to_string().contains("error"));
     }
 
+    #[test]
+    fn test_custom_provider_uses_saved_password() {
+        let mut client = create_test_client(None, None);
+        let provider = email::Provider::Custom(email::SmtpConfig {
+            host: "smtp.fastmail.com".to_string(),
+            ..Default::default()
+        });
+
+        let missing = client.get_or_refresh_token(&provider, "me@fastmail.com");
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        client.save_password(&provider, "me@fastmail.com", "app-password".to_string());
+        let password = client.get_or_refresh_token(&provider, "me@fastmail.com");
+        assert_eq!(password.unwrap(), "app-password");
+
+        let other = client.get_or_refresh_token(&provider, "you@fastmail.com");
+        assert!(other.is_err());
+
+        client.forget_token(&provider, "me@fastmail.com");
+        let forgotten = client.get_or_refresh_token(&provider, "me@fastmail.com");
+        assert_eq!(forgotten.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_forget_token_without_remove_token() {
+        // A storage written before tokens could be removed
+        struct OldStorage(HashMap<String, String>);
+
+        impl TokenStorage for OldStorage {
+            fn get_token(&self, key: &str) -> Option<String> {
+                self.0.get(key).cloned()
+            }
+
+            fn set_token(&mut self, key: &str, token: String) {
+                self.0.insert(key.to_string(), token);
+            }
+        }
+
+        let mut client = OAuthClient {
+            token_storage: Box::new(OldStorage(HashMap::new())),
+            ..OAuthClient::default()
+        };
+        let provider = email::Provider::Custom(email::SmtpConfig::default());
+        client.save_password(&provider, "me@x.org", "secret".to_string());
+        client.forget_token(&provider, "me@x.org");
+        let forgotten = client.get_or_refresh_token(&provider, "me@x.org");
+        assert_eq!(forgotten.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
     #[test]
     fn test_get_provider_config() {
-        let gmail_config = get_provider_config(&email::Provider::Gmail);
+        let gmail_config = get_provider_config(&email::Provider::Gmail).unwrap();
         assert_eq!(gmail_config.client_id, GMAIL_CLIENT_ID);
         assert_eq!(
             gmail_config.auth_url,
             "https://accounts.google.com/o/oauth2/v2/auth"
         );
 
-        let outlook_config = get_provider_config(&email::Provider::Outlook);
+        let outlook_config = get_provider_config(&email::Provider::Outlook).unwrap();
         assert_eq!(outlook_config.client_id, OUTLOOK_CLIENT_ID);
         assert_eq!(
             outlook_config.auth_url,
@@ -488,11 +739,59 @@ mod tests {
 
     #[test]
     fn test_create_oauth_client() {
-        let gmail_client = create_oauth_client(&email::Provider::Gmail);
+        let gmail_client = create_oauth_client(&email::Provider::Gmail).unwrap();
         assert_eq!(gmail_client.client_id().as_str(), GMAIL_CLIENT_ID);
 
-        let outlook_client = create_oauth_client(&email::Provider::Outlook);
+        let outlook_client = create_oauth_client(&email::Provider::Outlook).unwrap();
         assert_eq!(outlook_client.client_id().as_str(), OUTLOOK_CLIENT_ID);
+
+        let custom = email::Provider::Custom(email::SmtpConfig::default());
+        let error = create_oauth_client(&custom).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        let mut client = OAuthClient::default();
+        assert_eq!(
+            client.sign_in(&custom, "me@x.org").unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_file_token_storage_persists_tokens() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let mut storage = FileTokenStorage::open(dir).unwrap();
+        assert_eq!(storage.get_token("test_key"), None);
+        storage.set_token("test_key", "secret_refresh_token".to_string());
+        assert_eq!(
+            storage.get_token("test_key"),
+            Some("secret_refresh_token".to_string())
+        );
+
+        let reopened = FileTokenStorage::open(dir).unwrap();
+        assert_eq!(
+            reopened.get_token("test_key"),
+            Some("secret_refresh_token".to_string())
+        );
+
+        storage.remove_token("test_key");
+        let reopened = FileTokenStorage::open(dir).unwrap();
+        assert_eq!(reopened.get_token("test_key"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_token_storage_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let mut storage = FileTokenStorage::open(dir).unwrap();
+        storage.set_token("test_key", "token".to_string());
+
+        let path = dir.join(FileTokenStorage::TOKENS_FILE);
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
     }
 
     #[test]
@@ -0,0 +1,16 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Directory name used inside the platform's per-user config directory.
+const APP_DIR_NAME: &str = "esim-mailer";
+
+/// Returns the per-user directory where the app keeps its files, creating it
+/// if needed, e.g. `~/.config/esim-mailer` on Linux.
+pub fn app_dir() -> io::Result<PathBuf> {
+    let dir = dirs::config_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No user config directory"))?
+        .join(APP_DIR_NAME);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
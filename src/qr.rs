@@ -0,0 +1,560 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The only activation code format version defined by GSMA SGP.22.
+const LPA_VERSION: &str = "1";
+
+/// Smallest width and height of generated QR codes, in pixels, so that they
+/// scan easily from another screen.
+const RENDER_SIZE: u32 = 300;
+
+/// Largest width or height of a normalized image, in pixels. A QR code scans
+/// fine at this size, while phone photos are often ten times larger.
+const MAX_IMAGE_SIZE: u32 = 1000;
+
+/// An error which can be returned when parsing an eSIM activation code.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LpaError {
+    #[error("expected text starting with 'LPA:'")]
+    MissingPrefix,
+    #[error("unsupported format version '{0}'")]
+    UnsupportedVersion(String),
+    #[error("invalid SM-DP+ address '{0}'")]
+    InvalidSmdpAddress(String),
+    #[error("missing matching ID")]
+    MissingMatchingId,
+    #[error("invalid matching ID '{0}'")]
+    InvalidMatchingId(String),
+    #[error("invalid confirmation code flag '{0}'")]
+    InvalidConfirmationFlag(String),
+    #[error("unexpected data after the activation code")]
+    TooManyFields,
+}
+
+/// An eSIM activation code, as encoded in the QR codes handed out by
+/// operators: `LPA:1$<SM-DP+ address>$<matching ID>[$<OID>[$1]]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ActivationCode {
+    /// Address of the server the eSIM profile is downloaded from.
+    pub smdp_address: String,
+    /// Identifies the profile on the SM-DP+ server.
+    pub matching_id: String,
+    /// Object identifier of the SM-DP+ server, rarely present.
+    pub smdp_oid: Option<String>,
+    /// Whether a confirmation code has to be entered when installing.
+    pub confirmation_code_required: bool,
+}
+
+impl FromStr for ActivationCode {
+    type Err = LpaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let rest = s
+            .get(..4)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("LPA:"))
+            .map(|_| &s[4..])
+            .ok_or(LpaError::MissingPrefix)?;
+
+        let mut fields = rest.split('$');
+        let version = fields.next().unwrap_or_default();
+        if version != LPA_VERSION {
+            return Err(LpaError::UnsupportedVersion(version.to_string()));
+        }
+
+        let smdp_address = fields.next().unwrap_or_default();
+        if !is_valid_hostname(smdp_address) {
+            return Err(LpaError::InvalidSmdpAddress(smdp_address.to_string()));
+        }
+
+        let matching_id = fields.next().unwrap_or_default();
+        if matching_id.is_empty() {
+            return Err(LpaError::MissingMatchingId);
+        }
+        if !matching_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(LpaError::InvalidMatchingId(matching_id.to_string()));
+        }
+
+        let smdp_oid = fields
+            .next()
+            .filter(|oid| !oid.is_empty())
+            .map(str::to_string);
+        let confirmation_code_required = match fields.next() {
+            None | Some("") => false,
+            Some("1") => true,
+            Some(flag) => return Err(LpaError::InvalidConfirmationFlag(flag.to_string())),
+        };
+        if fields.next().is_some() {
+            return Err(LpaError::TooManyFields);
+        }
+
+        Ok(Self {
+            smdp_address: smdp_address.to_string(),
+            matching_id: matching_id.to_string(),
+            smdp_oid,
+            confirmation_code_required,
+        })
+    }
+}
+
+impl Display for ActivationCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "LPA:{}${}${}",
+            LPA_VERSION, self.smdp_address, self.matching_id
+        )?;
+        match (&self.smdp_oid, self.confirmation_code_required) {
+            (oid, true) => write!(f, "${}$1", oid.as_deref().unwrap_or_default()),
+            (Some(oid), false) => write!(f, "${}", oid),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+impl Serialize for ActivationCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ActivationCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A problem with one line of a list of activation codes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineError {
+    pub line: usize,
+    pub error: LpaError,
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.error)
+    }
+}
+
+/// Parses activation codes pasted or read from a text file, one per line.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_activation_codes(text: &str) -> (Vec<ActivationCode>, Vec<LineError>) {
+    let mut codes = Vec::new();
+    let mut errors = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse() {
+            Ok(code) => codes.push(code),
+            Err(error) => errors.push(LineError {
+                line: index + 1,
+                error,
+            }),
+        }
+    }
+    (codes, errors)
+}
+
+/// Whether `host` is a fully qualified domain name.
+fn is_valid_hostname(host: &str) -> bool {
+    let labels: Vec<&str> = host.split('.').collect();
+    host.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// An error which can be returned when reading the activation code from a QR
+/// code image.
+#[derive(Debug, thiserror::Error)]
+pub enum QrError {
+    #[error("could not read file: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a supported image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("no QR code found in the image")]
+    NotFound,
+    #[error("QR code could not be read: {0}")]
+    Unreadable(#[from] rqrr::DeQRError),
+    #[error("QR code is not an eSIM activation code: {0}")]
+    Invalid(#[from] LpaError),
+    #[error("activation code does not fit in a QR code: {0}")]
+    Encode(#[from] qrcode::types::QrError),
+}
+
+/// Where the QR code of an email comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrSource {
+    /// An image file holding the QR code.
+    Image(PathBuf),
+    /// An activation code, rendered into a QR code when sending.
+    ActivationCode(ActivationCode),
+}
+
+impl Display for QrSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Image(path) => write!(f, "{}", path.display()),
+            Self::ActivationCode(code) => write!(f, "{}", code),
+        }
+    }
+}
+
+/// A QR code image ready to be attached, with the activation code it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrImage {
+    pub data: Vec<u8>,
+    pub activation_code: ActivationCode,
+}
+
+impl QrImage {
+    /// The MIME type of the image, sniffed from its contents rather than
+    /// trusted from the file extension.
+    pub fn mime_type(&self) -> &'static str {
+        image::guess_format(&self.data)
+            .map(|format| format.to_mime_type())
+            .unwrap_or("application/octet-stream")
+    }
+
+    /// Re-encodes the image as PNG, scaling it down if either side is larger
+    /// than [`MAX_IMAGE_SIZE`]. The original is kept if the QR code can't be
+    /// read anymore afterwards.
+    pub fn normalize(self) -> Result<Self, QrError> {
+        let mut image = image::load_from_memory(&self.data)?;
+        if image.width().max(image.height()) > MAX_IMAGE_SIZE {
+            image = image.resize(
+                MAX_IMAGE_SIZE,
+                MAX_IMAGE_SIZE,
+                image::imageops::FilterType::Triangle,
+            );
+        }
+        let mut data = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+
+        match decode_qr_data(&data) {
+            Ok(code) if code == self.activation_code => Ok(Self {
+                data,
+                activation_code: self.activation_code,
+            }),
+            _ => Ok(self),
+        }
+    }
+}
+
+impl QrSource {
+    /// Reads and checks the image, or renders the activation code as a PNG.
+    pub fn load(&self) -> Result<QrImage, QrError> {
+        match self {
+            Self::Image(path) => {
+                let data = std::fs::read(path)?;
+                let activation_code = decode_qr_data(&data)?;
+                Ok(QrImage {
+                    data,
+                    activation_code,
+                })
+            }
+            Self::ActivationCode(code) => Ok(QrImage {
+                data: render_qr_code(code)?,
+                activation_code: code.clone(),
+            }),
+        }
+    }
+
+    /// The activation code, decoding the image if needed.
+    pub fn activation_code(&self) -> Result<ActivationCode, QrError> {
+        match self {
+            Self::Image(path) => decode_qr_code(path),
+            Self::ActivationCode(code) => Ok(code.clone()),
+        }
+    }
+}
+
+/// Renders an activation code as a PNG image of its QR code.
+pub fn render_qr_code(code: &ActivationCode) -> Result<Vec<u8>, QrError> {
+    let image = qrcode::QrCode::new(code.to_string().as_bytes())?
+        .render::<image::Luma<u8>>()
+        .min_dimensions(RENDER_SIZE, RENDER_SIZE)
+        .build();
+    let mut data = Vec::new();
+    image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+    Ok(data)
+}
+
+/// Reads the eSIM activation code from a QR code image file.
+pub fn decode_qr_code(path: &Path) -> Result<ActivationCode, QrError> {
+    decode_qr_data(&std::fs::read(path)?)
+}
+
+/// Reads the eSIM activation code from the bytes of a QR code image.
+///
+/// If the image holds several QR codes, the first one that decodes is used.
+pub fn decode_qr_data(data: &[u8]) -> Result<ActivationCode, QrError> {
+    let image = image::load_from_memory(data)?.to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    let mut result = Err(QrError::NotFound);
+    for grid in prepared.detect_grids() {
+        match grid.decode() {
+            Ok((_, content)) => return Ok(content.parse()?),
+            Err(e) => result = Err(e.into()),
+        }
+    }
+    result
+}
+
+/// The outcome of checking one selected QR code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCheck {
+    pub source: QrSource,
+    /// The activation code, or why the QR code can't be sent.
+    pub result: Result<ActivationCode, String>,
+}
+
+impl QrCheck {
+    pub fn new(source: &QrSource) -> Self {
+        Self {
+            source: source.clone(),
+            result: source.activation_code().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl Display for QrCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(code) => write!(f, "{}: {}", self.source, code.smdp_address),
+            Err(e) => write!(f, "{}: {}", self.source, e),
+        }
+    }
+}
+
+/// Writes a QR code image holding `data`, for tests elsewhere in the crate.
+#[cfg(test)]
+pub(crate) fn write_test_qr(path: &Path, data: &str) {
+    qrcode::QrCode::new(data.as_bytes())
+        .unwrap()
+        .render::<image::Luma<u8>>()
+        .build()
+        .save(path)
+        .unwrap();
+}
+
+/// Creates a directory of its own, removed when dropped, holding `files`
+/// for tests elsewhere in the crate. Images hold the QR code of
+/// `LPA:1$smdp.example.com$<file name>`, with `-` in place of `.`, and other
+/// files aren't images.
+#[cfg(test)]
+pub(crate) fn qr_dir(files: &[&str]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for file in files {
+        let path = dir.path().join(file);
+        if image::ImageFormat::from_path(&path).is_ok() {
+            let matching_id = file.replace('.', "-");
+            write_test_qr(&path, &format!("LPA:1$smdp.example.com${}", matching_id));
+        } else {
+            std::fs::write(path, b"not an image").unwrap();
+        }
+    }
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_activation_code() {
+        let code: ActivationCode = "LPA:1$smdp.example.com$ABC-123".parse().unwrap();
+        assert_eq!(code.smdp_address, "smdp.example.com");
+        assert_eq!(code.matching_id, "ABC-123");
+        assert_eq!(code.smdp_oid, None);
+        assert!(!code.confirmation_code_required);
+        assert_eq!(code.to_string(), "LPA:1$smdp.example.com$ABC-123");
+
+        let code: ActivationCode = "lpa:1$rsp.example.net$X1$1.3.6.1.4.1.31746$1"
+            .parse()
+            .unwrap();
+        assert_eq!(code.smdp_oid.as_deref(), Some("1.3.6.1.4.1.31746"));
+        assert!(code.confirmation_code_required);
+        assert_eq!(
+            code.to_string(),
+            "LPA:1$rsp.example.net$X1$1.3.6.1.4.1.31746$1"
+        );
+
+        let code: ActivationCode = "LPA:1$rsp.example.net$X1$$1".parse().unwrap();
+        assert_eq!(code.smdp_oid, None);
+        assert!(code.confirmation_code_required);
+        assert_eq!(code.to_string(), "LPA:1$rsp.example.net$X1$$1");
+    }
+
+    #[test]
+    fn test_parse_invalid_activation_code() {
+        let cases = [
+            ("https://example.com", LpaError::MissingPrefix),
+            (
+                "LPA:2$smdp.example.com$ABC",
+                LpaError::UnsupportedVersion("2".into()),
+            ),
+            (
+                "LPA:1$localhost$ABC",
+                LpaError::InvalidSmdpAddress("localhost".into()),
+            ),
+            (
+                "LPA:1$bad_host.com$ABC",
+                LpaError::InvalidSmdpAddress("bad_host.com".into()),
+            ),
+            ("LPA:1$smdp.example.com", LpaError::MissingMatchingId),
+            (
+                "LPA:1$smdp.example.com$AB C",
+                LpaError::InvalidMatchingId("AB C".into()),
+            ),
+            (
+                "LPA:1$smdp.example.com$ABC$$yes",
+                LpaError::InvalidConfirmationFlag("yes".into()),
+            ),
+            ("LPA:1$smdp.example.com$ABC$$1$x", LpaError::TooManyFields),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<ActivationCode>(), Err(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_activation_codes() {
+        let (codes, errors) = parse_activation_codes(
+            "# codes from the carrier\n\
+             LPA:1$smdp.example.com$ONE\n\
+             \n  LPA:1$smdp.example.com$TWO  \n\
+             smdp.example.com$THREE\n",
+        );
+        let ids: Vec<&str> = codes.iter().map(|c| c.matching_id.as_str()).collect();
+        assert_eq!(ids, vec!["ONE", "TWO"]);
+        assert_eq!(
+            errors,
+            vec![LineError {
+                line: 5,
+                error: LpaError::MissingPrefix
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "Line 5: expected text starting with 'LPA:'"
+        );
+    }
+
+    #[test]
+    fn test_render_qr_code_round_trip() {
+        let code: ActivationCode = "LPA:1$smdp.example.com$K2-1A2B3C-4D5E6F$$1"
+            .parse()
+            .unwrap();
+        let source = QrSource::ActivationCode(code.clone());
+
+        let image = source.load().unwrap();
+        assert_eq!(image.activation_code, code);
+        assert!(image.data.starts_with(b"\x89PNG"));
+        assert_eq!(decode_qr_data(&image.data).unwrap(), code);
+        assert_eq!(
+            QrCheck::new(&source).to_string(),
+            "LPA:1$smdp.example.com$K2-1A2B3C-4D5E6F$$1: smdp.example.com"
+        );
+
+        let json = serde_json::to_string(&source).unwrap();
+        assert_eq!(
+            json,
+            r#"{"activation_code":"LPA:1$smdp.example.com$K2-1A2B3C-4D5E6F$$1"}"#
+        );
+        assert_eq!(serde_json::from_str::<QrSource>(&json).unwrap(), source);
+    }
+
+    #[test]
+    fn test_qr_image_mime_type_and_normalize() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let code: ActivationCode = "LPA:1$smdp.example.com$PHOTO".parse().unwrap();
+
+        let large = qrcode::QrCode::new(code.to_string().as_bytes())
+            .unwrap()
+            .render::<image::Luma<u8>>()
+            .min_dimensions(1200, 1200)
+            .build();
+        let photo = dir.join("photo.jpg");
+        large.save(&photo).unwrap();
+        let gif = dir.join("code.gif");
+        let small = qrcode::QrCode::new(code.to_string().as_bytes())
+            .unwrap()
+            .render::<image::Luma<u8>>()
+            .build();
+        image::DynamicImage::ImageLuma8(small)
+            .to_rgba8()
+            .save(&gif)
+            .unwrap();
+
+        let image = QrSource::Image(gif).load().unwrap();
+        assert_eq!(image.mime_type(), "image/gif");
+
+        let image = QrSource::Image(photo).load().unwrap();
+        assert_eq!(image.mime_type(), "image/jpeg");
+        let normalized = image.clone().normalize().unwrap();
+        assert_eq!(normalized.mime_type(), "image/png");
+        assert_eq!(normalized.activation_code, code);
+        assert!(normalized.data.len() < image.data.len());
+        let decoded = image::load_from_memory(&normalized.data).unwrap();
+        assert_eq!(decoded.width().max(decoded.height()), MAX_IMAGE_SIZE);
+
+        let rendered = QrSource::ActivationCode(code).load().unwrap();
+        assert_eq!(rendered.mime_type(), "image/png");
+    }
+
+    #[test]
+    fn test_decode_qr_code() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let valid = dir.join("valid.png");
+        write_test_qr(&valid, "LPA:1$smdp.example.com$ABC-123");
+        let code = decode_qr_code(&valid).unwrap();
+        assert_eq!(code.smdp_address, "smdp.example.com");
+        assert_eq!(code.matching_id, "ABC-123");
+
+        let website = dir.join("website.png");
+        write_test_qr(&website, "https://example.com");
+        assert!(matches!(
+            decode_qr_code(&website),
+            Err(QrError::Invalid(LpaError::MissingPrefix))
+        ));
+
+        let blank = dir.join("blank.png");
+        image::GrayImage::from_pixel(64, 64, image::Luma([255]))
+            .save(&blank)
+            .unwrap();
+        assert!(matches!(decode_qr_code(&blank), Err(QrError::NotFound)));
+
+        let not_image = dir.join("not_image.png");
+        fs::write(&not_image, b"fake image data").unwrap();
+        assert!(matches!(decode_qr_code(&not_image), Err(QrError::Image(_))));
+
+        let check = QrCheck::new(&QrSource::Image(dir.join("missing.png")));
+        assert!(check.result.unwrap_err().contains("could not read file"));
+        assert_eq!(
+            QrCheck::new(&QrSource::Image(valid.clone())).to_string(),
+            format!("{}: smdp.example.com", valid.display())
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Most emails sent at the same time, whatever the limits say.
+pub const MAX_CONNECTIONS: u32 = 8;
+
+/// How many emails an account may send, as set by its provider. Zero means
+/// no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    /// Counted over the last 24 hours, as Gmail and Outlook do.
+    pub per_day: u32,
+    /// Emails sent at the same time, each over a connection of its own, up
+    /// to [`MAX_CONNECTIONS`]. Only the GUI sends more than one at a time.
+    #[serde(default = "one")]
+    pub connections: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_minute: 0,
+            per_day: 0,
+            connections: 1,
+        }
+    }
+}
+
+impl RateLimit {
+    /// Warns if sending `batch` more emails from `account`, which has sent
+    /// `sent` in the last 24 hours, would go over the daily limit.
+    pub fn check_quota(&self, account: &str, sent: usize, batch: usize) -> Option<QuotaWarning> {
+        (self.per_day > 0 && sent + batch > self.per_day as usize).then(|| QuotaWarning {
+            account: account.to_string(),
+            per_day: self.per_day,
+            sent,
+            batch,
+        })
+    }
+}
+
+/// A batch that would take an account over its daily limit, after which
+/// the provider rejects emails until the next day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaWarning {
+    pub account: String,
+    pub per_day: u32,
+    /// Emails sent in the last 24 hours.
+    pub sent: usize,
+    pub batch: usize,
+}
+
+impl Display for QuotaWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sending {} emails would take {} over its limit of {} a day, \
+             {} were sent in the last 24 hours",
+            self.batch, self.account, self.per_day, self.sent
+        )
+    }
+}
+
+/// Spaces emails out evenly to stay under a per-minute limit.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(limit: RateLimit) -> Self {
+        let interval = match limit.per_minute {
+            0 => Duration::ZERO,
+            per_minute => Duration::from_secs(60) / per_minute,
+        };
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// How long to wait at `now` before the next email may go out.
+    pub fn delay(&self, now: Instant) -> Duration {
+        self.last.map_or(Duration::ZERO, |last| {
+            (last + self.interval).saturating_duration_since(now)
+        })
+    }
+
+    /// Books the next slot for an email, for senders that share the
+    /// throttle, and returns how long to wait at `now` until it comes.
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        let delay = self.delay(now);
+        self.last = Some(now + delay);
+        delay
+    }
+
+    /// Sleeps until the next email may go out, and counts it as sent.
+    pub fn wait(&mut self) {
+        thread::sleep(self.reserve(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_quota() {
+        let limit = RateLimit {
+            per_minute: 20,
+            per_day: 500,
+            ..RateLimit::default()
+        };
+        assert_eq!(limit.check_quota("me@gmail.com", 480, 20), None);
+
+        let warning = limit.check_quota("me@gmail.com", 480, 21).unwrap();
+        assert_eq!(
+            warning.to_string(),
+            "Sending 21 emails would take me@gmail.com over its limit of 500 a day, \
+             480 were sent in the last 24 hours"
+        );
+
+        assert_eq!(
+            RateLimit::default().check_quota("me@x.org", 10_000, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::new(RateLimit {
+            per_minute: 30,
+            ..RateLimit::default()
+        });
+        let now = Instant::now();
+        assert_eq!(throttle.delay(now), Duration::ZERO);
+
+        throttle.last = Some(now);
+        assert_eq!(throttle.delay(now), Duration::from_secs(2));
+        assert_eq!(
+            throttle.delay(now + Duration::from_millis(500)),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(throttle.delay(now + Duration::from_secs(3)), Duration::ZERO);
+
+        // Senders sharing the throttle get one slot each
+        let now = now + Duration::from_secs(10);
+        assert_eq!(throttle.reserve(now), Duration::ZERO);
+        assert_eq!(throttle.reserve(now), Duration::from_secs(2));
+        assert_eq!(throttle.reserve(now), Duration::from_secs(4));
+
+        let mut unlimited = Throttle::new(RateLimit::default());
+        unlimited.wait();
+        assert_eq!(unlimited.delay(Instant::now()), Duration::ZERO);
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::error::MailerError;
+
+/// How often, and how patiently, emails that failed for a reason that may
+/// go away by itself are sent again. See [`MailerError::is_transient`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each further one.
+    pub base_delay: Duration,
+    /// Longest wait between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Every attempt is made straight after the one before, for tests.
+    #[cfg(test)]
+    pub fn immediate(retries: u32) -> Self {
+        Self {
+            retries,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    /// The wait before retry number `retry`, counting from 1: the backoff
+    /// doubles with each retry up to `max_delay`, and a random part of up to
+    /// half of it keeps clients that failed together from retrying together.
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        backoff / 2 + backoff.mul_f64(rng.random::<f64>() / 2.0)
+    }
+
+    /// Whether to try again after `error` ended attempt number `attempts`.
+    pub fn should_retry(&self, error: &MailerError, attempts: u32) -> bool {
+        error.is_transient() && attempts <= self.retries
+    }
+
+    /// Makes `attempt` until it succeeds, fails for good or runs out of
+    /// retries, and returns its last result with the number of attempts made.
+    /// `wait` is given the number of the failed attempt, its error and the
+    /// delay before the next one, and is expected to sleep for that long.
+    pub fn run<T>(
+        &self,
+        mut attempt: impl FnMut() -> Result<T, MailerError>,
+        mut wait: impl FnMut(u32, &MailerError, Duration),
+    ) -> (Result<T, MailerError>, u32) {
+        let mut rng = rand::rng();
+        let mut attempts = 1;
+        loop {
+            match attempt() {
+                Err(e) if self.should_retry(&e, attempts) => {
+                    wait(attempts, &e, self.delay(attempts, &mut rng));
+                    attempts += 1;
+                }
+                result => return (result, attempts),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::{Provider, SmtpConfig};
+    use crate::error::smtp_error;
+    use std::io;
+
+    fn transient() -> MailerError {
+        let error = smtp_error("421 4.7.0 Try again later");
+        MailerError::from_smtp(error, &Provider::Custom(SmtpConfig::default()), "me@x.org")
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy::default();
+        let mut rng = rand::rng();
+        for _ in 0..20 {
+            let first = policy.delay(1, &mut rng);
+            assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2));
+            let third = policy.delay(3, &mut rng);
+            assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(8));
+            let late = policy.delay(30, &mut rng);
+            assert!(late >= Duration::from_secs(30) && late <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_errors() {
+        let policy = RetryPolicy::default();
+        let mut waits = Vec::new();
+        let mut failures = 2;
+        let (result, attempts) = policy.run(
+            || {
+                if failures > 0 {
+                    failures -= 1;
+                    Err(transient())
+                } else {
+                    Ok("<id@mock>")
+                }
+            },
+            |attempt, e, delay| {
+                assert!(e.is_transient());
+                waits.push((attempt, delay));
+            },
+        );
+        assert_eq!(result.unwrap(), "<id@mock>");
+        assert_eq!(attempts, 3);
+        assert_eq!(waits.iter().map(|(n, _)| *n).collect::<Vec<_>>(), [1, 2]);
+        assert!(waits[1].1 >= Duration::from_secs(2));
+
+        // Gives up after the last retry
+        let (result, attempts) = RetryPolicy::immediate(2).run(
+            || Err::<(), _>(transient()),
+            |_, _, delay| assert_eq!(delay, Duration::ZERO),
+        );
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_retry_skips_permanent_errors() {
+        let (result, attempts) = RetryPolicy::default().run(
+            || Err::<(), _>(io::Error::other("disk full").into()),
+            |_, _, _| panic!("permanent errors aren't retried"),
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
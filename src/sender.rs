@@ -0,0 +1,646 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Notify, Semaphore, mpsc};
+
+use crate::Args;
+use crate::batch::BatchItem;
+use crate::email::{AsyncMailer, Mailer, Provider};
+use crate::error::MailerError;
+use crate::oauth::{self, OAuthClient};
+use crate::qr::QrSource;
+use crate::rate_limit::{MAX_CONNECTIONS, RateLimit, Throttle};
+use crate::retry::RetryPolicy;
+
+/// A future that can be sent to another thread, as trait objects return.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Trait for email operations to allow mocking in tests
+pub trait EmailOperations: Send + Sync {
+    fn send_email(
+        &self,
+        args: &Args,
+        token: String,
+        qr_codes: &[QrSource],
+        count: usize,
+    ) -> Result<String, MailerError>;
+    fn get_token(&self, provider: &Provider, email: &str) -> Result<String, MailerError>;
+    fn save_password(
+        &self,
+        provider: &Provider,
+        email: &str,
+        password: String,
+    ) -> Result<(), MailerError>;
+    /// Forgets the sign-in or password saved for `email`, after the mail
+    /// server rejected it.
+    fn forget_token(&self, provider: &Provider, email: &str);
+
+    /// Sends an email like [`EmailOperations::send_email`] on the tokio
+    /// runtime, where [`send_batch`] sends several at the same time. Any
+    /// blocking work belongs on the blocking pool.
+    fn send_email_async<'a>(
+        &'a self,
+        args: &'a Args,
+        token: String,
+        qr_codes: &'a [QrSource],
+        count: usize,
+    ) -> BoxFuture<'a, Result<String, MailerError>>;
+
+    /// Gets a token like [`EmailOperations::get_token`] on the tokio runtime.
+    fn get_token_async<'a>(
+        &'a self,
+        provider: &'a Provider,
+        email: &'a str,
+    ) -> BoxFuture<'a, Result<String, MailerError>>;
+}
+
+// Default implementation that uses real email functionality
+pub struct DefaultEmailOperations {
+    oauth_client: Arc<Mutex<OAuthClient>>,
+    /// The connection of the batch being sent, kept for its next email.
+    mailer: Mutex<Option<Mailer>>,
+    /// The connections of the batch being sent on the tokio runtime.
+    async_mailer: Mutex<Option<Arc<AsyncMailer>>>,
+}
+
+impl DefaultEmailOperations {
+    pub fn new(oauth_client: Arc<Mutex<OAuthClient>>) -> Self {
+        Self {
+            oauth_client,
+            mailer: Mutex::new(None),
+            async_mailer: Mutex::new(None),
+        }
+    }
+}
+
+impl EmailOperations for DefaultEmailOperations {
+    fn send_email(
+        &self,
+        args: &Args,
+        token: String,
+        qr_codes: &[QrSource],
+        count: usize,
+    ) -> Result<String, MailerError> {
+        let provider = args.email_provider()?;
+        let mut mailer = self.mailer.lock().unwrap();
+        let mailer = match mailer.take() {
+            Some(open) if open.signs_in_as(&provider, &args.email_from, &token) => {
+                mailer.insert(open)
+            }
+            _ => mailer.insert(Mailer::new(provider, &args.email_from, token)?),
+        };
+        mailer.send(args, qr_codes, count)
+    }
+
+    fn get_token(&self, provider: &Provider, email: &str) -> Result<String, MailerError> {
+        let mut client = self.oauth_client.lock().unwrap();
+        client
+            .get_or_refresh_token(provider, email)
+            .map_err(MailerError::SignIn)
+    }
+
+    fn save_password(
+        &self,
+        provider: &Provider,
+        email: &str,
+        password: String,
+    ) -> Result<(), MailerError> {
+        let mut client = self.oauth_client.lock().unwrap();
+        client.save_password(provider, email, password);
+        Ok(())
+    }
+
+    fn forget_token(&self, provider: &Provider, email: &str) {
+        *self.mailer.lock().unwrap() = None;
+        *self.async_mailer.lock().unwrap() = None;
+        let mut client = self.oauth_client.lock().unwrap();
+        client.forget_token(provider, email);
+    }
+
+    fn send_email_async<'a>(
+        &'a self,
+        args: &'a Args,
+        token: String,
+        qr_codes: &'a [QrSource],
+        count: usize,
+    ) -> BoxFuture<'a, Result<String, MailerError>> {
+        Box::pin(async move {
+            let provider = args.email_provider()?;
+            let mailer = {
+                let mut mailer = self.async_mailer.lock().unwrap();
+                match mailer.as_ref() {
+                    Some(open) if open.signs_in_as(&provider, &args.email_from, &token) => {
+                        Arc::clone(open)
+                    }
+                    _ => Arc::clone(mailer.insert(Arc::new(AsyncMailer::new(
+                        provider,
+                        &args.email_from,
+                        token,
+                    )?))),
+                }
+            };
+            mailer.send(args, qr_codes, count).await
+        })
+    }
+
+    fn get_token_async<'a>(
+        &'a self,
+        provider: &'a Provider,
+        email: &'a str,
+    ) -> BoxFuture<'a, Result<String, MailerError>> {
+        Box::pin(async move {
+            let refresh_token = self
+                .oauth_client
+                .lock()
+                .unwrap()
+                .saved_refresh_token(provider, email);
+            if let Some(refresh_token) = refresh_token
+                && let Ok((access_token, refresh_token)) =
+                    oauth::refresh_oauth_token_async(provider, &refresh_token).await
+            {
+                let mut client = self.oauth_client.lock().unwrap();
+                client.update_refresh_token(provider, email, refresh_token);
+                return Ok(access_token);
+            }
+
+            // Signing in waits for the browser, on a thread of its own
+            let client = Arc::clone(&self.oauth_client);
+            let (provider, email) = (provider.clone(), email.to_string());
+            tokio::task::spawn_blocking(move || {
+                let mut client = client.lock().unwrap();
+                match provider {
+                    Provider::Custom(_) => client.get_or_refresh_token(&provider, &email),
+                    _ => client.sign_in(&provider, &email),
+                }
+            })
+            .await
+            .map_err(io::Error::other)
+            .flatten()
+            .map_err(MailerError::SignIn)
+        })
+    }
+}
+
+/// Asks a batch to stop sending. Emails already on their way to the server
+/// are let through, so that none is sent without being recorded.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once [`CancelToken::cancel`] has been called.
+    pub async fn cancelled(&self) {
+        loop {
+            // Registered before checking, so that a cancel in between isn't missed
+            let notified = self.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Sleeps for `delay`, returning `false` if cancelled in the meantime.
+    async fn sleep(&self, delay: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => !self.is_cancelled(),
+            _ = self.cancelled() => false,
+        }
+    }
+}
+
+/// What happened to an email of a batch sent with [`send_batch`].
+#[derive(Debug)]
+pub enum SendEvent {
+    /// The email at `index` of the batch failed for a transient reason, and
+    /// is sent again after `delay`.
+    Retrying {
+        index: usize,
+        attempt: u32,
+        error: MailerError,
+        delay: Duration,
+    },
+    /// The email at `index` of the batch was sent, or failed for good.
+    Finished {
+        index: usize,
+        result: Result<String, MailerError>,
+        attempts: u32,
+    },
+}
+
+/// Sends the `items` of a batch, each given with its position in the batch,
+/// up to `limit.connections` at a time and no faster than `limit.per_minute`,
+/// retrying transient failures as `retry` says. `on_event` is told about
+/// each email as it goes.
+///
+/// Sending stops when `cancel` is cancelled, or when an error means the rest
+/// of the batch would fail too, which is returned. Emails that hadn't
+/// finished by then get no [`SendEvent::Finished`] and stay to be sent later.
+pub async fn send_batch(
+    email_ops: Arc<dyn EmailOperations>,
+    items: Vec<(usize, BatchItem)>,
+    token: String,
+    limit: RateLimit,
+    retry: RetryPolicy,
+    cancel: CancelToken,
+    mut on_event: impl FnMut(SendEvent),
+) -> Option<MailerError> {
+    let connections = limit.connections.clamp(1, MAX_CONNECTIONS) as usize;
+    let semaphore = Arc::new(Semaphore::new(connections));
+    let throttle = Arc::new(Mutex::new(Throttle::new(limit)));
+    let stopped = Arc::new(Mutex::new(None));
+    let (events, mut received) = mpsc::unbounded_channel();
+
+    // Every email waits for a connection in a task of its own
+    for (index, item) in items {
+        let email_ops = Arc::clone(&email_ops);
+        let token = token.clone();
+        let semaphore = Arc::clone(&semaphore);
+        let throttle = Arc::clone(&throttle);
+        let stopped = Arc::clone(&stopped);
+        let cancel = cancel.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            let _permit = tokio::select! {
+                permit = semaphore.acquire_owned() => permit.unwrap(),
+                _ = cancel.cancelled() => return,
+            };
+            let mut attempts = 1;
+            loop {
+                let delay = throttle.lock().unwrap().reserve(Instant::now());
+                if !cancel.sleep(delay).await {
+                    return;
+                }
+                let result = email_ops
+                    .send_email_async(&item.args, token.clone(), &item.qr_codes, index + 1)
+                    .await;
+                match result {
+                    Err(e) if e.stops_batch() => {
+                        stopped.lock().unwrap().get_or_insert(e);
+                        cancel.cancel();
+                        return;
+                    }
+                    Err(e) if retry.should_retry(&e, attempts) => {
+                        let delay = retry.delay(attempts, &mut rand::rng());
+                        let _ = events.send(SendEvent::Retrying {
+                            index,
+                            attempt: attempts,
+                            error: e,
+                            delay,
+                        });
+                        if !cancel.sleep(delay).await {
+                            return;
+                        }
+                        attempts += 1;
+                    }
+                    result => {
+                        let _ = events.send(SendEvent::Finished {
+                            index,
+                            result,
+                            attempts,
+                        });
+                        return;
+                    }
+                }
+            }
+        });
+    }
+    drop(events);
+
+    // Ends once every task has finished and dropped its sender
+    while let Some(event) = received.recv().await {
+        on_event(event);
+    }
+    stopped.lock().unwrap().take()
+}
+
+/// A stand-in for the mail server, shared by the tests of the crate.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// The error returned for the email numbered `count`, `times` times.
+    pub struct Failing {
+        pub count: usize,
+        pub error: fn() -> MailerError,
+        pub times: usize,
+    }
+
+    pub fn mock_error() -> MailerError {
+        io::Error::other("Mock error").into()
+    }
+
+    /// Records the QR codes of the emails it is given instead of sending
+    /// them, and keeps track of how many are sent at once.
+    #[derive(Default)]
+    pub struct MockEmailOperations {
+        pub sent: Mutex<Vec<QrSource>>,
+        pub failing: Mutex<Option<Failing>>,
+        /// Whether getting a token fails, so that nothing can be sent.
+        pub token_fails: bool,
+        /// How long each email takes on the tokio runtime.
+        pub delay: Duration,
+        sending: AtomicUsize,
+        pub most_at_once: AtomicUsize,
+        pub saved_passwords: Mutex<Vec<String>>,
+        pub forgotten: Mutex<Vec<String>>,
+    }
+
+    impl MockEmailOperations {
+        pub fn new(token_fails: bool) -> Self {
+            Self {
+                token_fails,
+                ..Self::default()
+            }
+        }
+
+        /// Takes `delay` to send each email on the tokio runtime.
+        pub fn slow(delay: Duration) -> Self {
+            Self {
+                delay,
+                ..Self::default()
+            }
+        }
+
+        /// Makes the email numbered `count` fail with `error` the next
+        /// `times` it is sent.
+        pub fn fail(&self, count: usize, error: fn() -> MailerError, times: usize) {
+            *self.failing.lock().unwrap() = Some(Failing {
+                count,
+                error,
+                times,
+            });
+        }
+
+        /// How many more times the failing email fails.
+        pub fn failures_left(&self) -> usize {
+            self.failing.lock().unwrap().as_ref().map_or(0, |f| f.times)
+        }
+
+        fn fails(&self, count: usize) -> Option<MailerError> {
+            let mut failing = self.failing.lock().unwrap();
+            let failing = failing
+                .as_mut()
+                .filter(|f| f.count == count && f.times > 0)?;
+            failing.times -= 1;
+            Some((failing.error)())
+        }
+
+        fn record(&self, qr_codes: &[QrSource], count: usize) -> String {
+            self.sent.lock().unwrap().extend_from_slice(qr_codes);
+            format!("<{}@mock>", count)
+        }
+    }
+
+    impl EmailOperations for MockEmailOperations {
+        fn send_email(
+            &self,
+            _args: &Args,
+            _token: String,
+            qr_codes: &[QrSource],
+            count: usize,
+        ) -> Result<String, MailerError> {
+            match self.fails(count) {
+                Some(error) => Err(error),
+                None => Ok(self.record(qr_codes, count)),
+            }
+        }
+
+        fn get_token(&self, _provider: &Provider, _email: &str) -> Result<String, MailerError> {
+            if self.token_fails {
+                return Err(MailerError::SignIn(io::Error::other("Mock token error")));
+            }
+            Ok("mock_token".to_string())
+        }
+
+        fn save_password(
+            &self,
+            _provider: &Provider,
+            _email: &str,
+            password: String,
+        ) -> Result<(), MailerError> {
+            self.saved_passwords.lock().unwrap().push(password);
+            Ok(())
+        }
+
+        fn forget_token(&self, _provider: &Provider, email: &str) {
+            self.forgotten.lock().unwrap().push(email.to_string());
+        }
+
+        fn send_email_async<'a>(
+            &'a self,
+            _args: &'a Args,
+            _token: String,
+            qr_codes: &'a [QrSource],
+            count: usize,
+        ) -> BoxFuture<'a, Result<String, MailerError>> {
+            Box::pin(async move {
+                if let Some(error) = self.fails(count) {
+                    return Err(error);
+                }
+                let sending = self.sending.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most_at_once.fetch_max(sending, Ordering::SeqCst);
+                tokio::time::sleep(self.delay).await;
+                self.sending.fetch_sub(1, Ordering::SeqCst);
+                Ok(self.record(qr_codes, count))
+            })
+        }
+
+        fn get_token_async<'a>(
+            &'a self,
+            provider: &'a Provider,
+            email: &'a str,
+        ) -> BoxFuture<'a, Result<String, MailerError>> {
+            Box::pin(async move { self.get_token(provider, email) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockEmailOperations;
+    use super::*;
+    use crate::email::SmtpConfig;
+    use crate::error::smtp_error;
+
+    fn slow() -> Arc<MockEmailOperations> {
+        Arc::new(MockEmailOperations::slow(Duration::from_millis(20)))
+    }
+
+    fn items(count: usize) -> Vec<(usize, BatchItem)> {
+        (0..count)
+            .map(|index| {
+                let code = format!("LPA:1$smdp.example.com${}", index);
+                let item = BatchItem {
+                    args: Args::default(),
+                    qr_codes: vec![QrSource::ActivationCode(code.parse().unwrap())],
+                };
+                (index, item)
+            })
+            .collect()
+    }
+
+    fn connections(connections: u32) -> RateLimit {
+        RateLimit {
+            connections,
+            ..RateLimit::default()
+        }
+    }
+
+    /// Sends `count` emails, returning the error that stopped the batch and
+    /// the outcome of each email in the order they finished.
+    async fn send(
+        email_ops: Arc<MockEmailOperations>,
+        count: usize,
+        limit: RateLimit,
+        cancel: CancelToken,
+    ) -> (Option<MailerError>, Vec<(usize, bool, u32)>) {
+        let mut finished = Vec::new();
+        let stopped = send_batch(
+            email_ops,
+            items(count),
+            "mock_token".to_string(),
+            limit,
+            RetryPolicy::immediate(3),
+            cancel,
+            |event| {
+                if let SendEvent::Finished {
+                    index,
+                    result,
+                    attempts,
+                } = event
+                {
+                    finished.push((index, result.is_ok(), attempts));
+                }
+            },
+        )
+        .await;
+        (stopped, finished)
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_concurrently() {
+        let email_ops = slow();
+        let (stopped, finished) = send(
+            Arc::clone(&email_ops),
+            6,
+            connections(2),
+            CancelToken::default(),
+        )
+        .await;
+
+        assert!(stopped.is_none());
+        assert_eq!(finished.len(), 6);
+        assert!(
+            finished
+                .iter()
+                .all(|(_, ok, attempts)| *ok && *attempts == 1)
+        );
+        assert_eq!(email_ops.most_at_once.load(Ordering::SeqCst), 2);
+
+        let email_ops = slow();
+        send(
+            Arc::clone(&email_ops),
+            3,
+            connections(1),
+            CancelToken::default(),
+        )
+        .await;
+        assert_eq!(email_ops.most_at_once.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_throttles() {
+        let email_ops = slow();
+        let limit = RateLimit {
+            per_minute: 600,
+            ..connections(4)
+        };
+
+        let started = Instant::now();
+        let (_, finished) = send(email_ops, 4, limit, CancelToken::default()).await;
+
+        assert_eq!(finished.len(), 4);
+        // One email every 100ms however many connections are free
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_cancel() {
+        let email_ops = slow();
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let (stopped, finished) = send(email_ops, 3, connections(1), cancel).await;
+        assert!(stopped.is_none());
+        assert!(finished.is_empty());
+
+        // Cancelled as soon as the first email is sent, when the next one
+        // may already be on its way
+        let email_ops = slow();
+        let cancel = CancelToken::default();
+        let mut finished = Vec::new();
+        let stopped = send_batch(
+            email_ops,
+            items(5),
+            "mock_token".to_string(),
+            connections(1),
+            RetryPolicy::default(),
+            cancel.clone(),
+            |event| {
+                if let SendEvent::Finished { index, .. } = event {
+                    finished.push(index);
+                    cancel.cancel();
+                }
+            },
+        )
+        .await;
+        assert!(stopped.is_none());
+        assert!(finished.len() <= 2);
+        assert_eq!(finished[0], 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_retries_and_stops() {
+        let email_ops = slow();
+        email_ops.fail(
+            2,
+            || {
+                let error = smtp_error("421 4.7.0 Try again later");
+                MailerError::from_smtp(error, &Provider::Custom(SmtpConfig::default()), "me")
+            },
+            2,
+        );
+        let (stopped, mut finished) =
+            send(email_ops, 3, connections(3), CancelToken::default()).await;
+        finished.sort();
+        assert!(stopped.is_none());
+        assert_eq!(finished, vec![(0, true, 1), (1, true, 3), (2, true, 1)]);
+
+        let email_ops = slow();
+        email_ops.fail(
+            2,
+            || MailerError::SignIn(io::Error::other("Mock token error")),
+            1,
+        );
+        let (stopped, finished) = send(email_ops, 3, connections(1), CancelToken::default()).await;
+        assert!(matches!(stopped, Some(MailerError::SignIn(_))));
+        // The email that failed and the rest are left to send later
+        assert_eq!(finished, vec![(0, true, 1)]);
+    }
+}
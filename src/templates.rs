@@ -0,0 +1,345 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::email::EmailTemplate;
+use crate::language::Language;
+
+/// Name under which the template compiled into the app is offered.
+pub const BUILT_IN: &str = "Built-in";
+
+/// Named email templates kept in the user's config directory, so wording can
+/// be changed without rebuilding the app.
+///
+/// Each template is a directory holding `subject.txt`, `body.html` and
+/// optionally `body.txt`, which can also be edited with any text editor.
+/// Versions in other languages sit next to them with the language code
+/// before the extension, such as `body.ar.html`. The extra variables a
+/// template declares are listed one per line in `variables.txt`, shared by
+/// all languages.
+#[derive(Debug, Clone)]
+pub struct TemplateStore {
+    dir: PathBuf,
+}
+
+impl TemplateStore {
+    const DIR_NAME: &str = "templates";
+    const SUBJECT_FILE: &str = "subject.txt";
+    const BODY_FILE: &str = "body.html";
+    const TEXT_FILE: &str = "body.txt";
+    const VARIABLES_FILE: &str = "variables.txt";
+
+    /// Opens the templates directory in the user's config directory.
+    pub fn open_default() -> io::Result<Self> {
+        Self::open(&crate::paths::app_dir()?.join(Self::DIR_NAME))
+    }
+
+    /// Opens the templates kept in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Names of the saved templates, sorted.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !self.languages_in(&path).is_empty()
+                && let Some(name) = path.file_name().and_then(|name| name.to_str())
+            {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Languages a template has a version in.
+    pub fn languages(&self, name: &str) -> io::Result<Vec<Language>> {
+        if name == BUILT_IN {
+            return Ok(Language::ALL.to_vec());
+        }
+        Ok(self.languages_in(&self.template_dir(name)?))
+    }
+
+    fn languages_in(&self, dir: &Path) -> Vec<Language> {
+        Language::ALL
+            .iter()
+            .copied()
+            .filter(|&language| dir.join(localized(Self::BODY_FILE, language)).is_file())
+            .collect()
+    }
+
+    /// Loads the version of a template in `language`, or the default
+    /// language's version if it has none, or else whichever it has. A missing
+    /// subject falls back to the built-in one and a missing plain-text body to
+    /// converting the HTML body.
+    pub fn load(&self, name: &str, language: Language) -> io::Result<EmailTemplate> {
+        if name == BUILT_IN {
+            return Ok(EmailTemplate::built_in(language));
+        }
+        let dir = self.template_dir(name)?;
+        let languages = self.languages_in(&dir);
+        let version = if languages.contains(&language) {
+            language
+        } else {
+            languages.first().copied().unwrap_or_default()
+        };
+        let body_template = fs::read_to_string(dir.join(localized(Self::BODY_FILE, version)))
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("Template '{}' not found: {}", name, e))
+            })?;
+        let subject_template =
+            match fs::read_to_string(dir.join(localized(Self::SUBJECT_FILE, version))) {
+                Ok(subject) => subject.trim().to_string(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    EmailTemplate::built_in(version).subject_template
+                }
+                Err(e) => return Err(e),
+            };
+        let text_template = match fs::read_to_string(dir.join(localized(Self::TEXT_FILE, version)))
+        {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let variables = match fs::read_to_string(dir.join(Self::VARIABLES_FILE)) {
+            Ok(variables) => variables
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(EmailTemplate {
+            subject_template,
+            body_template,
+            text_template,
+            variables,
+        })
+    }
+
+    /// Saves the version of a template in `language`, replacing any existing
+    /// one. Versions in other languages are kept, while the declared
+    /// variables apply to all of them.
+    pub fn save(&self, name: &str, language: Language, template: &EmailTemplate) -> io::Result<()> {
+        let dir = self.template_dir(name)?;
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join(localized(Self::SUBJECT_FILE, language)),
+            &template.subject_template,
+        )?;
+        fs::write(
+            dir.join(localized(Self::BODY_FILE, language)),
+            &template.body_template,
+        )?;
+        let text_file = dir.join(localized(Self::TEXT_FILE, language));
+        match &template.text_template {
+            Some(text) => fs::write(text_file, text)?,
+            None => remove_if_exists(&text_file)?,
+        }
+        let variables_file = dir.join(Self::VARIABLES_FILE);
+        if template.variables.is_empty() {
+            remove_if_exists(&variables_file)
+        } else {
+            fs::write(variables_file, template.variables.join("\n") + "\n")
+        }
+    }
+
+    /// Deletes a template in all languages.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_dir_all(self.template_dir(name)?)
+    }
+
+    fn template_dir(&self, name: &str) -> io::Result<PathBuf> {
+        let valid = !name.trim().is_empty()
+            && name != BUILT_IN
+            && name != "."
+            && name != ".."
+            && !name.contains(['/', '\\']);
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' can't be used as a template name", name),
+            ));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The name of the file holding a template part in `language`: the plain
+/// name for the default language, which other languages fall back to when a
+/// template has no version of its own in them, and e.g. `body.ar.html` for
+/// the others.
+fn localized(file: &str, language: Language) -> String {
+    match file.rsplit_once('.') {
+        Some((stem, extension)) if language != Language::default() => {
+            format!("{}.{}.{}", stem, language.code(), extension)
+        }
+        _ => file.to_string(),
+    }
+}
+
+/// Loads the template with the given name in `language` from the user's
+/// config directory, or the built-in one if no name is given, and checks it
+/// can be filled in.
+pub fn load_template(name: Option<&str>, language: Language) -> io::Result<EmailTemplate> {
+    let template = match name {
+        None | Some("") | Some(BUILT_IN) => EmailTemplate::built_in(language),
+        Some(name) => TemplateStore::open_default()?.load(name, language)?,
+    };
+    template.check().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Template '{}': {}", name.unwrap_or(BUILT_IN), e),
+        )
+    })?;
+    Ok(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> (tempfile::TempDir, TemplateStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TemplateStore::open(dir.path()).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn test_save_load_and_delete() {
+        let (tmp, store) = store();
+        let dir = tmp.path();
+        assert!(store.names().unwrap().is_empty());
+
+        let template = EmailTemplate {
+            subject_template: "{{location}} eSIM from {{name}}".to_string(),
+            body_template: "<p>Hi, here is your eSIM.</p>".to_string(),
+            text_template: Some("Hi, here is your eSIM.".to_string()),
+            variables: vec!["iccid".to_string()],
+        };
+        store.save("Short", Language::English, &template).unwrap();
+        store
+            .save(
+                "Arabic",
+                Language::Arabic,
+                &EmailTemplate::built_in(Language::Arabic),
+            )
+            .unwrap();
+        fs::create_dir(dir.join("not a template")).unwrap();
+
+        assert_eq!(store.names().unwrap(), vec!["Arabic", "Short"]);
+        assert_eq!(store.load("Short", Language::English).unwrap(), template);
+        assert_eq!(
+            fs::read_to_string(dir.join("Short").join("variables.txt")).unwrap(),
+            "iccid\n"
+        );
+        let converted = EmailTemplate {
+            text_template: None,
+            variables: Vec::new(),
+            ..template
+        };
+        store.save("Short", Language::English, &converted).unwrap();
+        assert_eq!(store.load("Short", Language::English).unwrap(), converted);
+        assert!(!dir.join("Short").join("variables.txt").exists());
+
+        store.delete("Short").unwrap();
+        assert_eq!(store.names().unwrap(), vec!["Arabic"]);
+        let err = store.load("Short", Language::English).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("Template 'Short' not found"));
+    }
+
+    #[test]
+    fn test_missing_subject_falls_back_to_built_in() {
+        let (tmp, store) = store();
+        let dir = tmp.path();
+        fs::create_dir(dir.join("Hand written")).unwrap();
+        fs::write(dir.join("Hand written").join("body.html"), "<p>Hello</p>").unwrap();
+
+        let template = store.load("Hand written", Language::English).unwrap();
+        assert_eq!(template.body_template, "<p>Hello</p>");
+        assert_eq!(
+            template.subject_template,
+            EmailTemplate::new().subject_template
+        );
+        assert_eq!(
+            store.load(BUILT_IN, Language::English).unwrap(),
+            EmailTemplate::new()
+        );
+    }
+
+    #[test]
+    fn test_languages() {
+        let (tmp, store) = store();
+        let dir = tmp.path();
+        let english = EmailTemplate {
+            subject_template: "{{location}} eSIM".to_string(),
+            body_template: "<p>Hello</p>".to_string(),
+            text_template: None,
+            variables: Vec::new(),
+        };
+        let arabic = EmailTemplate {
+            subject_template: "شريحة eSIM في {{location}}".to_string(),
+            body_template: "<p dir=\"rtl\">مرحبًا</p>".to_string(),
+            text_template: Some("مرحبًا".to_string()),
+            variables: Vec::new(),
+        };
+        store.save("Welcome", Language::English, &english).unwrap();
+        assert_eq!(store.languages("Welcome").unwrap(), vec![Language::English]);
+        // Without an Arabic version, the English one is used
+        assert_eq!(store.load("Welcome", Language::Arabic).unwrap(), english);
+
+        store.save("Welcome", Language::Arabic, &arabic).unwrap();
+        assert!(dir.join("Welcome").join("body.ar.html").is_file());
+        assert_eq!(
+            store.languages("Welcome").unwrap(),
+            vec![Language::English, Language::Arabic]
+        );
+        assert_eq!(store.load("Welcome", Language::Arabic).unwrap(), arabic);
+        assert_eq!(store.load("Welcome", Language::English).unwrap(), english);
+
+        // A template only written in Arabic is still listed and used for both
+        store
+            .save("Arabic only", Language::Arabic, &arabic)
+            .unwrap();
+        assert_eq!(store.names().unwrap(), vec!["Arabic only", "Welcome"]);
+        assert_eq!(store.load("Arabic only", Language::Arabic).unwrap(), arabic);
+        assert_eq!(
+            store.load("Arabic only", Language::English).unwrap(),
+            arabic
+        );
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let (_dir, store) = store();
+        for name in ["", " ", BUILT_IN, "..", "a/b", "a\\b"] {
+            let err = store
+                .save(name, Language::English, &EmailTemplate::new())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+        assert_eq!(
+            load_template(None, Language::English).unwrap(),
+            EmailTemplate::new()
+        );
+        assert_eq!(
+            load_template(Some(BUILT_IN), Language::Arabic).unwrap(),
+            EmailTemplate::built_in(Language::Arabic)
+        );
+    }
+}